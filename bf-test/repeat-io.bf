@@ -0,0 +1,7 @@
+[
+    Repeated input and output: reads three chars into c0 so only the last one
+    is kept, prints it three times, then prints a newline twelve times so that
+    long runs of output are covered too
+]
+,,,...
+>++++++++++............
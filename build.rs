@@ -0,0 +1,24 @@
+#[cfg(feature = "llvm")]
+extern crate cc;
+
+use std::env;
+
+fn main() {
+	println!("cargo:rerun-if-changed=src/bftape.c");
+
+	// Only code compiled by LLVM on Linux with glibc runs on a tape with guard regions.
+	// See `bftape.rs`.
+	let linux_gnu = env::var("CARGO_CFG_TARGET_OS").map(|os| os == "linux").unwrap_or(false) &&
+		env::var("CARGO_CFG_TARGET_ENV").map(|target_env| target_env == "gnu").unwrap_or(false);
+	if linux_gnu {
+		build_tape_shim();
+	}
+}
+
+#[cfg(feature = "llvm")]
+fn build_tape_shim() {
+	cc::Build::new().file("src/bftape.c").compile("bftape");
+}
+
+#[cfg(not(feature = "llvm"))]
+fn build_tape_shim() {}
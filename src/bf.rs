@@ -1,16 +1,30 @@
-use std::io::{self, Read, Write, StdinLock, StdoutLock};
+use std::fmt;
+use std::io::{self, Read, StdinLock, StdoutLock, Write};
+use std::mem;
+use ::bfopt;
+use ::bfloop::{self, CellUpdate, LoopSummary};
+use ::bfidiom::{self, Idiom, IdiomOp};
+use ::bfscan;
+use ::bftape::Tape;
+use ::bfreport::{LoopProfile, LoopProfileEntry, OptReport, SourceLocation, SourceSpan};
 
 pub type BFCellValue = u8;
 pub const BF_MEMORY_SIZE: usize = 3000;
 pub const GROUP_REPEAT_PRINTS: bool = false;
+pub const DEFAULT_PARTIAL_EVAL_STEPS: usize = 10_000_000;
+pub const DEFAULT_TIER_THRESHOLD: usize = 10_000;
 
-const OPTIMIZATIONS: [fn(&[BFInstr], &mut Vec<BFInstr>) -> bool; 3] = [
-	BFProgram::optimize_zero,
-	BFProgram::optimize_move_data,
-	BFProgram::optimize_find_zero,
+/// Rewrites a loop into the workspace. If it can't, says why, unless the loop isn't
+/// the kind of loop that it's for at all.
+type LoopOptimization = fn(&[BFInstr], &mut Vec<BFInstr>) -> Result<(), Option<&'static str>>;
+
+const OPTIMIZATIONS: [(&str, LoopOptimization); 3] = [
+	("zero", BFProgram::optimize_zero),
+	("counted-loop", BFProgram::optimize_counted_loop),
+	("find-zero", BFProgram::optimize_find_zero),
 ];
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum BFInstr {
 	IncPC(usize),
 	DecPC(usize),
@@ -25,14 +39,29 @@ pub enum BFInstr {
 	// Optimized Instructions:
 	ZeroCurrentCell,
 
-	AddCellValueRight(usize), // (dist, rhs)
-	AddCellValueLeft(usize), // (dist, rhs)
-	
-	SubCellValueRight(usize), // (dist, rhs)
-	SubCellValueLeft(usize), // (dist, rhs)
+	/// Adds the cell at `offset` multiplied by each factor to the cell at each target
+	/// offset (relative to `offset`), then zeroes the cell at `offset`. (offset, factor)
+	MultiplyAdd { offset: isize, targets: Box<[(isize, BFCellValue)]> },
+
+	/// A loop that was replaced with closed-form arithmetic.
+	ClosedForm(Box<LoopSummary>),
 
 	FindZeroCellLeft(usize),
 	FindZeroCellRight(usize),
+
+	// Offset Addressed Instructions (relative to the data pointer):
+	Add { offset: isize, amount: BFCellValue },
+	SetCell(BFCellValue, isize), // (value, offset)
+	Out { offset: isize, times: usize },
+	In { offset: isize, times: usize },
+
+	/// Output that was already computed at compile time.
+	OutputBytes(Box<[u8]>),
+
+	/// Runs `op` and jumps to the matching `IdiomEnd` if it can be used on the current
+	/// tape, otherwise falls through to the original snippet that follows it.
+	Idiom { op: Box<IdiomOp>, end: usize },
+	IdiomEnd(usize),
 }
 
 #[derive(Default)]
@@ -42,70 +71,325 @@ pub struct BFProgramStats {
 
 	/// Number of loops found in the program.
 	pub loop_count: usize,
+
+	/// Number of instructions that were run at compile time by partial evaluation.
+	pub partial_eval_steps: usize,
+
+	/// Number of snippets that were recognized as idioms.
+	pub idiom_count: usize,
+}
+
+/// Why a program stopped before it finished.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RunError {
+	/// A cell that isn't on the tape was used, by the instruction at `location` if that's known.
+	PointerOutOfRange { cell: isize, location: Option<SourceLocation> },
+}
+
+impl fmt::Display for RunError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			RunError::PointerOutOfRange { cell, location: Some(location) } => {
+				write!(f, "Data pointer out of range at {} (cell {} of {})", location, cell, BF_MEMORY_SIZE)
+			},
+			RunError::PointerOutOfRange { cell, location: None } => {
+				write!(f, "Data pointer out of range (cell {} of {})", cell, BF_MEMORY_SIZE)
+			},
+		}
+	}
+}
+
+/// Somewhere other than the interpreter to run loops that have been gone around often
+/// enough, like native code.
+pub trait LoopTier {
+	/// Number of times a loop has to get to its end before it's handed to `compile`.
+	fn threshold(&self) -> usize;
+
+	/// Gets the loop in `program` that starts at instruction `start` ready to run.
+	/// Returns false if it can't be, in which case the interpreter keeps running it.
+	fn compile(&mut self, program: &BFProgram, start: usize) -> bool;
+
+	/// Runs the loop starting at `start`, which `compile` returned true for, on the tape
+	/// that starts at `cells` with the data pointer at `data_ptr`. Returns where it leaves
+	/// the data pointer.
+	fn run(&mut self, start: usize, cells: *mut BFCellValue, data_ptr: usize, stdin: &mut StdinLock, stdout: &mut StdoutLock) -> Result<usize, RunError>;
+}
+
+/// How many times each loop has got to its end while running, by the index of its start.
+#[cfg_attr(not(any(feature = "stats", feature = "llvm")), allow(dead_code))]
+struct LoopCounts {
+	counts: Vec<usize>,
+}
+
+#[cfg_attr(not(any(feature = "stats", feature = "llvm")), allow(dead_code))]
+impl LoopCounts {
+	fn new(instructions: &[BFInstr]) -> LoopCounts {
+		LoopCounts { counts: vec![0; instructions.len()] }
+	}
+
+	/// Counts the loop starting at `start` getting to its end, and returns how many times it has.
+	#[inline(always)]
+	fn count(&mut self, start: usize) -> usize {
+		let count = &mut self.counts[start];
+		*count += 1;
+		*count
+	}
+
+	/// The start of every loop that has got to its end, with how many times it has.
+	#[cfg(feature = "stats")]
+	fn counted<'a>(&'a self) -> impl Iterator<Item = (usize, usize)> + 'a {
+		self.counts.iter().cloned().enumerate().filter(|&(_, count)| count > 0)
+	}
 }
 
 /// Brainfuck program.
 pub struct BFProgram {
-	memory: Vec<BFCellValue>,
+	memory: Tape,
 	instructions: Vec<BFInstr>,
 
+	/// Byte offset in the source of the BF command that each instruction came from.
+	positions: Vec<usize>,
+
+	/// Byte offset of the start of every line in the source after the first.
+	line_starts: Vec<usize>,
+
 	/// Number of BF commands that actually make up this program (# read).
 	instr_count: usize,
 	
 	data_ptr: usize,
 	pc: usize,
 
+	/// Maximum number of instructions to run at compile time, if partial evaluation is enabled.
+	partial_eval_budget: Option<usize>,
+
+	/// New cell values computed by a `ClosedForm` before they're written back.
+	closed_form_values: Vec<BFCellValue>,
+
+	/// Parsed snippets that are replaced with idioms wherever they show up, with the
+	/// number of BF commands in each snippet.
+	idioms: Vec<(Box<[BFInstr]>, IdiomOp, usize)>,
+
+	/// Which rewrites were done where, if that is being recorded.
+	report: Option<OptReport>,
+
 	pub stats: BFProgramStats,
 }
 
 impl BFProgram {
 	pub fn new() -> BFProgram {
-		let mut _mem = Vec::with_capacity(BF_MEMORY_SIZE);
-		_mem.resize(BF_MEMORY_SIZE, 0);
+		let mut program = BFProgram::without_idioms();
+		for idiom in bfidiom::builtin_idioms().iter() {
+			program.add_idiom(idiom);
+		}
+		program
+	}
 
+	fn without_idioms() -> BFProgram {
 		BFProgram {
-			memory: _mem,
+			memory: Tape::new(),
 			instructions: Vec::new(),
+			positions: Vec::new(),
+			line_starts: Vec::new(),
 			instr_count: 0,
 
 			data_ptr: 0,
 			pc: 0,
 
+			partial_eval_budget: None,
+			closed_form_values: Vec::new(),
+			idioms: Vec::new(),
+			report: None,
+
 			stats: BFProgramStats::default()
 		}
 	}
 
-	pub fn compile<R>(&mut self, mut input: R) where R: Read+Sized {
+	/// Runs the program at compile time until it first reads input or has run
+	/// `step_budget` instructions, and replaces everything it ran with the resulting
+	/// tape state and output.
+	pub fn enable_partial_eval(&mut self, step_budget: usize) {
+		self.partial_eval_budget = Some(step_budget);
+	}
+
+	/// Recognizes `idiom` in programs compiled after this. It's assumed to do the same
+	/// thing as its snippet, so user supplied idioms should be checked with `verify_idiom` first.
+	pub fn add_idiom(&mut self, idiom: &Idiom) {
+		let mut pattern = BFProgram::without_idioms();
+		pattern.parse(&idiom.pattern[..]);
+		self.idioms.push((pattern.instructions.into_boxed_slice(), idiom.op.clone(), idiom.pattern.len()));
+	}
+
+	/// Records which rewrites are done to which parts of the program while compiling.
+	pub fn enable_opt_report(&mut self) {
+		self.report = Some(OptReport::new());
+	}
+
+	pub fn get_opt_report(&self) -> Option<&OptReport> {
+		self.report.as_ref()
+	}
+
+	/// Checks that `idiom` does the same thing as its snippet by running both on random
+	/// tapes that its op applies to.
+	pub fn verify_idiom(idiom: &Idiom) -> Result<(), String> {
+		const SAMPLES: usize = 2000;
+		const STEP_LIMIT: usize = 1_000_000;
+		const SPREAD: isize = 16;
+
+		let mut snippet = BFProgram::without_idioms();
+		snippet.parse(&idiom.pattern[..]);
+
+		let base = BF_MEMORY_SIZE / 2;
+		let touched = idiom.op.touched_offsets();
+		let zero_cells = idiom.op.zero_cells();
+
+		// xorshift, so the same idiom is always checked against the same tapes.
+		let mut rng = 0x2545_f491_4f6c_dd1du64;
+		let mut random = move || {
+			rng ^= rng << 13;
+			rng ^= rng >> 7;
+			rng ^= rng << 17;
+			rng
+		};
+
+		let mut applied = 0;
+		for _ in 0..SAMPLES {
+			for cell in snippet.memory.iter_mut() { *cell = 0; }
+			for offset in -SPREAD..(SPREAD + 1) {
+				let value = random();
+				// Mostly small values, since that's where the edge cases are.
+				snippet.memory[(base as isize + offset) as usize] = if value & 1 == 0 {
+					((value >> 8) % 16) as BFCellValue
+				} else {
+					(value >> 8) as BFCellValue
+				};
+			}
+			if random() % 4 != 0 {
+				for &offset in zero_cells.iter() {
+					snippet.memory[(base as isize + offset) as usize] = 0;
+				}
+			}
+			if random() % 4 == 0 && touched.len() > 1 {
+				let other = touched[1 + (random() as usize) % (touched.len() - 1)];
+				snippet.memory[(base as isize + other) as usize] = snippet.memory[base];
+			}
+
+			if !idiom.op.applies(&snippet.memory, base) { continue }
+			applied += 1;
+
+			let tape: Vec<String> = touched.iter()
+				.map(|&offset| format!("{}: {}", offset, snippet.memory[(base as isize + offset) as usize]))
+				.collect();
+			let tape = tape.join(", ");
+
+			let mut expected = snippet.memory.clone();
+			let mut expected_output = Vec::new();
+			idiom.op.run(&mut expected, base, &mut expected_output);
+
+			let mut output = Vec::new();
+			let mut steps = 0;
+			snippet.data_ptr = base;
+			snippet.pc = 0;
+			while snippet.pc < snippet.instructions.len() {
+				if steps >= STEP_LIMIT || !snippet.step_in_bounds() {
+					return Err(format!("The snippet for '{}' doesn't finish with [{}]", idiom.op.name(), tape));
+				}
+				snippet._step(&mut io::empty(), &mut output).expect("Only steps that stay on the tape are run.");
+				snippet.pc += 1;
+				steps += 1;
+			}
+
+			if snippet.data_ptr != base || snippet.memory[..] != expected[..] || output != expected_output {
+				return Err(format!("'{}' doesn't do the same thing as its snippet with [{}]", idiom.op.name(), tape));
+			}
+		}
+
+		if applied == 0 {
+			return Err(format!("Couldn't find a tape that '{}' applies to", idiom.op.name()));
+		}
+		Ok(())
+	}
+
+	pub fn compile<R>(&mut self, input: R) where R: Read+Sized {
+		self.parse(input);
+
+		if cfg!(not(feature = "nooptim")) {
+			let instructions = mem::take(&mut self.instructions);
+			let parsed = if self.report.is_some() { instructions.clone() } else { Vec::new() };
+			let located = instructions.into_iter().zip(self.positions.drain(..)).collect();
+			let mut dead_loops = Vec::new();
+			let optimized = bfopt::optimize(located, &mut dead_loops, self.report.as_mut());
+			self.set_located(optimized);
+			bfopt::link_loops(&mut self.instructions);
+
+			if let Some(ref mut report) = self.report {
+				let loop_starts: Vec<usize> = parsed.iter().enumerate()
+					.filter(|&(_, instr)| matches!(instr, BFInstr::LoopStart(_)))
+					.map(|(idx, _)| idx)
+					.collect();
+				for &nth in dead_loops.iter() {
+					let start = loop_starts[nth];
+					let end = match parsed[start] { BFInstr::LoopStart(end) => end, _ => unreachable!() };
+					if let Some(span) = report.loop_span(nth) {
+						report.rewrote(span, "dead-code", format_bf_window(&parsed[start..(end + 1)]),
+							"removed, the loop cell is always 0 here".to_string());
+					}
+				}
+			}
+		}
+
+		if let Some(step_budget) = self.partial_eval_budget {
+			self.partial_evaluate(step_budget);
+		}
+	}
+
+	fn parse<R>(&mut self, mut input: R) where R: Read+Sized {
 		let mut buffer = [0u8; 2048];
 		let mut last_char = 0;
 		let mut last_char_count = 0;
 		let mut loop_stack = Vec::new();
 		let mut optim_workspace = Vec::new();
+
+		// Byte offset of the start of the buffer and number of BF commands read.
+		let mut position = 0;
+		let mut command_count = 0;
+		// Byte offset of each command in the current run of them.
+		let mut run_positions = Vec::new();
 		loop {
 			match input.read(&mut buffer) {
 				Ok(read) => {
 					if read == 0 {
 						if last_char_count > 0 {
-							self.push_instr(last_char, last_char_count, &mut loop_stack, &mut optim_workspace);
+							let first_command = command_count - last_char_count;
+							self.push_instr(last_char, last_char_count, first_command, &run_positions, &mut loop_stack, &mut optim_workspace);
 						}
 						break
 					}
-					for idx in 0..read {
-						let ch = buffer[idx];
+					for (idx, &ch) in buffer[..read].iter().enumerate() {
+						if let Some(ref mut report) = self.report {
+							if Self::valid_bf_char(ch) || ch == b'\n' { report.read_char(ch, position + idx); }
+						}
+						if ch == b'\n' { self.line_starts.push(position + idx + 1); }
 						if !Self::valid_bf_char(ch) { continue }
 						if last_char_count > 0 {
 							if ch != last_char {
-								self.push_instr(last_char, last_char_count, &mut loop_stack, &mut optim_workspace);
+								let first_command = command_count - last_char_count;
+								self.push_instr(last_char, last_char_count, first_command, &run_positions, &mut loop_stack, &mut optim_workspace);
 								last_char_count = 1;
 								last_char = ch;
+								run_positions.clear();
+								run_positions.push(position + idx);
 							} else if ch == last_char {
 								last_char_count += 1;
+								run_positions.push(position + idx);
 							}
 						} else {
 							last_char = ch;
 							last_char_count = 1;
+							run_positions.push(position + idx);
 						}
+						command_count += 1;
 					}
+					position += read;
 				},
 
 				Err(e) => {
@@ -114,9 +398,135 @@ impl BFProgram {
 			}
 		}
 
-		if let Some(unmatched_loop_start) = loop_stack.pop() {
+		if let Some((unmatched_loop_start, _)) = loop_stack.pop() {
 			panic!("No matching ']' for '[' at {}", unmatched_loop_start);
 		}
+
+		if let Some(ref mut report) = self.report {
+			report.finish_parse();
+		}
+	}
+
+	/// Runs the program from the start until it reaches an `Input` instruction or runs
+	/// out of steps, then replaces the part that was run with instructions that set
+	/// the tape to the state it was left in and print everything that was output.
+	///
+	/// Evaluation always resumes from an instruction outside of any loop, so if it
+	/// stops inside of a loop everything since that loop started is thrown away.
+	fn partial_evaluate(&mut self, step_budget: usize) {
+		let mut top_level = Vec::with_capacity(self.instructions.len());
+		let mut depth = 0usize;
+		for instr in self.instructions.iter() {
+			// The end of a loop is part of it, so evaluation never stops there.
+			top_level.push(depth == 0);
+			match instr {
+				&BFInstr::LoopStart(_) | &BFInstr::Idiom { .. } => depth += 1,
+				&BFInstr::LoopEnd(_) | &BFInstr::IdiomEnd(_) => depth -= 1,
+				_ => {},
+			}
+		}
+
+		let mut output = Vec::new();
+		let mut stdin = io::empty();
+		let mut steps = 0;
+
+		// State at the start of the top level loop that is currently running.
+		// (pc, memory, data_ptr, output length, steps)
+		let mut loop_snapshot = None;
+
+		while self.pc < self.instructions.len() {
+			let stop = match self.instructions[self.pc] {
+				BFInstr::Input(_) | BFInstr::In { .. } => true,
+				_ => steps >= step_budget || !self.step_in_bounds(),
+			};
+
+			if stop {
+				if !top_level[self.pc] {
+					let (pc, memory, data_ptr, output_len, loop_steps) = loop_snapshot.take().unwrap();
+					self.pc = pc;
+					self.memory = memory;
+					self.data_ptr = data_ptr;
+					output.truncate(output_len);
+					steps = loop_steps;
+				}
+				break;
+			}
+
+			if top_level[self.pc] {
+				match self.instructions[self.pc] {
+					BFInstr::LoopStart(_) | BFInstr::Idiom { .. } => {
+						loop_snapshot = Some((self.pc, self.memory.clone(), self.data_ptr, output.len(), steps));
+					},
+					_ => {},
+				}
+			}
+
+			self._step(&mut stdin, &mut output).expect("Only steps that stay on the tape are run at compile time.");
+			self.pc += 1;
+			steps += 1;
+		}
+
+		if self.pc > 0 {
+			let mut instructions = Vec::new();
+			if !output.is_empty() {
+				instructions.push(BFInstr::OutputBytes(output.into_boxed_slice()));
+			}
+			// The tape doesn't matter anymore if the whole program was run.
+			if self.pc < self.instructions.len() {
+				for (cell, &value) in self.memory.iter().enumerate() {
+					if value != 0 {
+						instructions.push(BFInstr::SetCell(value, cell as isize));
+					}
+				}
+				if self.data_ptr > 0 {
+					instructions.push(BFInstr::IncPC(self.data_ptr));
+				}
+			}
+			// Everything that was run is attributed to where evaluation stopped.
+			let resume_position = self.positions.get(self.pc).cloned().unwrap_or(0);
+			if let Some(ref mut report) = self.report {
+				let start = self.positions[..self.pc].iter().cloned().min().unwrap();
+				let end = self.positions[..self.pc].iter().cloned().max().unwrap();
+				report.rewrote(SourceSpan { start, end }, "partial-eval", format_bf_window(&self.instructions[..self.pc]), format_bf_window(&instructions));
+			}
+			let mut located: Vec<bfopt::Located> = instructions.into_iter().map(|instr| (instr, resume_position)).collect();
+			located.extend(self.instructions.drain(self.pc..).zip(self.positions.drain(self.pc..)));
+
+			if cfg!(not(feature = "nooptim")) {
+				located = bfopt::optimize(located, &mut Vec::new(), self.report.as_mut());
+			}
+			self.set_located(located);
+			bfopt::link_loops(&mut self.instructions);
+			self.stats.partial_eval_steps = steps;
+		}
+
+		for cell in self.memory.iter_mut() { *cell = 0; }
+		self.data_ptr = 0;
+		self.pc = 0;
+	}
+
+	/// Returns true if running the current instruction only touches cells on the tape.
+	fn step_in_bounds(&self) -> bool {
+		let in_bounds = |offset: isize| {
+			let cell = self.data_ptr as isize + offset;
+			cell >= 0 && cell < BF_MEMORY_SIZE as isize
+		};
+
+		match self.instructions[self.pc] {
+			BFInstr::IncPC(n) => in_bounds(n as isize),
+			BFInstr::DecPC(n) => in_bounds(-(n as isize)),
+			BFInstr::Add { offset, .. } | BFInstr::SetCell(_, offset) |
+			BFInstr::Out { offset, .. } | BFInstr::In { offset, .. } => in_bounds(offset),
+			BFInstr::MultiplyAdd { offset, ref targets } => {
+				in_bounds(offset) && targets.iter().all(|&(target, _)| in_bounds(offset + target))
+			},
+			BFInstr::ClosedForm(ref summary) => {
+				summary.touched_offsets().iter().all(|&target| in_bounds(summary.offset + target))
+			},
+			BFInstr::FindZeroCellLeft(step_size) => bfscan::find_zero_left(&self.memory, self.data_ptr, step_size).is_some(),
+			BFInstr::FindZeroCellRight(step_size) => bfscan::find_zero_right(&self.memory, self.data_ptr, step_size).is_some(),
+			_ => true,
+		}
 	}
 
 	fn valid_bf_char(ch: u8) -> bool {
@@ -127,248 +537,569 @@ impl BFProgram {
 	}
 
 	#[inline(always)]
-	fn push_instr(&mut self, ch: u8, arg: usize, loop_stack: &mut Vec<usize>, optim_workspace: &mut Vec<BFInstr>) {
+	/// `first_command` is the number of BF commands that came before this run of them,
+	/// and `positions` has the byte offset of each of them in the source. Loop brackets
+	/// each get their own, anything else that the run is merged into gets the first one's.
+	/// Each entry in `loop_stack` is (instruction index, command number) of a `[`.
+	fn push_instr(&mut self, ch: u8, arg: usize, first_command: usize, positions: &[usize], loop_stack: &mut Vec<(usize, usize)>, optim_workspace: &mut Vec<BFInstr>) {
+		let last_command = first_command + arg - 1;
 		match ch {
-			b'>' => { self.instructions.push(BFInstr::IncPC(arg)); self.instr_count += arg; },
-			b'<' => { self.instructions.push(BFInstr::DecPC(arg)); self.instr_count += arg; },
-			b'+' => { self.instructions.push(BFInstr::IncVal(arg)); self.instr_count += arg; },
-			b'-' => { self.instructions.push(BFInstr::DecVal(arg)); self.instr_count += arg; },
-			b'.' => { self.instructions.push(BFInstr::Output(arg)); self.instr_count += arg; },
-			b',' => { self.instructions.push(BFInstr::Input(arg)); self.instr_count += arg; },
+			b'>' => self.push_net_move(arg as isize),
+			b'<' => self.push_net_move(-(arg as isize)),
+			b'+' => self.push_net_add(arg as isize),
+			b'-' => self.push_net_add(-(arg as isize)),
+			b'.' => self.instructions.push(BFInstr::Output(arg)),
+			b',' => self.instructions.push(BFInstr::Input(arg)),
 
 			b'[' => {
-				for _ in 0..arg {
-					loop_stack.push(self.instructions.len());
+				for (n, &position) in positions.iter().enumerate() {
+					loop_stack.push((self.instructions.len(), first_command + n));
 					self.instructions.push(BFInstr::LoopStart(0)); // will be back patched.
 					self.instr_count += 1;
+					self.sync_positions(position);
 				}
+				return;
 			},
 
 			b']' => {
-				for _ in 0..arg {
-					if let Some(loop_start) = loop_stack.pop() {
+				for (n, &position) in positions.iter().enumerate() {
+					if let Some((loop_start, open_command)) = loop_stack.pop() {
 						let loop_end = self.instructions.len();
 						unsafe {*self.instructions.get_unchecked_mut(loop_start) =
 							BFInstr::LoopStart(loop_end); }
 						self.instructions.push(BFInstr::LoopEnd(loop_start));
 						self.instr_count += 1;
+						self.sync_positions(position);
 
 						self.stats.loop_count += 1;
 
 						if cfg!(not(feature = "nooptim")) {
-							if self.optimize_loop(loop_start, optim_workspace) {
+							let span = self.report.as_ref().map(|report| report.span(open_command, first_command + n));
+							if self.optimize_loop(loop_start, span, optim_workspace) {
 								self.stats.optimized_loop_count += 1;
 							}
+							self.sync_positions(position);
 						}
+						self.match_idioms(first_command + n);
 					} else {
 						panic!("No matching '[' for ']' at {}", self.instructions.len());
 					}
 				}
+				return;
 			},
 
-			_ => { /* Non comman characters are just ignored. */ },
+			_ => { /* Non comman characters are just ignored. */ return; },
+		}
+		self.instr_count += arg;
+		self.sync_positions(positions[0]);
+		self.match_idioms(last_command);
+	}
+
+	/// Brings `positions` back in line with the end of the program after instructions
+	/// were pushed, netted or rewritten there. Anything new came from `position`.
+	fn sync_positions(&mut self, position: usize) {
+		self.positions.truncate(self.instructions.len());
+		let missing = self.instructions.len() - self.positions.len();
+		self.positions.extend((0..missing).map(|_| position));
+	}
+
+	/// Wraps the end of the program in an `Idiom` if it matches one of the idiom
+	/// snippets. Idioms that were already recognized inside of the snippet are
+	/// unwrapped, so a snippet that uses a smaller one is still recognized.
+	/// `last_command` is the number of the last BF command that was pushed.
+	fn match_idioms(&mut self, last_command: usize) {
+		if cfg!(feature = "nooptim") { return }
+
+		let mut matched = None;
+		for &(ref pattern, ref op, command_count) in self.idioms.iter() {
+			if let Some((start, head_excess, tail_excess)) = match_suffix(&self.instructions, pattern) {
+				matched = Some((start, op.clone(), command_count, head_excess, tail_excess));
+				break;
+			}
+		}
+
+		if let Some((start, op, command_count, head_excess, tail_excess)) = matched {
+			let op_name = op.name();
+			let mut unwrapped = 0;
+			let (mut snippet, snippet_positions): (Vec<BFInstr>, Vec<usize>) = self.instructions.drain(start..)
+				.zip(self.positions.drain(start..))
+				.filter(|(instr, _)| match *instr {
+					BFInstr::Idiom { .. } => { unwrapped += 1; false },
+					BFInstr::IdiomEnd(_) => false,
+					_ => true,
+				})
+				.unzip();
+			let first_position = snippet_positions[0];
+			let last_position = *snippet_positions.last().unwrap();
+
+			// The snippet can start or end in the middle of a run of moves or additions,
+			// in which case the rest of the run goes outside of the idiom.
+			let mut after = None;
+			if tail_excess > 0 {
+				let last = snippet.pop().unwrap();
+				snippet.push(with_run_length(&last, run_length(&last).unwrap() - tail_excess));
+				after = Some(with_run_length(&last, tail_excess));
+			}
+			if head_excess > 0 {
+				let first = snippet[0].clone();
+				snippet[0] = with_run_length(&first, run_length(&first).unwrap() - head_excess);
+				self.instructions.push(with_run_length(&first, head_excess));
+				self.positions.push(first_position);
+			}
+
+			let start = self.instructions.len();
+			self.instructions.push(BFInstr::Idiom { op: Box::new(op), end: 0 });
+			self.positions.push(first_position);
+			self.instructions.extend(snippet);
+			self.positions.extend(snippet_positions);
+			let end = self.instructions.len();
+			self.instructions.push(BFInstr::IdiomEnd(start));
+			self.positions.push(last_position);
+			if let BFInstr::Idiom { end: ref mut idiom_end, .. } = self.instructions[start] {
+				*idiom_end = end;
+			}
+
+			let mut loop_stack = Vec::new();
+			for idx in (start + 1)..end {
+				match self.instructions[idx] {
+					BFInstr::LoopStart(_) => loop_stack.push(idx),
+					BFInstr::LoopEnd(_) => {
+						let loop_start = loop_stack.pop().expect("Idiom snippets have balanced loops.");
+						self.instructions[loop_start] = BFInstr::LoopStart(idx);
+						self.instructions[idx] = BFInstr::LoopEnd(loop_start);
+					},
+					_ => {},
+				}
+			}
+
+			if let Some(ref mut report) = self.report {
+				// This is off by a bit if something around the snippet was netted into it.
+				let end_command = last_command.saturating_sub(tail_excess);
+				let span = report.span((end_command + 1).saturating_sub(command_count), end_command);
+				report.rewrote(span, "idiom", format_bf_window(&self.instructions[(start + 1)..end]), format!("@{}", op_name));
+			}
+
+			if let Some(instr) = after {
+				self.instructions.push(instr);
+				self.positions.push(last_position);
+			}
+
+			self.stats.idiom_count = self.stats.idiom_count + 1 - unwrapped;
 		}
 	}
 
+	/// Pushes a data pointer move, netting it out against the previous instruction
+	/// if that was also a move so that things like `><><` disappear entirely.
+	fn push_net_move(&mut self, mut amount: isize) {
+		if cfg!(not(feature = "nooptim")) {
+			match self.instructions.last() {
+				Some(&BFInstr::IncPC(prev)) => amount += prev as isize,
+				Some(&BFInstr::DecPC(prev)) => amount -= prev as isize,
+				_ => return self.instructions.push(move_instr(amount)),
+			}
+			self.instructions.pop();
+			if amount == 0 { return }
+		}
+		self.instructions.push(move_instr(amount));
+	}
+
+	/// Pushes a cell addition, netting it out against the previous instruction if
+	/// that was also an addition so that things like `+-+-` or `+++--` are reduced.
+	fn push_net_add(&mut self, mut amount: isize) {
+		if cfg!(not(feature = "nooptim")) {
+			match self.instructions.last() {
+				Some(&BFInstr::IncVal(prev)) => amount += prev as isize,
+				Some(&BFInstr::DecVal(prev)) => amount -= prev as isize,
+				_ => return self.instructions.push(add_instr(amount)),
+			}
+			self.instructions.pop();
+			// Cells wrap so adding any multiple of the cell size does nothing.
+			if amount % (BFCellValue::MAX as isize + 1) == 0 { return }
+		}
+		self.instructions.push(add_instr(amount));
+	}
+
 	#[cfg(not(feature = "stats"))]
-	pub fn run(&mut self) {
+	pub fn run(&mut self) -> Result<(), RunError> {
 		let stdin = io::stdin();
 		let stdout = io::stdout();
 		let mut stdin_locked = stdin.lock();
 		let mut stdout_locked = stdout.lock();
 
 		while self.pc < self.instructions.len() {
-			self._step(&mut stdin_locked, &mut stdout_locked);
+			self._step(&mut stdin_locked, &mut stdout_locked)?;
 			self.pc += 1;
 		}
+		Ok(())
 	}
 
 	#[cfg(feature = "stats")]
-	pub fn run(&mut self) {
+	pub fn run(&mut self) -> Result<(), RunError> {
 		use std::collections::HashMap;
 
-		let mut loop_map: HashMap<String, usize> = HashMap::new();
+		let mut loop_counts = LoopCounts::new(&self.instructions);
 
 		let stdin = io::stdin();
 		let stdout = io::stdout();
 		let mut stdin_locked = stdin.lock();
 		let mut stdout_locked = stdout.lock();
 
-		let mut window_buffer = String::new();
-
+		let mut result = Ok(());
 		while self.pc < self.instructions.len() {
-
 			if let BFInstr::LoopEnd(loop_start) = self.instructions[self.pc] {
-				window_buffer.clear();
-				format_bf_window_into(&self.instructions[loop_start..(self.pc + 1)], &mut window_buffer);
-				if !loop_map.contains_key(&window_buffer) {
-					loop_map.insert(window_buffer.clone(), 1);
-				} else {
-					if let Some(c) = loop_map.get_mut(&window_buffer) {
-						*c += 1;
-					}
-				}
+				loop_counts.count(loop_start);
 			}
 
-			self._step(&mut stdin_locked, &mut stdout_locked);
+			if let Err(err) = self._step(&mut stdin_locked, &mut stdout_locked) {
+				result = Err(err);
+				break;
+			}
 			self.pc += 1;
 		}
 
+		// Loops that look the same are counted together.
+		let mut loop_map: HashMap<String, usize> = HashMap::new();
+		let mut window_buffer = String::new();
+		for (loop_start, exec_count) in loop_counts.counted() {
+			let loop_end = match self.instructions[loop_start] { BFInstr::LoopStart(end) => end, _ => unreachable!() };
+			window_buffer.clear();
+			format_bf_window_into(&self.instructions[loop_start..(loop_end + 1)], &mut window_buffer);
+			*loop_map.entry(window_buffer.clone()).or_insert(0) += exec_count;
+		}
+
 		let mut loop_stats = Vec::new();
 
 		for item in loop_map.drain() {
 			loop_stats.push(item);
 		}
 
-		loop_stats.sort_by(|a, b| b.1.cmp(&a.1));
+		loop_stats.sort_by_key(|&(_, exec_count)| std::cmp::Reverse(exec_count));
 
-		for &(ref loopstr, ref exec_count) in loop_stats.iter().take(10) {
+		for (loopstr, exec_count) in loop_stats.iter().take(10) {
 			println!("{}\t\t\t\t ...\t{} times", loopstr, exec_count);
 		}
+		result
 	}
 
-	fn _step(&mut self, stdin: &mut StdinLock, stdout: &mut StdoutLock) {
-		match unsafe { *self.instructions.get_unchecked(self.pc) } {
-			BFInstr::IncPC(inc) => self.data_ptr += inc,
-			BFInstr::DecPC(dec) => self.data_ptr -= dec,
+	/// Runs the program with the interpreter, and hands each loop to `tier` once it has
+	/// got to its end `tier.threshold()` times. Loops switch over when they start or go
+	/// back to their start, so one that gets hot while it's running switches on its next pass.
+	#[cfg_attr(not(feature = "llvm"), allow(dead_code))]
+	pub fn run_tiered<T: LoopTier>(&mut self, tier: &mut T) -> Result<(), RunError> {
+		let stdin = io::stdin();
+		let stdout = io::stdout();
+		let mut stdin_locked = stdin.lock();
+		let mut stdout_locked = stdout.lock();
+
+		let threshold = tier.threshold();
+		let mut loop_counts = LoopCounts::new(&self.instructions);
+		// Whether `tier` runs each loop, by the index of its start.
+		let mut tiered = vec![false; self.instructions.len()];
+
+		let cells = self.memory.cells();
+		while self.pc < self.instructions.len() {
+			let tier_loop = match self.instructions[self.pc] {
+				BFInstr::LoopStart(_) if tiered[self.pc] => Some(self.pc),
+				BFInstr::LoopEnd(loop_start) => {
+					if loop_counts.count(loop_start) == threshold {
+						tiered[loop_start] = tier.compile(self, loop_start);
+					}
+					if tiered[loop_start] && self.load_cell(self.data_ptr) != 0 { Some(loop_start) } else { None }
+				},
+				_ => None,
+			};
+
+			if let Some(loop_start) = tier_loop {
+				self.data_ptr = tier.run(loop_start, cells, self.data_ptr, &mut stdin_locked, &mut stdout_locked)?;
+				// The loop ran until its cell was 0, so the interpreter carries on from its end.
+				self.pc = match self.instructions[loop_start] { BFInstr::LoopStart(end) => end, _ => unreachable!() };
+			}
+
+			self._step(&mut stdin_locked, &mut stdout_locked)?;
+			self.pc += 1;
+		}
+		Ok(())
+	}
+
+	/// Runs the current instruction. Every cell is checked before it's used and the
+	/// data pointer is checked every time it moves, so going off of the tape stops the
+	/// program with an error for the instruction that did it.
+	fn _step<R: Read, W: Write>(&mut self, stdin: &mut R, stdout: &mut W) -> Result<(), RunError> {
+		match *unsafe { self.instructions.get_unchecked(self.pc) } {
+			BFInstr::IncPC(inc) => self.data_ptr = self.cell_index(inc as isize)?,
+			BFInstr::DecPC(dec) => self.data_ptr = self.cell_index(-(dec as isize))?,
 			BFInstr::IncVal(inc) => { let cur_cell = self.data_ptr; self.cell_add_imm(cur_cell, inc) },
 			BFInstr::DecVal(dec) => { let cur_cell = self.data_ptr; self.cell_sub_imm(cur_cell, dec) },
 			BFInstr::Output(times) => for _ in 0..times {
-				let buf = &self.memory[self.data_ptr..(self.data_ptr + 1)];
-				match stdout.write(buf) {
-					Err(err) => {println!("Error while outputting char: {}", err)},
-					_ => {}
+				let buf = [self.load_cell(self.data_ptr)];
+				if let Err(err) = stdout.write(&buf) {
+					println!("Error while outputting char: {}", err);
 				}
 			},
 
 			BFInstr::Input(times) => for _ in 0..times {
-				let mut buf = &mut self.memory[self.data_ptr..(self.data_ptr + 1)];
-				match stdin.read(buf) {
-					Err(err) => {println!("Error while reading char: {}", err)},
-					_ => {}
+				let mut buf = [self.load_cell(self.data_ptr)];
+				if let Err(err) = stdin.read(&mut buf) {
+					println!("Error while reading char: {}", err);
 				}
+				self.store_cell(self.data_ptr, buf[0]);
 			},
 
 			BFInstr::LoopStart(jump_to) => {
-				if self.memory[self.data_ptr] == 0 {
+				if self.load_cell(self.data_ptr) == 0 {
 					self.pc = jump_to;
 				}
 			},
 
 			BFInstr::LoopEnd(jump_to) => {
-				if self.memory[self.data_ptr] != 0 {
+				if self.load_cell(self.data_ptr) != 0 {
 					self.pc = jump_to;
 				}
 			},
 
 			BFInstr::ZeroCurrentCell => {
 				// no zero check necessary
-				self.memory[self.data_ptr] = 0;
+				self.store_cell(self.data_ptr, 0);
 			},
 
-			BFInstr::AddCellValueRight(dist) => {
-				if self.memory[self.data_ptr] != 0 {
-					debug_assert!(self.data_ptr + dist < BF_MEMORY_SIZE,
-						"Expected self.data_ptr ({}) + dist ({}) to be less than BF_MEMORY_SIZE ({})",
-						self.data_ptr, dist, BF_MEMORY_SIZE);
-					let (lhs_cell, rhs_cell) = (self.data_ptr + dist, self.data_ptr);
-					self.cell_add_cell(lhs_cell, rhs_cell);
-					self.memory[self.data_ptr] = 0;
+			BFInstr::MultiplyAdd { offset, ref targets } => {
+				let loop_cell = self.cell_index(offset)?;
+				let value = self.load_cell(loop_cell);
+				if value != 0 {
+					for &(target, factor) in targets.iter() {
+						let cell = self.cell_index(offset + target)?;
+						self.memory[cell] = self.memory[cell].wrapping_add(value.wrapping_mul(factor));
+					}
+					self.memory[loop_cell] = 0;
 				}
 			},
 
-			BFInstr::AddCellValueLeft(dist) => {
-				if self.memory[self.data_ptr] != 0 {
-					debug_assert!(self.data_ptr >= dist,
-						"Expected self.data_ptr ({}) to be greater than or equal to dist ({})",
-						self.data_ptr, dist);
-					let (lhs_cell, rhs_cell) = (self.data_ptr - dist, self.data_ptr);
-					self.cell_add_cell(lhs_cell, rhs_cell);
-					self.memory[self.data_ptr] = 0;
+			BFInstr::ClosedForm(ref summary) => {
+				let loop_cell = self.cell_index(summary.offset)?;
+				let value = self.memory[loop_cell];
+				if value != 0 {
+					// Every cell between the lowest and highest one is on the tape if they are.
+					let (lowest, highest) = summary.extent();
+					self.cell_index(summary.offset + lowest)?;
+					self.cell_index(summary.offset + highest)?;
+					let passes = value.wrapping_mul(summary.trip_factor);
+
+					// Everything is in terms of the values from before the loop, so
+					// nothing can be written back until all of it is computed.
+					self.closed_form_values.clear();
+					for &(target, ref update) in summary.updates.iter() {
+						let new_value = match update {
+							CellUpdate::Set(expr) => expr.eval(&self.memory, loop_cell),
+							CellUpdate::Accumulate { first, rest } => {
+								let cell = self.memory[(loop_cell as isize + target) as usize];
+								let rest_value = rest.eval(&self.memory, loop_cell).wrapping_mul(passes.wrapping_sub(1));
+								cell.wrapping_add(first.eval(&self.memory, loop_cell)).wrapping_add(rest_value)
+							},
+						};
+						self.closed_form_values.push(new_value);
+					}
+
+					for (&(target, _), &new_value) in summary.updates.iter().zip(self.closed_form_values.iter()) {
+						self.memory[(loop_cell as isize + target) as usize] = new_value;
+					}
+					self.memory[loop_cell] = 0;
 				}
 			},
 
-			BFInstr::SubCellValueRight(dist) => {
-				if self.memory[self.data_ptr] != 0 {
-					debug_assert!(self.data_ptr + dist < BF_MEMORY_SIZE,
-						"Expected self.data_ptr ({}) + dist ({}) to be less than BF_MEMORY_SIZE ({})",
-						self.data_ptr, dist, BF_MEMORY_SIZE);
-					let (lhs_cell, rhs_cell) = (self.data_ptr + dist, self.data_ptr);
-					self.cell_sub_cell(lhs_cell, rhs_cell);
-					self.memory[self.data_ptr] = 0;
+			BFInstr::FindZeroCellLeft(step_size) => {
+				match bfscan::find_zero_left(&self.memory, self.data_ptr, step_size) {
+					Some(cell) => self.data_ptr = cell,
+					// The scan goes off of the tape at the cell after the last one it looked at.
+					None => return Err(self.out_of_range((self.data_ptr % step_size) as isize - step_size as isize)),
 				}
 			},
 
-			BFInstr::SubCellValueLeft(dist) => {
-				if self.memory[self.data_ptr] != 0 {
-					debug_assert!(self.data_ptr >= dist,
-						"Expected self.data_ptr ({}) to be greater than or equal to dist ({})",
-						self.data_ptr, dist);
-					let (lhs_cell, rhs_cell) = (self.data_ptr - dist, self.data_ptr);
-					self.cell_sub_cell(lhs_cell, rhs_cell);
-					self.memory[self.data_ptr] = 0;
+			BFInstr::FindZeroCellRight(step_size) => {
+				match bfscan::find_zero_right(&self.memory, self.data_ptr, step_size) {
+					Some(cell) => self.data_ptr = cell,
+					None => {
+						let steps = (BF_MEMORY_SIZE - self.data_ptr).div_ceil(step_size);
+						return Err(self.out_of_range((self.data_ptr + steps * step_size) as isize));
+					},
 				}
 			},
 
-			BFInstr::FindZeroCellLeft(step_size) => {
-				while self.memory[self.data_ptr] != 0 {
-					self.data_ptr -= step_size;
+			BFInstr::Add { offset, amount } => {
+				let cell = self.cell_index(offset)?;
+				let sum = self.load_cell(cell).wrapping_add(amount);
+				self.store_cell(cell, sum);
+			},
+
+			BFInstr::SetCell(value, offset) => {
+				let cell = self.cell_index(offset)?;
+				self.store_cell(cell, value);
+			},
+
+			BFInstr::Out { offset, times } => {
+				let cell = self.cell_index(offset)?;
+				for _ in 0..times {
+					if let Err(err) = stdout.write(&[self.load_cell(cell)]) {
+						println!("Error while outputting char: {}", err);
+					}
 				}
 			},
 
-			BFInstr::FindZeroCellRight(step_size) => {
-				while self.memory[self.data_ptr] != 0 {
-					self.data_ptr += step_size;
+			BFInstr::In { offset, times } => {
+				let cell = self.cell_index(offset)?;
+				for _ in 0..times {
+					let mut buf = [self.load_cell(cell)];
+					if let Err(err) = stdin.read(&mut buf) {
+						println!("Error while reading char: {}", err);
+					}
+					self.store_cell(cell, buf[0]);
 				}
 			},
+
+			BFInstr::OutputBytes(ref bytes) => {
+				if let Err(err) = stdout.write_all(bytes) {
+					println!("Error while outputting chars: {}", err);
+				}
+			},
+
+			// `applies` is false if any of the cells the op uses are off of the tape, in
+			// which case the snippet runs and stops where it goes off of it.
+			BFInstr::Idiom { ref op, end } => {
+				if op.applies(&self.memory, self.data_ptr) {
+					op.run(&mut self.memory, self.data_ptr, stdout);
+					self.pc = end;
+				}
+			},
+
+			BFInstr::IdiomEnd(_) => {},
+		}
+		Ok(())
+	}
+
+	/// Index of the cell at `offset` from the data pointer, or an error for the current
+	/// instruction if it isn't on the tape.
+	#[inline(always)]
+	fn cell_index(&self, offset: isize) -> Result<usize, RunError> {
+		let cell = self.data_ptr as isize + offset;
+		if cell >= 0 && cell < BF_MEMORY_SIZE as isize {
+			Ok(cell as usize)
+		} else {
+			Err(self.out_of_range(cell))
 		}
 	}
 
+	#[cold]
+	fn out_of_range(&self, cell: isize) -> RunError {
+		RunError::PointerOutOfRange { cell, location: Some(self.source_location(self.pc)) }
+	}
+
+	/// `cell` has to be the data pointer, which is always on the tape, or come from `cell_index`.
 	#[inline(always)]
-	pub fn cell_add_cell(&mut self, lhs_cell: usize, rhs_cell: usize) {
-		let rhs = self.memory[rhs_cell];
-		self.cell_add_imm(lhs_cell, rhs);
+	fn load_cell(&self, cell: usize) -> BFCellValue {
+		self.memory[cell]
 	}
 
 	#[inline(always)]
-	pub fn cell_sub_cell(&mut self, lhs_cell: usize, rhs_cell: usize) {
-		let rhs = self.memory[rhs_cell];
-		self.cell_sub_imm(lhs_cell, rhs);
+	fn store_cell(&mut self, cell: usize, value: BFCellValue) {
+		self.memory[cell] = value;
 	}
 
 	#[inline(always)]
 	pub fn cell_add_imm<I: Into<usize>>(&mut self, cell: usize, amt: I) {
-		self.memory[cell] = (self.memory[cell] as usize).wrapping_add(amt.into()) as BFCellValue;
+		let value = (self.load_cell(cell) as usize).wrapping_add(amt.into()) as BFCellValue;
+		self.store_cell(cell, value);
 	}
 
 	#[inline(always)]
 	pub fn cell_sub_imm<I: Into<usize>>(&mut self, cell: usize, amt: I) {
-		self.memory[cell] = (self.memory[cell] as usize).wrapping_sub(amt.into()) as BFCellValue;
+		let value = (self.load_cell(cell) as usize).wrapping_sub(amt.into()) as BFCellValue;
+		self.store_cell(cell, value);
 	}
 
 	pub fn get_instr_count(&self) -> usize {
 		self.instr_count
 	}
 
-	pub fn get_instructions<'p>(&'p self) -> &'p [BFInstr] {
+	/// Where in the source the instruction at `idx` came from.
+	pub fn source_location(&self, idx: usize) -> SourceLocation {
+		SourceLocation::of(self.positions[idx], &self.line_starts)
+	}
+
+	/// Matches up counts from a build instrumented with `BFLLVMProgram::enable_profiling`,
+	/// which are (entries, iterations) for each loop in the order they start, with the loops.
+	#[cfg_attr(not(feature = "llvm"), allow(dead_code))]
+	pub fn loop_profile(&self, counts: &[(u64, u64)]) -> LoopProfile {
+		let loops = self.instructions.iter().enumerate().filter_map(|(idx, instr)| match instr {
+			&BFInstr::LoopStart(end) => Some((idx, end)),
+			_ => None,
+		});
+		let entries = loops.zip(counts.iter()).map(|((start, end), &(entries, iterations))| LoopProfileEntry {
+			start: self.source_location(start),
+			end: self.source_location(end),
+			code: format_bf_window(&self.instructions[start..(end + 1)]),
+			entries,
+			iterations,
+		}).collect();
+		LoopProfile { entries }
+	}
+
+	fn set_located(&mut self, located: Vec<bfopt::Located>) {
+		let (instructions, positions) = located.into_iter().unzip();
+		self.instructions = instructions;
+		self.positions = positions;
+	}
+
+	pub fn get_instructions(&self) -> &[BFInstr] {
 		&self.instructions
 	}
 
-	fn optimize_loop(&mut self, loop_start: usize, workspace: &mut Vec<BFInstr>) -> bool {
+	/// `span` is where the loop is in the source, if an optimization report is being made.
+	fn optimize_loop(&mut self, loop_start: usize, span: Option<SourceSpan>, workspace: &mut Vec<BFInstr>) -> bool {
 		let mut optimized = false;
 
 		{
 			let window = &self.instructions[(loop_start + 1)..(self.instructions.len() - 1)];
 
-			for optim in OPTIMIZATIONS.iter() {
-				if optim(window, workspace) {
-					optimized = true;
-					break;
+			// Idioms do the same thing as their snippets, so they shouldn't keep the
+			// loop around them from being optimized.
+			let unwrapped: Vec<BFInstr>;
+			let window = if window.iter().any(is_idiom_marker) {
+				unwrapped = window.iter().filter(|instr| !is_idiom_marker(instr)).cloned().collect();
+				&unwrapped[..]
+			} else {
+				window
+			};
+
+			let mut rejections = Vec::new();
+			for &(name, optim) in OPTIMIZATIONS.iter() {
+				match optim(window, workspace) {
+					Ok(()) => {
+						optimized = true;
+						if let (Some(report), Some(span)) = (self.report.as_mut(), span) {
+							report.rewrote(span, name, format_bf_window(&self.instructions[loop_start..]), format_bf_window(workspace));
+							report.replaced_loop(span);
+						}
+						break;
+					},
+					Err(Some(reason)) => rejections.push((name, reason)),
+					Err(None) => {},
+				}
+			}
+
+			if !optimized {
+				if let (Some(report), Some(span)) = (self.report.as_mut(), span) {
+					for (name, reason) in rejections {
+						report.rejected(span, name, format_bf_window(&self.instructions[loop_start..]), reason);
+					}
+					report.kept_loop(span);
 				}
 			}
 		}
 
 		if optimized {
+			let replaced_idioms = self.instructions[loop_start..].iter()
+				.filter(|instr| matches!(instr, BFInstr::Idiom { .. }))
+				.count();
+			self.stats.idiom_count -= replaced_idioms;
 			self.instructions.truncate(loop_start);
 			self.instructions.append(workspace);
 			workspace.clear();
@@ -382,81 +1113,119 @@ impl BFProgram {
 		optimized
 	}
 
-	fn optimize_find_zero(window: &[BFInstr], workspace: &mut Vec<BFInstr>) -> bool {
+	fn optimize_find_zero(window: &[BFInstr], workspace: &mut Vec<BFInstr>) -> Result<(), Option<&'static str>> {
 		if window.len() == 1 {
 			if let BFInstr::DecPC(step_size) = window[0] {
 				workspace.push(BFInstr::FindZeroCellLeft(step_size));
-				return true;
+				return Ok(());
 			} else if let BFInstr::IncPC(step_size) = window[0] {
 				workspace.push(BFInstr::FindZeroCellRight(step_size));
-				return true;
+				return Ok(());
 			}
 		}
-		false
+		Err(None)
 	}
 
-	fn optimize_zero(window: &[BFInstr], workspace: &mut Vec<BFInstr>) -> bool {
+	fn optimize_zero(window: &[BFInstr], workspace: &mut Vec<BFInstr>) -> Result<(), Option<&'static str>> {
 		if window.len() == 1 {
 			if let BFInstr::DecVal(1) = window[0] {
 				// OPTIMIZES [-]
 				workspace.push(BFInstr::ZeroCurrentCell);
-				return true;
+				return Ok(());
 			} else if let BFInstr::ZeroCurrentCell = window[0] {
 				// OPTIMIZES [[-]]
 				workspace.push(BFInstr::ZeroCurrentCell);
-				return true;
+				return Ok(());
 			}
 		}
-		false
+		Err(None)
 	}
 
-	fn optimize_move_data(window: &[BFInstr], workspace: &mut Vec<BFInstr>) -> bool {
-		if window.len() == 4 {
-			if let BFInstr::DecVal(1) = window[0] {
-				if let BFInstr::IncVal(1) = window[2] {
-					if let BFInstr::IncPC(dist_a) = window[1] {
-						if let BFInstr::DecPC(dist_b) = window[3] {
-							if dist_a == dist_b {
-								// OPTIMIZES: ->+<
-								workspace.push(BFInstr::AddCellValueRight(dist_a));
-								return true;
-							}
-						}
-					} else if let BFInstr::DecPC(dist_a) = window[1] {
-						if let BFInstr::IncPC(dist_b) = window[3] {
-							if dist_a == dist_b {
-								// OPTIMIZES: -<+>
-								workspace.push(BFInstr::AddCellValueLeft(dist_a));
-								return true;
-							}
-						}
-					}
-				} else if let BFInstr::DecVal(1) = window[2] {
-					if let BFInstr::IncPC(dist_a) = window[1] {
-						if let BFInstr::DecPC(dist_b) = window[3] {
-							if dist_a == dist_b {
-								// OPTIMIZES: ->-<
-								workspace.push(BFInstr::SubCellValueRight(dist_a));
-								return true;
-							}
-						}
-					} else if let BFInstr::DecPC(dist_a) = window[1] {
-						if let BFInstr::IncPC(dist_b) = window[3] {
-							if dist_a == dist_b {
-								// OPTIMIZES: -<->
-								workspace.push(BFInstr::SubCellValueLeft(dist_a));
-								return true;
-							}
-						}
-					}
-				}
-			}
+	/// Optimizes loops that run a number of times that can be worked out from the
+	/// loop cell, like `[->++>+++<<]`, `[>+<---]` or `[>[->+>+<<]>>[-<<+>>]<<<-]`.
+	fn optimize_counted_loop(window: &[BFInstr], workspace: &mut Vec<BFInstr>) -> Result<(), Option<&'static str>> {
+		let summary = bfloop::summarize_loop(window).map_err(Some)?;
+		workspace.push(bfloop::summary_to_instr(summary));
+		Ok(())
+	}
+}
+
+fn move_instr(amount: isize) -> BFInstr {
+	if amount < 0 { BFInstr::DecPC((-amount) as usize) } else { BFInstr::IncPC(amount as usize) }
+}
+
+fn add_instr(amount: isize) -> BFInstr {
+	if amount < 0 { BFInstr::DecVal((-amount) as usize) } else { BFInstr::IncVal(amount as usize) }
+}
+
+fn is_idiom_marker(instr: &BFInstr) -> bool {
+	matches!(instr, BFInstr::Idiom { .. } | BFInstr::IdiomEnd(_))
+}
+
+fn run_length(instr: &BFInstr) -> Option<usize> {
+	match instr {
+		&BFInstr::IncPC(n) | &BFInstr::DecPC(n) | &BFInstr::IncVal(n) | &BFInstr::DecVal(n) => Some(n),
+		_ => None,
+	}
+}
+
+fn with_run_length(instr: &BFInstr, n: usize) -> BFInstr {
+	match *instr {
+		BFInstr::IncPC(_) => BFInstr::IncPC(n),
+		BFInstr::DecPC(_) => BFInstr::DecPC(n),
+		BFInstr::IncVal(_) => BFInstr::IncVal(n),
+		BFInstr::DecVal(_) => BFInstr::DecVal(n),
+		_ => unreachable!("Only moves and additions are runs."),
+	}
+}
+
+/// Returns where `pattern` starts if `instructions` end with it. Idiom markers in
+/// `instructions` and the targets of loop instructions are ignored. The first and
+/// last instruction of the pattern can be part of a longer run of moves or additions,
+/// so this also returns how much longer those runs are. (start, head excess, tail excess)
+fn match_suffix(instructions: &[BFInstr], pattern: &[BFInstr]) -> Option<(usize, usize, usize)> {
+	if pattern.is_empty() { return None }
+
+	let mut idx = instructions.len();
+	let mut head_excess = 0;
+	let mut tail_excess = 0;
+	for (pattern_idx, expected) in pattern.iter().enumerate().rev() {
+		loop {
+			if idx == 0 { return None }
+			idx -= 1;
+			if !is_idiom_marker(&instructions[idx]) { break }
 		}
-		false
+
+		let is_tail = pattern_idx == pattern.len() - 1;
+		match (&instructions[idx], expected) {
+			(&BFInstr::LoopStart(_), &BFInstr::LoopStart(_)) => {},
+			(&BFInstr::LoopEnd(_), &BFInstr::LoopEnd(_)) => {},
+			(instr, expected) if instr == expected => {},
+			(instr, expected) if is_tail || pattern_idx == 0 => {
+				let excess = match (run_length(instr), run_length(expected)) {
+					(Some(n), Some(m)) if n > m && mem::discriminant(instr) == mem::discriminant(expected) => n - m,
+					_ => return None,
+				};
+				if is_tail { tail_excess = excess; } else { head_excess = excess; }
+			},
+			_ => return None,
+		}
+	}
+
+	// Idioms that start where the pattern does are inside of it.
+	while idx > 0 {
+		if let BFInstr::Idiom { .. } = instructions[idx - 1] { idx -= 1; } else { break }
 	}
+
+	// An idiom that starts before the pattern can't be split up.
+	let splits_idiom = instructions[idx..].iter().any(|instr| match instr {
+		&BFInstr::IdiomEnd(start) => start < idx,
+		_ => false,
+	});
+	if splits_idiom { None } else { Some((idx, head_excess, tail_excess)) }
 }
 
-fn format_bf_window(window: &[BFInstr]) -> String {
+pub fn format_bf_window(window: &[BFInstr]) -> String {
 	let mut s = String::new();
 	format_bf_window_into(window, &mut s);
 	s
@@ -483,13 +1252,270 @@ fn format_bf_window_into(window: &[BFInstr], s: &mut String){
 			&BFInstr::LoopEnd(_) => s.push(']'),
 
 			&BFInstr::ZeroCurrentCell => s.push('Z'),
-			&BFInstr::AddCellValueRight(dist) => s.push_str(&format!("Ar({})", dist)),
-			&BFInstr::AddCellValueLeft(dist) => s.push_str(&format!("Al({})", dist)),
-			&BFInstr::SubCellValueRight(dist) => s.push_str(&format!("Sr({})", dist)),
-			&BFInstr::SubCellValueLeft(dist) => s.push_str(&format!("Sl({})", dist)),
+			&BFInstr::MultiplyAdd { offset, ref targets } => {
+				s.push_str(&format!("Ma({};", offset));
+				for (idx, &(target, factor)) in targets.iter().enumerate() {
+					if idx > 0 { s.push(','); }
+					s.push_str(&format!("{}*{}", target, factor));
+				}
+				s.push(')');
+			},
+
+			BFInstr::ClosedForm(summary) => {
+				s.push_str(&format!("Cf({};", summary.offset));
+				for (idx, &(target, _)) in summary.updates.iter().enumerate() {
+					if idx > 0 { s.push(','); }
+					s.push_str(&format!("{}", target));
+				}
+				s.push(')');
+			},
 
 			&BFInstr::FindZeroCellLeft(step_size) => s.push_str(&format!("Fzl({})", step_size)),
 			&BFInstr::FindZeroCellRight(step_size) => s.push_str(&format!("Fzr({})", step_size)),
+
+			&BFInstr::Add { offset, amount } => s.push_str(&format!("A({};{})", offset, amount as i8)),
+			&BFInstr::SetCell(value, offset) => s.push_str(&format!("S({};{})", offset, value)),
+			&BFInstr::Out { offset, times } => s.push_str(&format!("O({};{})", offset, times)),
+			&BFInstr::In { offset, times } => s.push_str(&format!("I({};{})", offset, times)),
+			BFInstr::OutputBytes(bytes) => s.push_str(&format!("Ob({})", bytes.len())),
+			BFInstr::Idiom { op, .. } => s.push_str(&format!("@{}{{", op.name())),
+			&BFInstr::IdiomEnd(_) => s.push('}'),
+		}
+	}
+}
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Small programs from `bf-test` that finish quickly without any input.
+	const PROGRAMS: [&str; 6] = [
+		include_str!("../bf-test/helloworld.bf"),
+		include_str!("../bf-test/sierpinski.bf"),
+		include_str!("../bf-test/count1to5.bf"),
+		include_str!("../bf-test/movedataloops.bf"),
+		include_str!("../bf-test/nested-loops-2.bf"),
+		include_str!("../bf-test/optimizable-loops.bf"),
+	];
+
+	/// Instructions for `source` with every optimization.
+	fn compiled(source: &str) -> Vec<BFInstr> {
+		let mut program = BFProgram::new();
+		program.compile(source.as_bytes());
+		program.instructions
+	}
+
+	/// Instructions for `source` the way it's written, one for each command.
+	fn unoptimized(source: &str) -> Vec<BFInstr> {
+		let mut instructions: Vec<BFInstr> = source.bytes().filter_map(|ch| match ch {
+			b'>' => Some(BFInstr::IncPC(1)),
+			b'<' => Some(BFInstr::DecPC(1)),
+			b'+' => Some(BFInstr::IncVal(1)),
+			b'-' => Some(BFInstr::DecVal(1)),
+			b'.' => Some(BFInstr::Output(1)),
+			b',' => Some(BFInstr::Input(1)),
+			b'[' => Some(BFInstr::LoopStart(0)),
+			b']' => Some(BFInstr::LoopEnd(0)),
+			_ => None,
+		}).collect();
+		bfopt::link_loops(&mut instructions);
+		instructions
+	}
+
+	/// Runs `instructions` on an empty tape, reading from `input`. Returns the tape and the output.
+	fn run(instructions: Vec<BFInstr>, mut input: &[u8]) -> (Vec<BFCellValue>, Vec<u8>) {
+		let mut program = BFProgram::new();
+		program.instructions = instructions;
+		let mut output = Vec::new();
+		while program.pc < program.instructions.len() {
+			program._step(&mut input, &mut output).expect("The program went off of the tape.");
+			program.pc += 1;
+		}
+		(program.memory.to_vec(), output)
+	}
+
+	#[test]
+	fn multiply_adds_do_what_their_loops_did() {
+		for source in [",>,>,<<[->+>++<<]", ",>,[<->-]", ">,<,>[-<+++++>]<", ",>,[<+>+++]", ",>,<[>[->+>+<<]>>[-<<+>>]<<<-]"].iter() {
+			for value in 0..=BFCellValue::MAX {
+				let input = [value, 7, 200];
+				assert_eq!(run(compiled(source), &input), run(unoptimized(source), &input), "{} with {}", source, value);
+			}
 		}
 	}
-}
\ No newline at end of file
+
+	#[test]
+	fn optimized_programs_do_what_they_did() {
+		for source in PROGRAMS.iter() {
+			assert!(run(compiled(source), b"") == run(unoptimized(source), b""), "{}", source);
+		}
+	}
+
+	#[test]
+	fn opposing_moves_and_additions_are_netted() {
+		let mut program = BFProgram::new();
+		program.push_net_move(3);
+		program.push_net_move(-3);
+		assert_eq!(program.instructions, vec![]);
+		program.push_net_move(-1);
+		program.push_net_move(3);
+		program.push_net_add(3);
+		program.push_net_add(-5);
+		assert_eq!(program.instructions, vec![BFInstr::IncPC(2), BFInstr::DecVal(2)]);
+		program.push_net_add(200);
+		program.push_net_add(58);
+		assert_eq!(program.instructions, vec![BFInstr::IncPC(2)]);
+	}
+
+	#[test]
+	fn brackets_in_a_run_keep_their_own_positions() {
+		let mut program = BFProgram::without_idioms();
+		program.parse(&b"+[ [->] ]"[..]);
+		assert_eq!(program.instructions, vec![
+			BFInstr::IncVal(1), BFInstr::LoopStart(6), BFInstr::LoopStart(5), BFInstr::DecVal(1),
+			BFInstr::IncPC(1), BFInstr::LoopEnd(2), BFInstr::LoopEnd(1),
+		]);
+		assert_eq!(program.positions, vec![0, 1, 3, 4, 5, 6, 8]);
+	}
+
+	/// Instructions for `source` after compiling it with partial evaluation.
+	fn partially_evaluated(source: &str, step_budget: usize) -> Vec<BFInstr> {
+		let mut program = BFProgram::new();
+		program.enable_partial_eval(step_budget);
+		program.compile(source.as_bytes());
+		program.instructions
+	}
+
+	#[test]
+	fn partial_eval_runs_programs_without_input() {
+		assert_eq!(partially_evaluated("++++++++[>++++++++<-]>+.+.", 1000), vec![BFInstr::OutputBytes(b"AB".to_vec().into_boxed_slice())]);
+	}
+
+	#[test]
+	fn partial_eval_stops_at_input() {
+		assert_eq!(partially_evaluated("+++.>++,.", 1000), vec![
+			BFInstr::OutputBytes(b"\x03".to_vec().into_boxed_slice()), BFInstr::SetCell(3, 0), BFInstr::SetCell(2, 1),
+			BFInstr::In { offset: 1, times: 1 }, BFInstr::Out { offset: 1, times: 1 }, BFInstr::IncPC(1),
+		]);
+	}
+
+	#[test]
+	fn partial_eval_keeps_behavior() {
+		for source in PROGRAMS.iter() {
+			let (_, expected) = run(unoptimized(source), b"");
+			for &step_budget in [0, 1, 10, 100, 1000, 100_000_000].iter() {
+				let (_, output) = run(partially_evaluated(source, step_budget), b"");
+				assert!(output == expected, "{} with a budget of {}", source, step_budget);
+			}
+		}
+	}
+
+	#[test]
+	fn partial_eval_that_stops_at_the_end_of_a_loop_resumes_from_its_start() {
+		let mut program = BFProgram::without_idioms();
+		// Runs out at the `]`, after printing 2.
+		program.enable_partial_eval(4);
+		program.compile(&b"+[+.]"[..]);
+		assert_eq!(program.instructions, vec![
+			BFInstr::SetCell(1, 0), BFInstr::LoopStart(4), BFInstr::Add { offset: 0, amount: 1 },
+			BFInstr::Out { offset: 0, times: 1 }, BFInstr::LoopEnd(1),
+		]);
+	}
+
+	/// Instructions for `source` with only the optimizations that are done while parsing it.
+	fn parsed(source: &str) -> Vec<BFInstr> {
+		let mut program = BFProgram::without_idioms();
+		program.parse(source.as_bytes());
+		program.instructions
+	}
+
+	/// Instructions for `source` with the optimizations that are done while parsing it,
+	/// including the built in idioms.
+	fn with_idioms(source: &str) -> Vec<BFInstr> {
+		let mut program = BFProgram::new();
+		program.parse(source.as_bytes());
+		program.instructions
+	}
+
+	#[test]
+	fn idioms_are_matched() {
+		let instructions = with_idioms("+++[->+>+<<]>>[-<<+>>]<<<");
+		assert_eq!(instructions[0], BFInstr::IncVal(3));
+		assert_eq!(instructions[1], BFInstr::Idiom { op: Box::new(IdiomOp::Copy { to: 1, temp: 2 }), end: 6 });
+		// The snippet stays behind the idiom, and the extra `<` goes after it.
+		assert_eq!(instructions[2..6], parsed("[->+>+<<]>>[-<<+>>]<<")[..]);
+		assert_eq!(instructions[6..], [BFInstr::IdiomEnd(1), BFInstr::DecPC(1)]);
+	}
+
+	#[test]
+	fn idioms_inside_of_bigger_idioms_are_unwrapped() {
+		// Only which snippets are matched is checked, so the second op doesn't matter.
+		let mut program = BFProgram::without_idioms();
+		program.add_idiom(&Idiom::parse("copy(1,2) [->+>+<<]>>[-<<+>>]<<").unwrap());
+		program.add_idiom(&Idiom::parse("copy(1,3) [->+>+<<]>>[-<<+>>]<<[-]").unwrap());
+		program.parse(&b"[->+>+<<]>>[-<<+>>]<<[-]"[..]);
+		let idioms: Vec<&BFInstr> = program.instructions.iter().filter(|instr| matches!(instr, BFInstr::Idiom { .. })).collect();
+		assert_eq!(idioms, vec![&BFInstr::Idiom { op: Box::new(IdiomOp::Copy { to: 1, temp: 3 }), end: program.instructions.len() - 1 }]);
+	}
+
+	#[test]
+	fn idioms_keep_behavior() {
+		let sources = [
+			">,<+++++[->+++<]>[->+>+<<]>>[-<<+>>]<<",
+			">,<++++++++[->++++++<]>+>+++++<[->-[>+>>]>[+[-<+>]>+>>]<<<<<]",
+			">,<+++++++>>[-]>[-]<<<[>>>+<<<-]+>[>>-<+<-]>[<+>-]>[<<<->>>[-]]<<<",
+		];
+		for source in sources.iter() {
+			assert!(with_idioms(source).iter().any(|instr| matches!(instr, BFInstr::Idiom { .. })), "{}", source);
+			for value in 0..=BFCellValue::MAX {
+				assert_eq!(run(compiled(source), &[value]), run(unoptimized(source), &[value]), "{} with {}", source, value);
+			}
+		}
+	}
+
+	#[test]
+	fn builtin_idioms_are_verified() {
+		for idiom in bfidiom::builtin_idioms().iter() {
+			assert_eq!(BFProgram::verify_idiom(idiom), Ok(()), "{}", idiom.op.name());
+		}
+	}
+
+	#[test]
+	fn idioms_that_dont_match_their_snippets_are_rejected() {
+		for line in ["copy(1,3) [->+>+<<]>>[-<<+>>]<<", "copy(2,1) [->+>+<<]>>[-<<+>>]<<", "divmod-keep [->-[>+>>]>[+[-<+>]>+>>]<<<<<]"].iter() {
+			assert!(BFProgram::verify_idiom(&Idiom::parse(line).unwrap()).is_err(), "{}", line);
+		}
+	}
+
+	#[test]
+	fn report_says_what_was_done_where() {
+		let mut program = BFProgram::without_idioms();
+		program.enable_opt_report();
+		program.compile(&b"[.]+\n  [->+<]>[>]\n[-.]"[..]);
+		let report = program.get_opt_report().unwrap().to_string();
+		assert_eq!(report.lines().collect::<Vec<&str>>(), vec![
+			"1:1-1:3 counted-loop: [.] rejected: the body has I/O, scans or loops that weren't optimized",
+			"1:1-1:3 dead-code: [.] => removed, the loop cell is always 0 here",
+			// The loop's known count is folded in, which makes the + before it dead.
+			"1:4-2:3 fold-writes: A(0;1)S(0;0) => S(0;0)",
+			"2:3-2:3 dead-code: Ma(0;1*1) => A(1;1)S(0;0)",
+			"2:3-2:8 counted-loop: [->+<] => Ma(0;1*1)",
+			"2:10-2:12 find-zero: [>] => Fzr(1)",
+			"3:1-3:4 counted-loop: [-.] rejected: the body has I/O, scans or loops that weren't optimized",
+			// Scans always stop on a zero cell.
+			"3:1-3:4 dead-code: [-.] => removed, the loop cell is always 0 here",
+		]);
+	}
+
+	#[test]
+	fn report_has_moves_sunk_and_code_run_at_compile_time() {
+		let mut program = BFProgram::without_idioms();
+		program.enable_opt_report();
+		program.enable_partial_eval(DEFAULT_PARTIAL_EVAL_STEPS);
+		program.compile(&b">+>++<.,"[..]);
+		let report = program.get_opt_report().unwrap().to_string();
+		assert_eq!(report.lines().collect::<Vec<&str>>(), vec![
+			"1:1-1:8 sink-moves: >+>++<., => A(1;1)A(2;2)O(1;1)I(1;1)>",
+			"1:2-1:7 partial-eval: A(1;1)A(2;2)O(1;1) => Ob(1)S(1;1)S(2;2)",
+		]);
+	}
+}
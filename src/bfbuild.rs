@@ -0,0 +1,45 @@
+use std::{env, fs, process};
+use std::path::Path;
+use std::process::Command;
+use ::bf::BF_MEMORY_SIZE;
+use ::bfllvm::{BFLLVMProgram, EmitKind};
+
+/// Runtime that the `bf` function in a program's object file is linked against.
+const RUNTIME_SOURCE: &str = include_str!("bfruntime.c");
+
+/// Links a compiled program with the runtime into an executable at `output`, using the
+/// system's C compiler (or the one in `CC`).
+pub fn build_executable(program: &BFLLVMProgram, output: &Path) -> Result<(), String> {
+	let build_dir = env::temp_dir().join(format!("bf-build-{}", process::id()));
+	fs::create_dir_all(&build_dir)
+		.map_err(|err| format!("Failed to create {}: {}", build_dir.display(), err))?;
+	let result = build_in_dir(program, output, &build_dir);
+	let _ = fs::remove_dir_all(&build_dir);
+	result
+}
+
+fn build_in_dir(program: &BFLLVMProgram, output: &Path, build_dir: &Path) -> Result<(), String> {
+	let object = build_dir.join("program.o");
+	program.emit(EmitKind::Obj, &object)?;
+
+	let runtime = build_dir.join("runtime.c");
+	fs::write(&runtime, RUNTIME_SOURCE)
+		.map_err(|err| format!("Failed to write {}: {}", runtime.display(), err))?;
+
+	let cc = env::var("CC").unwrap_or_else(|_| "cc".to_string());
+	let status = Command::new(&cc)
+		.arg("-O2")
+		.arg(format!("-DBF_MEMORY_SIZE={}", BF_MEMORY_SIZE))
+		.arg(&object)
+		.arg(&runtime)
+		.arg("-o")
+		.arg(output)
+		.status()
+		.map_err(|err| format!("Failed to run {}: {}", cc, err))?;
+
+	if status.success() {
+		Ok(())
+	} else {
+		Err(format!("{} failed to link the program ({})", cc, status))
+	}
+}
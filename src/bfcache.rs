@@ -0,0 +1,93 @@
+use std::{fs, mem, process};
+use std::collections::hash_map::DefaultHasher;
+use std::ffi::CStr;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use libc::{self, c_void};
+use llvm::core::LLVMContextCreate;
+use ::bf::BFProgram;
+use ::bfdebuginfo;
+
+/// Bumped when what's in a cached object changes in a way that the key doesn't cover.
+const CACHE_FORMAT: u32 = 1;
+
+/// A directory of object files compiled by LLVM, named after a hash of everything that
+/// went into compiling them.
+pub struct JITCache {
+	dir: PathBuf,
+}
+
+impl JITCache {
+	pub fn new(dir: &Path) -> JITCache {
+		JITCache { dir: dir.to_path_buf() }
+	}
+
+	/// Key of the object for `program` compiled with these options.
+	///
+	/// The program is hashed after it has been parsed and optimized, which covers the
+	/// source along with everything else that changes what is compiled, like idioms and
+	/// partial evaluation. The files that bf and LLVM were loaded from are part of it too,
+	/// so that a different build of either doesn't reuse code compiled by the old one.
+	pub fn key(&self, program: &BFProgram, opt_level: u32, checked: bool, profile: bool, debug_source: Option<&Path>) -> String {
+		// Two differently seeded hashes, so that a collision is too unlikely to worry about.
+		let mut hashers = [DefaultHasher::new(), DefaultHasher::new()];
+		for (seed, hasher) in hashers.iter_mut().enumerate() {
+			seed.hash(hasher);
+			CACHE_FORMAT.hash(hasher);
+			loaded_file_identity(loaded_file_identity as *const ()).hash(hasher);
+			loaded_file_identity(LLVMContextCreate as *const ()).hash(hasher);
+
+			format!("{:?}", program.get_instructions()).hash(hasher);
+			for idx in 0..program.get_instructions().len() {
+				let location = program.source_location(idx);
+				(location.line, location.column).hash(hasher);
+			}
+			opt_level.hash(hasher);
+			checked.hash(hasher);
+			profile.hash(hasher);
+			debug_source.map(bfdebuginfo::absolute).hash(hasher);
+		}
+		format!("{:016x}{:016x}", hashers[0].finish(), hashers[1].finish())
+	}
+
+	/// The object stored under `key`, if there is one.
+	pub fn load(&self, key: &str) -> Option<Vec<u8>> {
+		fs::read(self.object_path(key)).ok()
+	}
+
+	/// Stores `object` under `key`. Other processes using the same directory at the same
+	/// time only ever see whole objects.
+	pub fn store(&self, key: &str, object: &[u8]) -> Result<(), String> {
+		fs::create_dir_all(&self.dir)
+			.map_err(|err| format!("Failed to create {}: {}", self.dir.display(), err))?;
+		let temp_path = self.dir.join(format!(".{}.{}.tmp", key, process::id()));
+		fs::write(&temp_path, object)
+			.map_err(|err| format!("Failed to write {}: {}", temp_path.display(), err))?;
+
+		let path = self.object_path(key);
+		fs::rename(&temp_path, &path).map_err(|err| {
+			let _ = fs::remove_file(&temp_path);
+			format!("Failed to write {}: {}", path.display(), err)
+		})
+	}
+
+	fn object_path(&self, key: &str) -> PathBuf {
+		self.dir.join(key).with_extension("o")
+	}
+}
+
+/// Path, size and modification time of the executable or shared library that `address`
+/// is in.
+fn loaded_file_identity(address: *const ()) -> Option<(PathBuf, u64, Option<::std::time::SystemTime>)> {
+	let path = unsafe {
+		let mut info: libc::Dl_info = mem::zeroed();
+		if libc::dladdr(address as *const c_void, &mut info) == 0 || info.dli_fname.is_null() {
+			return None;
+		}
+		PathBuf::from(CStr::from_ptr(info.dli_fname).to_string_lossy().into_owned())
+	};
+	// The main executable can be reported by the name that it was run as.
+	let path = if path.is_absolute() { path } else { ::std::env::current_exe().ok()? };
+	let metadata = fs::metadata(&path).ok()?;
+	Some((path, metadata.len(), metadata.modified().ok()))
+}
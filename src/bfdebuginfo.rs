@@ -0,0 +1,76 @@
+use std::{env, ptr};
+use std::os::raw::c_uint;
+use std::path::{Path, PathBuf};
+use llvm;
+use llvm::core::*;
+use llvm::debuginfo::*;
+use llvm::prelude::{LLVMDIBuilderRef, LLVMMetadataRef};
+use ::bfreport::SourceLocation;
+
+/// Builds the DWARF debug info that maps a compiled program back to its `.bf` file,
+/// with the whole program as one function.
+pub struct DebugInfo {
+	context: llvm::prelude::LLVMContextRef,
+	builder: LLVMDIBuilderRef,
+	subprogram: LLVMMetadataRef,
+}
+
+impl DebugInfo {
+	/// Starts the debug info for `function`, which was compiled from the file at `source`.
+	pub unsafe fn new(context: llvm::prelude::LLVMContextRef, module: llvm::prelude::LLVMModuleRef,
+		function: llvm::prelude::LLVMValueRef, source: &Path) -> DebugInfo {
+		let i32_type = LLVMInt32TypeInContext(context);
+		let debug_version = LLVMValueAsMetadata(LLVMConstInt(i32_type, LLVMDebugMetadataVersion() as u64, 0));
+		add_module_flag(module, "Debug Info Version", debug_version);
+		add_module_flag(module, "Dwarf Version", LLVMValueAsMetadata(LLVMConstInt(i32_type, 4, 0)));
+
+		let builder = LLVMCreateDIBuilder(module);
+		// Debuggers look for the source relative to the directory, so it has to be absolute.
+		let source = absolute(source);
+		let name = source.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+		let directory = source.parent().map(|dir| dir.to_string_lossy().into_owned()).unwrap_or_default();
+		let file = LLVMDIBuilderCreateFile(builder, name.as_ptr() as *const _, name.len(),
+			directory.as_ptr() as *const _, directory.len());
+
+		let producer = "bf";
+		let empty = "";
+		// The builder attaches everything created after this to the compile unit.
+		LLVMDIBuilderCreateCompileUnit(builder, LLVMDWARFSourceLanguage::LLVMDWARFSourceLanguageC, file,
+			producer.as_ptr() as *const _, producer.len(), 0, empty.as_ptr() as *const _, 0, 0,
+			empty.as_ptr() as *const _, 0, LLVMDWARFEmissionKind::LLVMDWARFEmissionKindLineTablesOnly, 0, 0, 0,
+			empty.as_ptr() as *const _, 0, empty.as_ptr() as *const _, 0);
+
+		let function_type = LLVMDIBuilderCreateSubroutineType(builder, file, [].as_mut_ptr(), 0, LLVMDIFlagZero);
+		let function_name = "bf";
+		let subprogram = LLVMDIBuilderCreateFunction(builder, file, function_name.as_ptr() as *const _,
+			function_name.len(), function_name.as_ptr() as *const _, function_name.len(), file, 1,
+			function_type, 0, 1, 1, LLVMDIFlagZero, 0);
+		LLVMSetSubprogram(function, subprogram);
+
+		DebugInfo { context, builder, subprogram }
+	}
+
+	/// Attributes the instructions that `builder` builds from now on to `location`.
+	pub unsafe fn set_location(&self, builder: llvm::prelude::LLVMBuilderRef, location: SourceLocation) {
+		let location = LLVMDIBuilderCreateDebugLocation(self.context, location.line as c_uint,
+			location.column as c_uint, self.subprogram, ptr::null_mut());
+		LLVMSetCurrentDebugLocation2(builder, location);
+	}
+
+	/// Finishes the debug info. Has to be done before the module is verified.
+	pub unsafe fn finish(self) {
+		LLVMDIBuilderFinalize(self.builder);
+		LLVMDisposeDIBuilder(self.builder);
+	}
+}
+
+unsafe fn add_module_flag(module: llvm::prelude::LLVMModuleRef, key: &str, value: LLVMMetadataRef) {
+	LLVMAddModuleFlag(module, llvm::LLVMModuleFlagBehavior::LLVMModuleFlagBehaviorWarning, key.as_ptr() as *const _, key.len(), value);
+}
+
+pub fn absolute(path: &Path) -> PathBuf {
+	path.canonicalize().unwrap_or_else(|_| match env::current_dir() {
+		Ok(dir) => dir.join(path),
+		Err(_) => path.to_path_buf(),
+	})
+}
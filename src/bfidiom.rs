@@ -0,0 +1,300 @@
+use std::io::Write;
+use ::bf::{BFCellValue, BF_MEMORY_SIZE};
+
+/// Dedicated implementation of a well known BF snippet. Offsets are relative to the
+/// cell the snippet starts on, and every snippet has to end on that same cell.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IdiomOp {
+	/// `n d 0 0 0 0` becomes `0 d-n%d n%d n/d 0 0`.
+	/// With `keep_dividend`, `n x d 0 0 0 0` becomes `0 x+n d-n%d n%d n/d 0`.
+	DivMod { keep_dividend: bool },
+
+	/// Sets the cell to 1 if it's equal to the cell at `y` and 0 otherwise, clearing both temps.
+	Equals { y: isize, temp0: isize, temp1: isize },
+
+	/// Adds the cell to the cell at `to` through `temp`, which is added back to the cell and cleared.
+	Copy { to: isize, temp: isize },
+
+	/// Prints the cell in decimal. The 9 cells to the right have to be 0.
+	PrintDecimal,
+}
+
+impl IdiomOp {
+	/// Parses an op written like `divmod`, `copy(1,2)` or `equals(1,2,3)`.
+	pub fn parse(text: &str) -> Result<IdiomOp, String> {
+		let (name, args) = match text.find('(') {
+			Some(open) => {
+				if !text.ends_with(')') {
+					return Err(format!("Missing ')' in '{}'", text));
+				}
+				let args: Result<Vec<isize>, _> = text[(open + 1)..(text.len() - 1)]
+					.split(',')
+					.map(|arg| arg.trim().parse::<isize>())
+					.collect();
+				match args {
+					Ok(args) => (&text[..open], args),
+					Err(err) => return Err(format!("Bad argument in '{}': {}", text, err)),
+				}
+			},
+			None => (text, Vec::new()),
+		};
+
+		match (name, args.len()) {
+			("divmod", 0) => Ok(IdiomOp::DivMod { keep_dividend: false }),
+			("divmod-keep", 0) => Ok(IdiomOp::DivMod { keep_dividend: true }),
+			("equals", 3) => Ok(IdiomOp::Equals { y: args[0], temp0: args[1], temp1: args[2] }),
+			("copy", 2) => Ok(IdiomOp::Copy { to: args[0], temp: args[1] }),
+			("print-decimal", 0) => Ok(IdiomOp::PrintDecimal),
+			_ => Err(format!("Unknown idiom op '{}' with {} arguments", name, args.len())),
+		}
+	}
+
+	pub fn name(&self) -> &'static str {
+		match *self {
+			IdiomOp::DivMod { keep_dividend: false } => "divmod",
+			IdiomOp::DivMod { keep_dividend: true } => "divmod-keep",
+			IdiomOp::Equals { .. } => "equals",
+			IdiomOp::Copy { .. } => "copy",
+			IdiomOp::PrintDecimal => "print-decimal",
+		}
+	}
+
+	/// Cells that have to be 0 for the op to do the same thing as its snippet.
+	pub fn zero_cells(&self) -> Vec<isize> {
+		match self {
+			&IdiomOp::DivMod { keep_dividend: false } => vec![2, 3, 4, 5],
+			&IdiomOp::DivMod { keep_dividend: true } => vec![3, 4, 5, 6],
+			&IdiomOp::Equals { .. } | &IdiomOp::Copy { .. } => Vec::new(),
+			&IdiomOp::PrintDecimal => (1..10).collect(),
+		}
+	}
+
+	/// Every cell the op reads or writes.
+	pub fn touched_offsets(&self) -> Vec<isize> {
+		match *self {
+			IdiomOp::DivMod { keep_dividend: false } => vec![0, 1, 2, 3, 4, 5],
+			IdiomOp::DivMod { keep_dividend: true } => vec![0, 1, 2, 3, 4, 5, 6],
+			IdiomOp::Equals { y, temp0, temp1 } => vec![0, y, temp0, temp1],
+			IdiomOp::Copy { to, temp } => vec![0, to, temp],
+			IdiomOp::PrintDecimal => (0..10).collect(),
+		}
+	}
+
+	/// Returns true if the op can be used instead of its snippet with the cell at `base`.
+	/// Otherwise the snippet has to run as it's written.
+	pub fn applies(&self, memory: &[BFCellValue], base: usize) -> bool {
+		let cell = |offset: isize| -> Option<BFCellValue> {
+			let idx = base as isize + offset;
+			if idx >= 0 && idx < BF_MEMORY_SIZE as isize { Some(memory[idx as usize]) } else { None }
+		};
+
+		if self.touched_offsets().iter().any(|&offset| cell(offset).is_none()) { return false }
+		if self.zero_cells().iter().any(|&offset| cell(offset) != Some(0)) { return false }
+
+		match self {
+			// Dividing by 1 walks off to the right forever.
+			&IdiomOp::DivMod { keep_dividend } => {
+				let divisor = cell(if keep_dividend { 2 } else { 1 });
+				cell(0) == Some(0) || divisor != Some(1)
+			},
+			_ => true,
+		}
+	}
+
+	/// Runs the op with the cell at `base`. `applies` has to have returned true.
+	pub fn run<W: Write>(&self, memory: &mut [BFCellValue], base: usize, stdout: &mut W) {
+		let at = |offset: isize| (base as isize + offset) as usize;
+
+		match *self {
+			IdiomOp::DivMod { keep_dividend } => {
+				let n = memory[base] as usize;
+				let divisor_cell = if keep_dividend { at(2) } else { at(1) };
+				// A divisor of 0 acts like the cell size since it wraps around.
+				let d = if memory[divisor_cell] == 0 { BFCellValue::MAX as usize + 1 } else { memory[divisor_cell] as usize };
+				memory[base] = 0;
+				if keep_dividend {
+					memory[at(1)] = memory[at(1)].wrapping_add(n as BFCellValue);
+				}
+				memory[divisor_cell] = (d - n % d) as BFCellValue;
+				memory[divisor_cell + 1] = (n % d) as BFCellValue;
+				memory[divisor_cell + 2] = (n / d) as BFCellValue;
+			},
+
+			IdiomOp::Equals { y, temp0, temp1 } => {
+				memory[base] = if memory[base] == memory[at(y)] { 1 } else { 0 };
+				memory[at(temp0)] = 0;
+				memory[at(temp1)] = 0;
+			},
+
+			IdiomOp::Copy { to, temp } => {
+				let value = memory[base];
+				memory[at(to)] = memory[at(to)].wrapping_add(value);
+				memory[base] = value.wrapping_add(memory[at(temp)]);
+				memory[at(temp)] = 0;
+			},
+
+			IdiomOp::PrintDecimal => {
+				if let Err(err) = write!(stdout, "{}", memory[base]) {
+					println!("Error while outputting chars: {}", err);
+				}
+			},
+		}
+	}
+}
+
+/// A BF snippet that is replaced with an `IdiomOp` wherever it shows up in a program.
+#[derive(Debug, Clone)]
+pub struct Idiom {
+	/// The snippet with everything but BF commands removed.
+	pub pattern: Vec<u8>,
+	pub op: IdiomOp,
+}
+
+impl Idiom {
+	pub fn new(pattern: &str, op: IdiomOp) -> Idiom {
+		Idiom {
+			pattern: pattern.bytes().filter(|&ch| is_bf_char(ch)).collect(),
+			op,
+		}
+	}
+
+	/// Parses a line written as `<op> <snippet>`, like `copy(1,2) [->+>+<<]>>[-<<+>>]<<`.
+	pub fn parse(line: &str) -> Result<Idiom, String> {
+		let line = line.trim();
+		let split = line.find(char::is_whitespace).unwrap_or(line.len());
+		let op = IdiomOp::parse(&line[..split])?;
+		let idiom = Idiom::new(&line[split..], op);
+		if idiom.pattern.is_empty() {
+			return Err(format!("No snippet for idiom '{}'", line));
+		}
+
+		let mut depth = 0isize;
+		for &ch in idiom.pattern.iter() {
+			if ch == b'[' { depth += 1; }
+			if ch == b']' { depth -= 1; }
+			if depth < 0 { break }
+		}
+		if depth != 0 {
+			return Err(format!("Unbalanced loops in idiom '{}'", line));
+		}
+		Ok(idiom)
+	}
+
+	/// Parses a file with one idiom per line. Empty lines and lines starting with '#' are ignored.
+	pub fn parse_all(text: &str) -> Result<Vec<Idiom>, String> {
+		text.lines()
+			.enumerate()
+			.filter(|&(_, line)| !line.trim().is_empty() && !line.trim().starts_with('#'))
+			.map(|(idx, line)| Idiom::parse(line).map_err(|err| format!("line {}: {}", idx + 1, err)))
+			.collect()
+	}
+}
+
+fn is_bf_char(ch: u8) -> bool {
+	ch == b'>' || ch == b'<' ||
+	ch == b'+' || ch == b'-' ||
+	ch == b'.' || ch == b',' ||
+	ch == b'[' || ch == b']'
+}
+
+/// Snippets from https://esolangs.org/wiki/Brainfuck_algorithms
+pub fn builtin_idioms() -> Vec<Idiom> {
+	vec![
+		Idiom::new("[->-[>+>>]>[+[-<+>]>+>>]<<<<<]", IdiomOp::DivMod { keep_dividend: false }),
+		Idiom::new("[->+>-[>+>>]>[+[-<+>]>+>>]<<<<<<]", IdiomOp::DivMod { keep_dividend: true }),
+		Idiom::new(">>[-]>[-]<<<[>>>+<<<-]+>[>>-<+<-]>[<+>-]>[<<<->>>[-]]<<<",
+			IdiomOp::Equals { y: 1, temp0: 2, temp1: 3 }),
+		Idiom::new("[->+>+<<]>>[-<<+>>]<<", IdiomOp::Copy { to: 1, temp: 2 }),
+		Idiom::new(">>++++++++++<<[->+>-[>+>>]>[+[-<+>]>+>>]<<<<<<]>>[-]>>>++++++++++<[->-[>+>>]>[+[-<+>]>+>>]\
+			<<<<<]>[-]>>[>++++++[-<++++++++>]<.<<+>+>[-]]<[<[->-<]++++++[->++++++++<]>.[-]]<<++++++\
+			[-<++++++++>]<.[-]<<[-<+>]<", IdiomOp::PrintDecimal),
+	]
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn ops_are_parsed_by_name() {
+		assert_eq!(IdiomOp::parse("divmod"), Ok(IdiomOp::DivMod { keep_dividend: false }));
+		assert_eq!(IdiomOp::parse("divmod-keep"), Ok(IdiomOp::DivMod { keep_dividend: true }));
+		assert_eq!(IdiomOp::parse("equals(1, 2, 3)"), Ok(IdiomOp::Equals { y: 1, temp0: 2, temp1: 3 }));
+		assert_eq!(IdiomOp::parse("copy(-1,2)"), Ok(IdiomOp::Copy { to: -1, temp: 2 }));
+		assert_eq!(IdiomOp::parse("print-decimal"), Ok(IdiomOp::PrintDecimal));
+	}
+
+	#[test]
+	fn bad_ops_are_rejected() {
+		for text in ["copy(1,2", "copy(1,x)", "copy(1)", "divmod(1)", "multiply"].iter() {
+			assert!(IdiomOp::parse(text).is_err(), "{}", text);
+		}
+	}
+
+	#[test]
+	fn idioms_are_parsed_from_lines() {
+		let idiom = Idiom::parse("  copy(1,2) [->+>+<<] and back >>[-<<+>>]<<").unwrap();
+		assert_eq!(idiom.pattern, b"[->+>+<<]>>[-<<+>>]<<".to_vec());
+		assert_eq!(idiom.op, IdiomOp::Copy { to: 1, temp: 2 });
+
+		assert!(Idiom::parse("copy(1,2)").is_err());
+		assert!(Idiom::parse("copy(1,2) no commands").is_err());
+		assert!(Idiom::parse("copy(1,2) [->+<").is_err());
+		assert!(Idiom::parse("copy(1,2) ][").is_err());
+	}
+
+	#[test]
+	fn idiom_files_skip_comments_and_blank_lines() {
+		let idioms = Idiom::parse_all("# copies\n\ncopy(1,2) [->+>+<<]>>[-<<+>>]<<\n  \ndivmod [->-[>+>>]>[+[-<+>]>+>>]<<<<<]\n").unwrap();
+		assert_eq!(idioms.len(), 2);
+		assert_eq!(idioms[1].op, IdiomOp::DivMod { keep_dividend: false });
+
+		let err = Idiom::parse_all("# copies\ncopy(1,2) [->+>+<<]>>[-<<+>>]<<\ncopy(1,2) [\n").unwrap_err();
+		assert!(err.starts_with("line 3: "), "{}", err);
+	}
+
+	#[test]
+	fn ops_only_apply_to_tapes_that_they_work_on() {
+		let mut memory = vec![0; BF_MEMORY_SIZE];
+		let divmod = IdiomOp::DivMod { keep_dividend: false };
+		memory[10] = 7;
+		memory[11] = 1;
+		assert!(!divmod.applies(&memory, 10));
+		memory[11] = 2;
+		assert!(divmod.applies(&memory, 10));
+		memory[13] = 1;
+		assert!(!divmod.applies(&memory, 10));
+
+		let copy = IdiomOp::Copy { to: -1, temp: 1 };
+		assert!(copy.applies(&memory, 1));
+		assert!(!copy.applies(&memory, 0));
+		assert!(copy.applies(&memory, BF_MEMORY_SIZE - 2));
+		assert!(!copy.applies(&memory, BF_MEMORY_SIZE - 1));
+	}
+
+	#[test]
+	fn ops_do_what_their_snippets_do() {
+		let mut memory = vec![0; 16];
+		let mut output = Vec::new();
+
+		memory[..2].copy_from_slice(&[17, 5]);
+		IdiomOp::DivMod { keep_dividend: false }.run(&mut memory, 0, &mut output);
+		assert_eq!(memory[..6], [0, 3, 2, 3, 0, 0]);
+
+		memory[..7].copy_from_slice(&[17, 1, 5, 0, 0, 0, 0]);
+		IdiomOp::DivMod { keep_dividend: true }.run(&mut memory, 0, &mut output);
+		assert_eq!(memory[..7], [0, 18, 3, 2, 3, 0, 0]);
+
+		memory[..4].copy_from_slice(&[9, 9, 4, 4]);
+		IdiomOp::Equals { y: 1, temp0: 2, temp1: 3 }.run(&mut memory, 0, &mut output);
+		assert_eq!(memory[..4], [1, 9, 0, 0]);
+
+		memory[..3].copy_from_slice(&[200, 100, 1]);
+		IdiomOp::Copy { to: 1, temp: 2 }.run(&mut memory, 0, &mut output);
+		assert_eq!(memory[..3], [201, 44, 0]);
+
+		memory[..10].copy_from_slice(&[107, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+		IdiomOp::PrintDecimal.run(&mut memory, 0, &mut output);
+		assert_eq!(output, b"107".to_vec());
+	}
+}
@@ -1,10 +1,24 @@
 use std::io::{self, Read, Write, StdinLock, StdoutLock};
 use llvm;
+use llvm::analysis::*;
+use llvm::bit_writer::*;
 use llvm::core::*;
 use llvm::execution_engine::*;
 use llvm::target::*;
-use std::{mem, ptr};
-use ::bf::{BFInstr, BF_MEMORY_SIZE, BFCellValue};
+use llvm::target_machine::*;
+use llvm::transforms::pass_manager_builder::*;
+use llvm::transforms::scalar::*;
+use llvm::transforms::util::*;
+use std::{mem, ptr, slice};
+use std::cell::Cell;
+use std::ffi::{CStr, CString};
+use std::path::{Path, PathBuf};
+use ::bf::{BFInstr, BFProgram, BF_MEMORY_SIZE, BFCellValue, RunError};
+use ::bfdebuginfo::DebugInfo;
+use ::bfloop::{Affine, CellUpdate, LoopSummary};
+use ::bforc::ObjectJIT;
+use ::bfreport::SourceLocation;
+use ::bftape::{self, Tape};
 
 macro_rules! cstring {
 	($s:expr) => (
@@ -12,45 +26,206 @@ macro_rules! cstring {
 	)
 }
 
+/// Kinds of files that a compiled program can be written to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EmitKind {
+	LlvmIr,
+	LlvmBc,
+	Asm,
+	Obj,
+}
+
+impl EmitKind {
+	/// Parses the names used by `--emit`.
+	pub fn parse(name: &str) -> Option<EmitKind> {
+		match name {
+			"llvm-ir" => Some(EmitKind::LlvmIr),
+			"llvm-bc" => Some(EmitKind::LlvmBc),
+			"asm" => Some(EmitKind::Asm),
+			"obj" => Some(EmitKind::Obj),
+			_ => None,
+		}
+	}
+
+	pub fn extension(&self) -> &'static str {
+		match *self {
+			EmitKind::LlvmIr => "ll",
+			EmitKind::LlvmBc => "bc",
+			EmitKind::Asm => "s",
+			EmitKind::Obj => "o",
+		}
+	}
+}
+
+/// Size of the buffer that JIT compiled code writes output to before it's passed to stdout.
+const OUTPUT_BUFFER_SIZE: u64 = 4096;
+
+/// Outputs repeated more than this many times are written by the runtime instead of
+/// being stored in the output buffer one at a time.
+const MAX_INLINE_OUTPUT_REPEATS: usize = 8;
+
+/// Returns 0 when the program finishes, or 1 if it was stopped by `__bf_out_of_bounds`.
+type CompiledBFFn = extern "C" fn(*mut u8, *mut StdinLock, *mut StdoutLock) -> u8;
+
+/// Same as `CompiledBFFn`, but for a single loop, which starts with the data pointer at
+/// the index that the last argument points to and leaves it there.
+type CompiledLoopFn = extern "C" fn(*mut u8, *mut StdinLock, *mut StdoutLock, *mut i64) -> u8;
+
+thread_local! {
+	/// Set by `__bf_out_of_bounds` for `run` to return once the compiled code has stopped.
+	static OUT_OF_BOUNDS: Cell<Option<RunError>> = const { Cell::new(None) };
+}
+
 struct BFLLVMInfo {
 	context: *mut llvm::LLVMContext,
 	module: *mut llvm::LLVMModule,
 	builder: *mut llvm::LLVMBuilder,
 	execution_engine: LLVMExecutionEngineRef,
 	llvm_bf_fn: *mut llvm::LLVMValue,
-	compiled_bf_fn: extern "C" fn(*mut u8, *mut StdinLock, *mut StdoutLock) -> (),
-	output: *mut i8,
+	compiled_bf_fn: Option<CompiledBFFn>,
 	ready: bool,
-	i32_type: *mut llvm::LLVMType,
+	opt_level: u32,
+	i64_type: *mut llvm::LLVMType,
 	i8_type: *mut llvm::LLVMType,
-	var_data_ptr: *mut llvm::LLVMValue,
-	var_data_ptr_name: *const i8,
+	i8_ptr_type: *mut llvm::LLVMType,
+	tape_ptr: *mut llvm::LLVMValue,
+	var_data_idx: *mut llvm::LLVMValue,
+	/// Where a compiled loop leaves the data index when it's done, null for a whole program.
+	data_idx_out: *mut llvm::LLVMValue,
 
 	bf_output_fn: *mut llvm::LLVMValue,
+	bf_output_bytes_fn: *mut llvm::LLVMValue,
+	bf_flush_fn: *mut llvm::LLVMValue,
 	bf_input_fn: *mut llvm::LLVMValue,
+	bf_out_of_bounds_fn: *mut llvm::LLVMValue,
+	output_buffer: *mut llvm::LLVMValue,
+	var_output_len: *mut llvm::LLVMValue,
 	var_ptr_stdin: *mut llvm::LLVMValue,
 	var_ptr_stdout: *mut llvm::LLVMValue,
 }
 
-pub struct BFLLVMProgram {
-	memory: Vec<BFCellValue>,
+/// What's needed to build range checks, when they're turned on or the tape has guard pages.
+struct BoundsChecks {
+	/// Whether going off of the upper end of the tape is left to the guard region after
+	/// it, because bounds checks weren't asked for. The lower end is checked either way,
+	/// since the cells before the first one share its page and don't fault.
+	guarded: bool,
+
+	/// Where each instruction came from, for the trap to report.
+	locations: Vec<SourceLocation>,
+
+	/// Offsets from the data pointer of the cells that are known to be on the tape at
+	/// the point that IR is being built, as (lowest, highest).
+	checked: Option<(isize, isize)>,
+
+	/// `checked` from before each loop that IR is being built for, if the loop leaves
+	/// the data pointer where it found it.
+	loops: Vec<Option<(isize, isize)>>,
+
+	/// Whether the output buffer might have something in it at the point that IR is
+	/// being built. Output that's still in it when the program faults in a guard region
+	/// is lost, so it's handed to the runtime first.
+	output_pending: bool,
+
+	/// `output_pending` from before each loop that IR is being built for.
+	loops_output_pending: Vec<bool>,
+}
+
+impl BoundsChecks {
+	fn new(guarded: bool) -> BoundsChecks {
+		BoundsChecks {
+			guarded,
+			locations: Vec::new(),
+			checked: None,
+			loops: Vec::new(),
+			output_pending: false,
+			loops_output_pending: Vec::new(),
+		}
+	}
+
+	fn covers(&self, offset: isize) -> bool {
+		match self.checked {
+			Some((lowest, highest)) => lowest <= offset && offset <= highest,
+			None => false,
+		}
+	}
+
+	/// Every cell between two cells that are on the tape is also on it.
+	fn add(&mut self, offset: isize) {
+		self.checked = Some(match self.checked {
+			Some((lowest, highest)) => (lowest.min(offset), highest.max(offset)),
+			None => (offset, offset),
+		});
+	}
+
+	fn moved(&mut self, amount: isize) {
+		self.checked = self.checked.map(|(lowest, highest)| (lowest - amount, highest - amount));
+	}
+
+	/// Keeps `output_pending` up to date once the IR for the instruction at `idx` is built.
+	fn track_output(&mut self, instructions: &[BFInstr], idx: usize) {
+		match instructions[idx] {
+			BFInstr::Output(_) | BFInstr::Out { .. } => self.output_pending = true,
+			// These hand the output buffer to the runtime.
+			BFInstr::Input(_) | BFInstr::In { .. } | BFInstr::OutputBytes(_) => self.output_pending = false,
+			// Output from one pass through the loop is still there at the start of the next.
+			BFInstr::LoopStart(_) => {
+				self.loops_output_pending.push(self.output_pending);
+				self.output_pending |= loop_has_output(instructions, idx);
+			},
+			// The loop might not have run at all.
+			BFInstr::LoopEnd(_) => self.output_pending |= self.loops_output_pending.pop().expect("No matching '[' for ']'"),
+			_ => {},
+		}
+	}
+}
+
+/// What's needed to count how many times each loop runs, when that's turned on.
+struct Profiling {
+	/// Global array of (entries, iterations) for each loop, in the order that they start.
+	/// Null if the program doesn't have any loops.
+	counters: *mut llvm::LLVMValue,
+
+	/// Number of loops that counters have been built for so far.
+	next_loop: usize,
+
+	/// Where the counters ended up once the program is ready to run.
+	address: Option<*const u64>,
+}
 
-	/// Only used during compilation.
-	pc: u32,
+pub struct BFLLVMProgram {
+	memory: Tape,
 	compiled: bool,
+	bounds_checks: Option<BoundsChecks>,
+	profiling: Option<Profiling>,
+	debug_source: Option<PathBuf>,
+	/// Holds the code when it was loaded from an object instead of compiled by MCJIT.
+	object_jit: Option<ObjectJIT>,
 	llvm_info: BFLLVMInfo,
 }
 
 impl BFLLVMProgram {
 	pub fn new() -> BFLLVMProgram {
-		let mut _mem = Vec::with_capacity(BF_MEMORY_SIZE);
-		_mem.resize(BF_MEMORY_SIZE, 0);
+		Self::with_llvm_info(unsafe { Self::create_llvm_info(false) })
+	}
+
+	/// A program that only compiles a single loop, with `compile_loop`, to be run on
+	/// another program's tape with `run_loop`.
+	pub fn for_loop() -> BFLLVMProgram {
+		Self::with_llvm_info(unsafe { Self::create_llvm_info(true) })
+	}
 
+	fn with_llvm_info(llvm_info: BFLLVMInfo) -> BFLLVMProgram {
 		BFLLVMProgram {
-			memory: _mem,
-			pc: 0,
+			memory: Tape::new(),
 			compiled: false,
-			llvm_info: unsafe { Self::create_llvm_info() }
+			// Without bounds checks the guard regions catch the program going off of the
+			// upper end of the tape, but not the lower end.
+			bounds_checks: if bftape::GUARD_PAGES { Some(BoundsChecks::new(true)) } else { None },
+			profiling: None,
+			debug_source: None,
+			object_jit: None,
+			llvm_info,
 		}
 	}
 
@@ -59,40 +234,67 @@ impl BFLLVMProgram {
 	}
 
 	unsafe fn clean_llvm_info(&mut self) {
-		LLVMDisposeExecutionEngine(self.llvm_info.execution_engine);
+		if !self.llvm_info.builder.is_null() {
+			LLVMDisposeBuilder(self.llvm_info.builder);
+		}
+		if !self.llvm_info.execution_engine.is_null() {
+			// The execution engine owns the module.
+			LLVMDisposeExecutionEngine(self.llvm_info.execution_engine);
+		} else {
+			LLVMDisposeModule(self.llvm_info.module);
+		}
 		LLVMContextDispose(self.llvm_info.context);
-		println!("Cleaned up LLVM");
 	}
 
-	unsafe fn finalize_llvm_info(&mut self) {
-		println!("Finalizing...");
-
+	unsafe fn finalize_llvm_info(&mut self) -> Result<(), String> {
 		let _bf_string = cstring!("bf");
 
-		LLVMBuildRetVoid(self.llvm_info.builder);
-		LLVMDisposeBuilder(self.llvm_info.builder);
-
-		// #TODO make sure these are completed successfully.
 		LLVMLinkInMCJIT();
-		LLVM_InitializeNativeTarget();
-		LLVM_InitializeNativeAsmPrinter();
+		initialize_native_target()?;
 
-		LLVMCreateExecutionEngineForModule(&mut self.llvm_info.execution_engine, self.llvm_info.module,
-			&mut self.llvm_info.output);
+		let mut options: LLVMMCJITCompilerOptions = mem::zeroed();
+		LLVMInitializeMCJITCompilerOptions(&mut options, mem::size_of::<LLVMMCJITCompilerOptions>());
+		options.OptLevel = self.llvm_info.opt_level;
+		let mut engine = ptr::null_mut();
+		let mut error = ptr::null_mut();
+		if LLVMCreateMCJITCompilerForModule(&mut engine, self.llvm_info.module,
+			&mut options, mem::size_of::<LLVMMCJITCompilerOptions>(), &mut error) != 0 {
+			return Err(format!("Failed to create the execution engine: {}", take_llvm_message(error)));
+		}
+		self.llvm_info.execution_engine = engine;
 
-		LLVMAddGlobalMapping(self.llvm_info.execution_engine, self.llvm_info.bf_output_fn, __bf_print_output as *mut _);
-		LLVMAddGlobalMapping(self.llvm_info.execution_engine, self.llvm_info.bf_input_fn, __bf_get_input as *mut _);
+		// Optimizing can remove the declarations of runtime functions that aren't called,
+		// so they're looked up again instead of using the values from `create_llvm_info`.
+		for &(name, address) in runtime_functions().iter() {
+			let name = CString::new(name).unwrap();
+			let function = LLVMGetNamedFunction(self.llvm_info.module, name.as_ptr());
+			if !function.is_null() {
+				LLVMAddGlobalMapping(self.llvm_info.execution_engine, function, address as *mut _);
+			}
+		}
 
 		let addr = LLVMGetFunctionAddress(self.llvm_info.execution_engine, _bf_string);
-		let f: extern "C" fn(*mut u8, *mut StdinLock, *mut StdoutLock) -> () = mem::transmute(addr);
+		if addr == 0 {
+			return Err("Failed to JIT compile the bf function.".to_string());
+		}
+		let f: CompiledBFFn = mem::transmute(addr as usize);
 
-		self.llvm_info.compiled_bf_fn = f;
-		self.llvm_info.ready = true;
+		if let Some(ref mut profiling) = self.profiling {
+			if !profiling.counters.is_null() {
+				let address = LLVMGetGlobalValueAddress(self.llvm_info.execution_engine, cstring!("bf_loop_counters"));
+				if address == 0 {
+					return Err("Failed to find the loop counters in the JIT compiled code.".to_string());
+				}
+				profiling.address = Some(address as usize as *const u64);
+			}
+		}
 
-		println!("Finalized LLVM info.");
+		self.llvm_info.compiled_bf_fn = Some(f);
+		self.llvm_info.ready = true;
+		Ok(())
 	}
 
-	unsafe fn create_llvm_info() -> BFLLVMInfo {
+	unsafe fn create_llvm_info(for_loop: bool) -> BFLLVMInfo {
 		let _bf_string = cstring!("bf");
 		let context = LLVMContextCreate();
 		let module = LLVMModuleCreateWithNameInContext(_bf_string, context);
@@ -100,11 +302,11 @@ impl BFLLVMProgram {
 
 		let i8_type = LLVMInt8TypeInContext(context);
 		let i8_ptr_type = LLVMPointerType(i8_type, 0);
+		let i64_type = LLVMInt64TypeInContext(context);
 		let void_type = LLVMVoidTypeInContext(context);
-		let void_ptr_type = LLVMPointerType(void_type, 0);
-		let i32_type = LLVMInt32TypeInContext(context);
 
-		let mut bf_output_function_args_type = [void_ptr_type, i8_type];
+		// The stdin and stdout locks are passed around as opaque pointers.
+		let mut bf_output_function_args_type = [i8_ptr_type, i8_type, i64_type];
 		let bf_output_function_type = LLVMFunctionType(
 			void_type,
 			bf_output_function_args_type.as_mut_ptr(),
@@ -114,19 +316,49 @@ impl BFLLVMProgram {
 		let bf_output_fn = LLVMAddFunction(module, cstring!("__bf_print_output"), bf_output_function_type);
 		LLVMSetFunctionCallConv(bf_output_fn, llvm::LLVMCallConv::LLVMCCallConv as u32);
 
-		let mut bf_input_function_args_type = [void_ptr_type];
+		let mut bf_output_bytes_function_args_type = [i8_ptr_type, i8_ptr_type, i64_type];
+		let bf_output_bytes_function_type = LLVMFunctionType(
+			void_type,
+			bf_output_bytes_function_args_type.as_mut_ptr(),
+			bf_output_bytes_function_args_type.len() as u32,
+			0
+		);
+		let bf_output_bytes_fn = LLVMAddFunction(module, cstring!("__bf_print_bytes"), bf_output_bytes_function_type);
+		LLVMSetFunctionCallConv(bf_output_bytes_fn, llvm::LLVMCallConv::LLVMCCallConv as u32);
+
+		let bf_flush_fn = LLVMAddFunction(module, cstring!("__bf_flush_output"), bf_output_bytes_function_type);
+		LLVMSetFunctionCallConv(bf_flush_fn, llvm::LLVMCallConv::LLVMCCallConv as u32);
+
+		let mut bf_input_function_args_type = [i8_ptr_type, i8_ptr_type, i64_type];
 		let bf_input_function_type = LLVMFunctionType(
-			i8_type,
+			void_type,
 			bf_input_function_args_type.as_mut_ptr(),
 			bf_input_function_args_type.len() as u32,
 			0
 		);
-		let bf_input_fn = LLVMAddFunction(module, cstring!("__bf_get_input"), bf_input_function_type);; // #TODO oh shit
+		let bf_input_fn = LLVMAddFunction(module, cstring!("__bf_get_input"), bf_input_function_type);
 		LLVMSetFunctionCallConv(bf_input_fn, llvm::LLVMCallConv::LLVMCCallConv as u32);
 
-		let mut bf_function_args_type = [i8_ptr_type, void_ptr_type, void_ptr_type];
-		let bf_function_type = LLVMFunctionType(
+		// (line, column, cell)
+		let mut bf_out_of_bounds_function_args_type = [i64_type, i64_type, i64_type];
+		let bf_out_of_bounds_function_type = LLVMFunctionType(
 			void_type,
+			bf_out_of_bounds_function_args_type.as_mut_ptr(),
+			bf_out_of_bounds_function_args_type.len() as u32,
+			0
+		);
+		let bf_out_of_bounds_fn = LLVMAddFunction(module, cstring!("__bf_out_of_bounds"), bf_out_of_bounds_function_type);
+		LLVMSetFunctionCallConv(bf_out_of_bounds_fn, llvm::LLVMCallConv::LLVMCCallConv as u32);
+		// Programs almost never go off of the tape, so the checks are laid out for staying on it.
+		let cold = LLVMCreateEnumAttribute(context, LLVMGetEnumAttributeKindForName(cstring!("cold"), 4), 0);
+		LLVMAddAttributeAtIndex(bf_out_of_bounds_fn, llvm::LLVMAttributeFunctionIndex, cold);
+
+		let mut bf_function_args_type = vec![i8_ptr_type, i8_ptr_type, i8_ptr_type];
+		if for_loop {
+			bf_function_args_type.push(LLVMPointerType(i64_type, 0));
+		}
+		let bf_function_type = LLVMFunctionType(
+			i8_type,
 			bf_function_args_type.as_mut_ptr(),
 			bf_function_args_type.len() as u32,
 			0
@@ -144,280 +376,965 @@ impl BFLLVMProgram {
 		let ptr_stdin = LLVMGetParam(bf_function, 1);
 		let ptr_stdout = LLVMGetParam(bf_function, 2);
 
-		let mut var_ptr_stdin = LLVMBuildAlloca(builder, void_ptr_type, cstring!("stdin_lock_ptr"));
+		let var_ptr_stdin = LLVMBuildAlloca(builder, i8_ptr_type, cstring!("stdin_lock_ptr"));
 		LLVMBuildStore(builder, ptr_stdin, var_ptr_stdin);
-		let mut var_ptr_stdout = LLVMBuildAlloca(builder, void_ptr_type, cstring!("stdout_lock_ptr"));
+		let var_ptr_stdout = LLVMBuildAlloca(builder, i8_ptr_type, cstring!("stdout_lock_ptr"));
 		LLVMBuildStore(builder, ptr_stdout, var_ptr_stdout);
 
-		let mut var_data_ptr = LLVMBuildAlloca(builder, i8_ptr_type, cstring!("data_ptr"));
+		// Output is stored here and only handed to the runtime when the buffer is full,
+		// before reading input and at the end.
+		let output_buffer_type = LLVMArrayType(i8_type, OUTPUT_BUFFER_SIZE as u32);
+		let var_output_buffer = LLVMBuildAlloca(builder, output_buffer_type, cstring!("output_buffer"));
+		let output_buffer = LLVMBuildBitCast(builder, var_output_buffer, i8_ptr_type, cstring!("output_buffer_ptr"));
+		let var_output_len = LLVMBuildAlloca(builder, i64_type, cstring!("output_len"));
+		LLVMBuildStore(builder, LLVMConstInt(i64_type, 0, 0), var_output_len);
+
+		// Nothing else points into the tape while the program runs.
+		let noalias = LLVMCreateEnumAttribute(context, LLVMGetEnumAttributeKindForName(cstring!("noalias"), 7), 0);
+		LLVMAddAttributeAtIndex(bf_function, 1, noalias);
+
+		// The data pointer is kept as an index into the tape.
+		let var_data_idx = LLVMBuildAlloca(builder, i64_type, cstring!("data_idx"));
+		let data_idx_out = if for_loop { LLVMGetParam(bf_function, 3) } else { ptr::null_mut() };
+		let start_data_idx = if for_loop {
+			LLVMBuildLoad2(builder, i64_type, data_idx_out, cstring!("start_data_idx"))
+		} else {
+			LLVMConstInt(i64_type, 0, 0)
+		};
 		LLVMBuildStore(
 			builder,
-			ptr_memory,
-			var_data_ptr
+			start_data_idx,
+			var_data_idx
 		);
 
-		let mut execution_engine = mem::uninitialized();
-		let mut output = mem::zeroed();
-
-		println!("Intialized LLVM");
-
 		BFLLVMInfo {
-			context: context,
-			module: module,
-			builder: builder,
-			execution_engine: execution_engine,
+			context,
+			module,
+			builder,
+			execution_engine: ptr::null_mut(),
 			llvm_bf_fn: bf_function,
-			compiled_bf_fn: mem::transmute(ptr::null() as *const i8),
-			output: output,
+			compiled_bf_fn: None,
 			ready: false,
-			i32_type: i32_type,
-			i8_type: i8_type,
-			var_data_ptr: var_data_ptr,
-			var_data_ptr_name: cstring!("data_ptr"),
-			bf_output_fn: bf_output_fn,
-			bf_input_fn: bf_input_fn,
-			var_ptr_stdin: var_ptr_stdin,
-			var_ptr_stdout: var_ptr_stdout,
+			opt_level: 0,
+			i64_type,
+			i8_type,
+			i8_ptr_type,
+			tape_ptr: ptr_memory,
+			var_data_idx,
+			data_idx_out,
+			bf_output_fn,
+			bf_output_bytes_fn,
+			bf_flush_fn,
+			bf_input_fn,
+			bf_out_of_bounds_fn,
+			output_buffer,
+			var_output_len,
+			var_ptr_stdin,
+			var_ptr_stdout,
 		}
 	}
 
-	pub fn compile<R>(&mut self, mut input: R) where R: Read+Sized {
+	/// Makes the compiled program check that every cell it uses is on the tape, and stop
+	/// with the location of the instruction that went off of it instead of touching memory
+	/// outside of the tape. Has to be called before `compile`.
+	///
+	/// Loops that leave the data pointer where they found it check the cells that every
+	/// pass through them uses once before they start, so one that would go off the tape
+	/// stops before doing anything.
+	pub fn enable_bounds_checks(&mut self) {
+		if self.compiled { panic!("Bounds checks have to be enabled before compiling.") }
+		self.bounds_checks = Some(BoundsChecks::new(false));
+	}
+
+	/// Makes the compiled program count how many times each loop is entered and goes
+	/// around, for `profile_counts` to read once it has run. Has to be called before `compile`.
+	pub fn enable_profiling(&mut self) {
+		if self.compiled { panic!("Profiling has to be enabled before compiling.") }
+		self.profiling = Some(Profiling { counters: ptr::null_mut(), next_loop: 0, address: None });
+	}
+
+	/// Adds DWARF debug info that maps the compiled code to the line and column in
+	/// `source` that each instruction came from, for debuggers and profilers. Has to be
+	/// called before `compile`.
+	pub fn enable_debug_info(&mut self, source: &Path) {
+		if self.compiled { panic!("Debug info has to be enabled before compiling.") }
+		self.debug_source = Some(source.to_path_buf());
+	}
+
+	/// Builds the IR for a program that was already parsed and optimized.
+	pub fn compile(&mut self, program: &BFProgram) -> Result<(), String> {
+		if !self.llvm_info.data_idx_out.is_null() { panic!("Use compile_loop to compile a BFLLVMProgram made with for_loop.") }
+		let loops = loop_count(program);
+		if let Some(ref mut profiling) = self.profiling {
+			if loops > 0 {
+				unsafe {
+					let counters_type = LLVMArrayType(self.llvm_info.i64_type, 2 * loops as u32);
+					let counters = LLVMAddGlobal(self.llvm_info.module, counters_type, cstring!("bf_loop_counters"));
+					LLVMSetInitializer(counters, LLVMConstNull(counters_type));
+					profiling.counters = counters;
+				}
+			}
+		}
+		self.compile_range(program, 0, program.get_instructions().len())
+	}
+
+	/// Builds the IR for the loop in `program` that starts at instruction `start`.
+	pub fn compile_loop(&mut self, program: &BFProgram, start: usize) -> Result<(), String> {
+		if self.llvm_info.data_idx_out.is_null() { panic!("Only a BFLLVMProgram made with for_loop can compile a loop.") }
+		if self.profiling.is_some() { panic!("Only whole programs can be profiled.") }
+		let end = match program.get_instructions()[start] {
+			BFInstr::LoopStart(end) => end,
+			_ => panic!("There's no loop at instruction {}.", start),
+		};
+		self.compile_range(program, start, end + 1)
+	}
+
+	fn compile_range(&mut self, program: &BFProgram, start: usize, end: usize) -> Result<(), String> {
 		if self.compiled { panic!("Cannot compile the same BFLLVMProgram twice.") }
 		self.compiled = true;
-		let mut buffer = [0u8; 2048];
-		let mut last_char = 0;
-		let mut last_char_count = 0;
+
+		let instructions = program.get_instructions();
+		if let Some(ref mut checks) = self.bounds_checks {
+			checks.locations = (0..instructions.len()).map(|idx| program.source_location(idx)).collect();
+		}
 
 		// format:
-		// (Loop Start PC, Loop Block, After Loop Block)
+		// (Loop Block, After Loop Block)
 		let mut block_stack = Vec::new();
 
-		loop {
-			match input.read(&mut buffer) {
-				Ok(read) => {
-					if read == 0 {
-						if last_char_count > 0 {
-							self.push_instr(last_char, last_char_count, &mut block_stack);
-						}
-						break
-					}
-					for idx in 0..read {
-						let ch = buffer[idx];
-						if !Self::valid_bf_char(ch) { continue }
-						if last_char_count > 0 {
-							if ch != last_char {
-								self.push_instr(last_char, last_char_count, &mut block_stack);
-								last_char_count = 1;
-								last_char = ch;
-							} else if ch == last_char {
-								last_char_count += 1;
-							}
-						} else {
-							last_char = ch;
-							last_char_count = 1;
-						}
-					}
-				},
+		let debug_info = self.debug_source.as_ref().map(|source| unsafe {
+			DebugInfo::new(self.llvm_info.context, self.llvm_info.module, self.llvm_info.llvm_bf_fn, source)
+		});
 
-				Err(e) => {
-					panic!("Error while reading input: {}", e);
+		for idx in start..end {
+			unsafe {
+				if let Some(ref debug_info) = debug_info {
+					debug_info.set_location(self.llvm_info.builder, program.source_location(idx));
 				}
+				self.push_instr(instructions, idx, &mut block_stack);
+			}
+		}
+
+		unsafe {
+			if self.llvm_info.data_idx_out.is_null() {
+				self.flush_output_buffer(true);
+			} else {
+				// The interpreter carries on writing to stdout after a loop, so it's
+				// only flushed when the whole program is done.
+				self.flush_output_buffer(false);
+				let data_idx = LLVMBuildLoad2(self.llvm_info.builder, self.llvm_info.i64_type, self.llvm_info.var_data_idx, cstring!("data_idx"));
+				LLVMBuildStore(self.llvm_info.builder, data_idx, self.llvm_info.data_idx_out);
+			}
+			LLVMBuildRet(self.llvm_info.builder, LLVMConstInt(self.llvm_info.i8_type, 0, 0));
+			if let Some(debug_info) = debug_info {
+				debug_info.finish();
 			}
+			LLVMDisposeBuilder(self.llvm_info.builder);
 		}
+		self.llvm_info.builder = ptr::null_mut();
+		self.verify()
+	}
 
-		if let Some((unmatched_loop_start, _, _)) = block_stack.pop() {
-			panic!("No matching ']' for '[' at {}", unmatched_loop_start);
+	/// Makes sure the module is valid, so that LLVM reports a mistake in the code
+	/// generation instead of crashing on it.
+	fn verify(&self) -> Result<(), String> {
+		unsafe {
+			let mut error = ptr::null_mut();
+			if LLVMVerifyModule(self.llvm_info.module, LLVMVerifierFailureAction::LLVMReturnStatusAction, &mut error) != 0 {
+				Err(format!("Invalid LLVM module: {}", take_llvm_message(error)))
+			} else {
+				LLVMDisposeMessage(error);
+				Ok(())
+			}
 		}
+	}
+
+	/// Runs LLVM's optimization passes over the compiled IR. Level 0 leaves it alone,
+	/// 1 to 3 are the same as clang's -O1 to -O3.
+	pub fn optimize(&mut self, opt_level: u32) -> Result<(), String> {
+		if !self.compiled || self.llvm_info.ready { panic!("Can only optimize a BFLLVMProgram between compiling and finalizing it.") }
+		self.llvm_info.opt_level = opt_level;
+		if opt_level == 0 { return Ok(()) }
+
+		unsafe {
+			let pass_manager_builder = LLVMPassManagerBuilderCreate();
+			LLVMPassManagerBuilderSetOptLevel(pass_manager_builder, opt_level);
+
+			// The data pointer and the stdin/stdout locks start out in allocas, so
+			// nothing else gets very far until mem2reg has put them in registers.
+			let function_passes = LLVMCreateFunctionPassManagerForModule(self.llvm_info.module);
+			LLVMAddPromoteMemoryToRegisterPass(function_passes);
+			LLVMAddInstructionCombiningPass(function_passes);
+			LLVMAddReassociatePass(function_passes);
+			LLVMAddGVNPass(function_passes);
+			LLVMAddCFGSimplificationPass(function_passes);
+			LLVMPassManagerBuilderPopulateFunctionPassManager(pass_manager_builder, function_passes);
 
-		unsafe { self.finalize_llvm_info(); }
+			LLVMInitializeFunctionPassManager(function_passes);
+			LLVMRunFunctionPassManager(function_passes, self.llvm_info.llvm_bf_fn);
+			LLVMFinalizeFunctionPassManager(function_passes);
+			LLVMDisposePassManager(function_passes);
+
+			let module_passes = LLVMCreatePassManager();
+			LLVMPassManagerBuilderPopulateModulePassManager(pass_manager_builder, module_passes);
+			LLVMRunPassManager(module_passes, self.llvm_info.module);
+			LLVMDisposePassManager(module_passes);
+
+			LLVMPassManagerBuilderDispose(pass_manager_builder);
+		}
+		self.verify()
 	}
 
-	fn valid_bf_char(ch: u8) -> bool {
-		ch == b'>' || ch == b'<' || 
-		ch == b'+' || ch == b'-' || 
-		ch == b'.' || ch == b',' || 
-		ch == b'[' || ch == b']'
+	/// Writes the program to a file, targeting the machine this is running on. The `bf`
+	/// function in object files has to be linked with the runtime in `bfruntime.c`.
+	pub fn emit(&self, kind: EmitKind, path: &Path) -> Result<(), String> {
+		if !self.compiled || self.llvm_info.ready { panic!("Can only write a BFLLVMProgram to a file between compiling and finalizing it.") }
+		let path = match path.to_str().map(CString::new) {
+			Some(Ok(path)) => path,
+			_ => return Err(format!("Invalid file path: {}", path.display())),
+		};
+
+		unsafe {
+			let target_machine = self.create_native_target_machine()?;
+			let mut error = ptr::null_mut();
+			let failed = match kind {
+				EmitKind::LlvmIr => LLVMPrintModuleToFile(self.llvm_info.module, path.as_ptr(), &mut error) != 0,
+				EmitKind::LlvmBc => LLVMWriteBitcodeToFile(self.llvm_info.module, path.as_ptr()) != 0,
+				EmitKind::Asm | EmitKind::Obj => {
+					let file_type = if kind == EmitKind::Asm {
+						LLVMCodeGenFileType::LLVMAssemblyFile
+					} else {
+						LLVMCodeGenFileType::LLVMObjectFile
+					};
+					LLVMTargetMachineEmitToFile(target_machine, self.llvm_info.module,
+						path.as_ptr() as *mut _, file_type, &mut error) != 0
+				},
+			};
+			LLVMDisposeTargetMachine(target_machine);
+
+			if failed { Err(take_llvm_message(error)) } else { Ok(()) }
+		}
+	}
+
+	/// Compiles the program to an object file in memory, for `finalize_from_object`.
+	pub fn emit_object(&self) -> Result<Vec<u8>, String> {
+		if !self.compiled || self.llvm_info.ready { panic!("Can only write a BFLLVMProgram to an object between compiling and finalizing it.") }
+
+		unsafe {
+			let target_machine = self.create_native_target_machine()?;
+			let mut error = ptr::null_mut();
+			let mut buffer = ptr::null_mut();
+			let failed = LLVMTargetMachineEmitToMemoryBuffer(target_machine, self.llvm_info.module,
+				LLVMCodeGenFileType::LLVMObjectFile, &mut error, &mut buffer) != 0;
+			LLVMDisposeTargetMachine(target_machine);
+			if failed {
+				return Err(take_llvm_message(error));
+			}
+
+			let object = slice::from_raw_parts(LLVMGetBufferStart(buffer) as *const u8, LLVMGetBufferSize(buffer)).to_vec();
+			LLVMDisposeMemoryBuffer(buffer);
+			Ok(object)
+		}
+	}
+
+	/// Creates a target machine for the machine this is running on and sets the module
+	/// up for it, so that every kind of file that's written matches.
+	unsafe fn create_native_target_machine(&self) -> Result<LLVMTargetMachineRef, String> {
+		initialize_native_target()?;
+
+		let triple = LLVMGetDefaultTargetTriple();
+		let mut target = ptr::null_mut();
+		let mut error = ptr::null_mut();
+		if LLVMGetTargetFromTriple(triple, &mut target, &mut error) != 0 {
+			LLVMDisposeMessage(triple);
+			return Err(take_llvm_message(error));
+		}
+
+		let codegen_level = match self.llvm_info.opt_level {
+			0 => LLVMCodeGenOptLevel::LLVMCodeGenLevelNone,
+			1 => LLVMCodeGenOptLevel::LLVMCodeGenLevelLess,
+			2 => LLVMCodeGenOptLevel::LLVMCodeGenLevelDefault,
+			_ => LLVMCodeGenOptLevel::LLVMCodeGenLevelAggressive,
+		};
+		// PIC so that it can be linked into a position independent executable.
+		let target_machine = LLVMCreateTargetMachine(target, triple, cstring!(""), cstring!(""),
+			codegen_level, LLVMRelocMode::LLVMRelocPIC, LLVMCodeModel::LLVMCodeModelDefault);
+		if target_machine.is_null() {
+			LLVMDisposeMessage(triple);
+			return Err("Failed to create a target machine.".to_string());
+		}
+
+		LLVMSetTarget(self.llvm_info.module, triple);
+		let data_layout = LLVMCreateTargetDataLayout(target_machine);
+		LLVMSetModuleDataLayout(self.llvm_info.module, data_layout);
+		LLVMDisposeTargetData(data_layout);
+		LLVMDisposeMessage(triple);
+
+		Ok(target_machine)
+	}
+
+	/// JIT compiles the IR so that the program can be run.
+	pub fn finalize(&mut self) -> Result<(), String> {
+		if !self.compiled { panic!("Cannot finalize a BFLLVMProgram before compiling it.") }
+		if self.llvm_info.ready { return Ok(()) }
+		unsafe { self.finalize_llvm_info() }
+	}
+
+	/// Gets `program` ready to run from an object written by `emit_object` for it, instead
+	/// of JIT compiling its IR. It doesn't have to have been compiled first.
+	pub fn finalize_from_object(&mut self, program: &BFProgram, object: &[u8]) -> Result<(), String> {
+		if self.llvm_info.ready { panic!("Cannot finalize a BFLLVMProgram twice.") }
+		self.compiled = true;
+
+		unsafe { initialize_native_target()?; }
+		let object_jit = ObjectJIT::load(object, &runtime_functions())?;
+		let address = object_jit.lookup("bf")?;
+		if let Some(ref mut profiling) = self.profiling {
+			// An object that was compiled with profiling has counters if the program has loops.
+			if loop_count(program) > 0 {
+				profiling.address = Some(object_jit.lookup("bf_loop_counters")? as *const u64);
+			}
+		}
+		self.llvm_info.compiled_bf_fn = Some(unsafe { mem::transmute::<usize, CompiledBFFn>(address) });
+		self.llvm_info.ready = true;
+		self.object_jit = Some(object_jit);
+		Ok(())
 	}
 
 	#[inline(always)]
-	fn push_instr(&mut self, ch: u8, arg: u32, block_stack: &mut Vec<(u32, *mut llvm::LLVMBasicBlock, *mut llvm::LLVMBasicBlock)>) {
-		match ch {
-			b'>' => {
-				unsafe {
-					let mut tmp = LLVMBuildLoad(self.llvm_info.builder,
-						self.llvm_info.var_data_ptr,
-						cstring!("temp"));
-					tmp = LLVMBuildAdd(self.llvm_info.builder, 
-						tmp, LLVMConstInt(self.llvm_info.i32_type, arg as u64, 0),
-						cstring!("temp"));
-					LLVMBuildStore(self.llvm_info.builder, tmp, self.llvm_info.var_data_ptr);
+	unsafe fn push_instr(&mut self, instructions: &[BFInstr], idx: usize, block_stack: &mut Vec<(*mut llvm::LLVMBasicBlock, *mut llvm::LLVMBasicBlock)>) {
+		let instr = &instructions[idx];
+
+		// A loop that doesn't print anything doesn't have to hand output to the runtime
+		// before each cell that might fault if it's done once before the loop.
+		if let BFInstr::LoopStart(_) = *instr {
+			if !loop_has_output(instructions, idx) {
+				self.flush_before_faults();
+			}
+		}
+
+		// The cells that the instruction always uses.
+		match instr {
+			&BFInstr::IncVal(_) | &BFInstr::DecVal(_) | &BFInstr::Output(_) | &BFInstr::Input(_) |
+			&BFInstr::LoopStart(_) | &BFInstr::LoopEnd(_) | &BFInstr::ZeroCurrentCell => self.require_cell(0, idx),
+			&BFInstr::Add { offset, .. } | &BFInstr::SetCell(_, offset) | &BFInstr::Out { offset, .. } |
+			&BFInstr::In { offset, .. } | &BFInstr::MultiplyAdd { offset, .. } => self.require_cell(offset, idx),
+			BFInstr::ClosedForm(summary) => self.require_cell(summary.offset, idx),
+			_ => {},
+		}
+
+		match instr {
+			&BFInstr::IncPC(n) => self.move_data_ptr(n as isize),
+			&BFInstr::DecPC(n) => self.move_data_ptr(-(n as isize)),
+			&BFInstr::IncVal(n) => self.add_to_cell(0, n as BFCellValue),
+			&BFInstr::DecVal(n) => self.add_to_cell(0, (n as BFCellValue).wrapping_neg()),
+			&BFInstr::Output(times) => self.build_output(0, times),
+			&BFInstr::Input(times) => self.build_input(0, times),
+
+			&BFInstr::LoopStart(_) => {
+				let loop_block = LLVMAppendBasicBlockInContext(
+					self.llvm_info.context, self.llvm_info.llvm_bf_fn,
+					cstring!("begin_loop")
+				);
+
+				let after_loop_block = LLVMAppendBasicBlockInContext(
+					self.llvm_info.context, self.llvm_info.llvm_bf_fn,
+					cstring!("after_loop")
+				);
+
+				block_stack.push((loop_block, after_loop_block));
+
+				let counters = self.profiling.as_mut().map(|profiling| {
+					profiling.next_loop += 1;
+					2 * (profiling.next_loop - 1)
+				});
+				if let Some(entries) = counters {
+					self.build_count(entries);
 				}
-				self.pc += arg;
-			},
-			b'<' => {
-				unsafe {
-					let mut tmp = LLVMBuildLoad(self.llvm_info.builder,
-						self.llvm_info.var_data_ptr,
-						cstring!("temp"));
-					tmp = LLVMBuildSub(self.llvm_info.builder, 
-						tmp, LLVMConstInt(self.llvm_info.i32_type, arg as u64, 0),
-						cstring!("temp"));
-					 LLVMBuildStore(self.llvm_info.builder, tmp, self.llvm_info.var_data_ptr);
+
+				let cell_val = self.load_cell(0);
+				let jump_out_of_loop = LLVMBuildICmp(self.llvm_info.builder,
+					llvm::LLVMIntPredicate::LLVMIntEQ,
+					cell_val,
+					self.const_cell(0),
+					cstring!("loop_start_cmp")
+				);
+
+				if self.bounds_checks.is_some() {
+					let checks_block = LLVMAppendBasicBlockInContext(
+						self.llvm_info.context, self.llvm_info.llvm_bf_fn,
+						cstring!("loop_checks")
+					);
+					LLVMBuildCondBr(self.llvm_info.builder,
+						jump_out_of_loop,
+						after_loop_block, checks_block);
+					LLVMPositionBuilderAtEnd(self.llvm_info.builder, checks_block);
+					self.begin_loop_checks(instructions, idx);
+					LLVMBuildBr(self.llvm_info.builder, loop_block);
+				} else {
+					LLVMBuildCondBr(self.llvm_info.builder,
+						jump_out_of_loop,
+						after_loop_block, loop_block);
 				}
-				self.pc += arg;
-			},
-			b'+' => {
-				unsafe {
-					let cell_ptr = LLVMBuildLoad(self.llvm_info.builder,
-						self.llvm_info.var_data_ptr,
-						cstring!("cell_ptr"));
-					let mut cell_val = LLVMBuildLoad(self.llvm_info.builder,
-						cell_ptr,
-						cstring!("cell_val"));
-					cell_val = LLVMBuildAdd(self.llvm_info.builder, 
-						cell_val, LLVMConstInt(self.llvm_info.i8_type, arg as u64, 0),
-						cstring!("cell_val"));
-					LLVMBuildStore(self.llvm_info.builder, cell_val, cell_ptr);
+				LLVMPositionBuilderAtEnd(self.llvm_info.builder, loop_block);
+				if let Some(entries) = counters {
+					self.build_count(entries + 1);
 				}
-				self.pc += arg;
 			},
-			b'-' => {
-				unsafe {
-					let cell_ptr = LLVMBuildLoad(self.llvm_info.builder,
-						self.llvm_info.var_data_ptr,
-						cstring!("cell_ptr"));
-					let mut cell_val = LLVMBuildLoad(self.llvm_info.builder,
-						cell_ptr,
-						cstring!("cell_val"));
-					cell_val = LLVMBuildSub(self.llvm_info.builder, 
-						cell_val, LLVMConstInt(self.llvm_info.i8_type, arg as u64, 0),
-						cstring!("cell_val"));
-					LLVMBuildStore(self.llvm_info.builder, cell_val, cell_ptr);
+
+			&BFInstr::LoopEnd(_) => {
+				let (loop_block, after_loop_block) = block_stack.pop().expect("No matching '[' for ']'");
+				let cell_val = self.load_cell(0);
+				let jump_restart_loop = LLVMBuildICmp(self.llvm_info.builder,
+					llvm::LLVMIntPredicate::LLVMIntNE,
+					cell_val,
+					self.const_cell(0),
+					cstring!("loop_end_cmp")
+				);
+				LLVMBuildCondBr(self.llvm_info.builder,
+					jump_restart_loop,
+					loop_block, after_loop_block);
+				LLVMPositionBuilderAtEnd(self.llvm_info.builder, after_loop_block);
+
+				if let Some(ref mut checks) = self.bounds_checks {
+					// Either the loop didn't move the data pointer, or the current cell
+					// was just checked.
+					checks.checked = checks.loops.pop().expect("No matching '[' for ']'").or(Some((0, 0)));
 				}
-				self.pc += arg;
 			},
-			b'.' => {
-				unsafe {
-					let cell_ptr = LLVMBuildLoad(self.llvm_info.builder,
-						self.llvm_info.var_data_ptr,
-						cstring!("cell_ptr"));
-					let cell_val = LLVMBuildLoad(self.llvm_info.builder,
-						cell_ptr,
-						cstring!("cell_val"));
-					let ptr_stdout = LLVMBuildLoad(self.llvm_info.builder, self.llvm_info.var_ptr_stdout, cstring!("sout"));
-					let mut output_args = [ptr_stdout, cell_val];
-					LLVMBuildCall(self.llvm_info.builder,
-						self.llvm_info.bf_output_fn,
-						output_args.as_mut_ptr(),
-						output_args.len() as u32,
-						cstring!("unused"));
+
+			&BFInstr::ZeroCurrentCell => self.store_cell(0, self.const_cell(0)),
+
+			&BFInstr::MultiplyAdd { offset, ref targets } => {
+				// The targets might be off the tape when the loop cell is 0, so they
+				// can't be touched unless the loop would have run.
+				let (value, after_block) = self.begin_if_nonzero(offset);
+				let checked = self.checked_cells();
+				self.require_cells(targets.iter().map(|&(target, _)| offset + target), idx);
+				for &(target, factor) in targets.iter() {
+					let product = LLVMBuildMul(self.llvm_info.builder, value, self.const_cell(factor), cstring!("product"));
+					let cell = self.load_cell(offset + target);
+					let sum = LLVMBuildAdd(self.llvm_info.builder, cell, product, cstring!("sum"));
+					self.store_cell(offset + target, sum);
 				}
-				self.pc += arg;
+				self.store_cell(offset, self.const_cell(0));
+				self.end_if(after_block);
+				self.set_checked_cells(checked);
 			},
-			b',' => {
-				unsafe {
-					let ptr_stdin = LLVMBuildLoad(self.llvm_info.builder, self.llvm_info.var_ptr_stdin, cstring!("sin"));
-					let mut input_args = [ptr_stdin];
-					let input_val = LLVMBuildCall(self.llvm_info.builder,
-						self.llvm_info.bf_input_fn,
-						input_args.as_mut_ptr(),
-						input_args.len() as u32,
-						cstring!("input"));
-					let cell_ptr = LLVMBuildLoad(self.llvm_info.builder,
-						self.llvm_info.var_data_ptr,
-						cstring!("cell_ptr"));
-					LLVMBuildStore(self.llvm_info.builder, input_val, cell_ptr);
-				}
-				self.pc += arg;
+
+			BFInstr::ClosedForm(summary) => self.build_closed_form(summary, idx),
+
+			&BFInstr::FindZeroCellLeft(step_size) => self.build_find_zero(-(step_size as isize), idx),
+			&BFInstr::FindZeroCellRight(step_size) => self.build_find_zero(step_size as isize, idx),
+
+			&BFInstr::Add { offset, amount } => self.add_to_cell(offset, amount),
+			&BFInstr::SetCell(value, offset) => self.store_cell(offset, self.const_cell(value)),
+			&BFInstr::Out { offset, times } => self.build_output(offset, times),
+			&BFInstr::In { offset, times } => self.build_input(offset, times),
+
+			BFInstr::OutputBytes(bytes) => {
+				let data = LLVMConstStringInContext(self.llvm_info.context,
+					bytes.as_ptr() as *const _, bytes.len() as u32, 1);
+				let global = LLVMAddGlobal(self.llvm_info.module, LLVMTypeOf(data), cstring!("output_bytes"));
+				LLVMSetInitializer(global, data);
+				LLVMSetGlobalConstant(global, 1);
+				LLVMSetLinkage(global, llvm::LLVMLinkage::LLVMPrivateLinkage);
+
+				self.flush_output_buffer(false);
+				self.build_output_bytes(self.llvm_info.bf_output_bytes_fn,
+					LLVMConstBitCast(global, self.llvm_info.i8_ptr_type),
+					self.const_i64(bytes.len() as i64));
 			},
 
-			b'[' => {
-				unsafe {
-					for _ in 0..arg {
-						let loop_block = LLVMAppendBasicBlockInContext(
-							self.llvm_info.context, self.llvm_info.llvm_bf_fn,
-							cstring!("begin_loop")
-						);
-
-						let after_loop_block = LLVMAppendBasicBlockInContext(
-							self.llvm_info.context, self.llvm_info.llvm_bf_fn,
-							cstring!("after_loop")
-						);
-
-						let _pc = self.pc;
-						block_stack.push((_pc, loop_block, after_loop_block));
-
-						
-						let cell_ptr = LLVMBuildLoad(self.llvm_info.builder,
-							self.llvm_info.var_data_ptr,
-							cstring!("cell_ptr"));
-						let cell_val = LLVMBuildLoad(self.llvm_info.builder,
-							cell_ptr,
-							cstring!("cell_val"));
-
-						let jump_out_of_loop = LLVMBuildICmp(self.llvm_info.builder,
-							llvm::LLVMIntPredicate::LLVMIntEQ,
-							cell_val,
-							LLVMConstInt(self.llvm_info.i8_type, 0, 0),
-							cstring!("loop_start_cmp")
-						);
-
-						LLVMBuildCondBr(self.llvm_info.builder,
-							jump_out_of_loop,
-							after_loop_block, loop_block);
-						LLVMPositionBuilderAtEnd(self.llvm_info.builder, loop_block);
-
-						self.pc += 1;
-					}
+			// The snippet after an `Idiom` does the same thing as the idiom itself, and
+			// LLVM does a good enough job with it.
+			&BFInstr::Idiom { .. } | &BFInstr::IdiomEnd(_) => {},
+		}
+
+		if let Some(ref mut checks) = self.bounds_checks {
+			checks.track_output(instructions, idx);
+		}
+	}
+
+	/// Adds one to the profiling counter at `idx`.
+	unsafe fn build_count(&self, idx: usize) {
+		let counters = self.profiling.as_ref().expect("Profiling isn't enabled.").counters;
+		let mut indices = [self.const_i64(0), self.const_i64(idx as i64)];
+		let counter_ptr = LLVMBuildInBoundsGEP2(self.llvm_info.builder, LLVMGlobalGetValueType(counters), counters,
+			indices.as_mut_ptr(), indices.len() as u32, cstring!("counter_ptr"));
+		let count = LLVMBuildLoad2(self.llvm_info.builder, self.llvm_info.i64_type, counter_ptr, cstring!("count"));
+		let count = LLVMBuildAdd(self.llvm_info.builder, count, self.const_i64(1), cstring!("count"));
+		LLVMBuildStore(self.llvm_info.builder, count, counter_ptr);
+	}
+
+	unsafe fn const_cell(&self, value: BFCellValue) -> *mut llvm::LLVMValue {
+		LLVMConstInt(self.llvm_info.i8_type, value as u64, 0)
+	}
+
+	unsafe fn const_i64(&self, value: i64) -> *mut llvm::LLVMValue {
+		LLVMConstInt(self.llvm_info.i64_type, value as u64, 1)
+	}
+
+	/// Index into the tape of the cell at `offset` from the data pointer.
+	unsafe fn cell_idx(&self, offset: isize) -> *mut llvm::LLVMValue {
+		let data_idx = LLVMBuildLoad2(self.llvm_info.builder, self.llvm_info.i64_type, self.llvm_info.var_data_idx, cstring!("data_idx"));
+		if offset == 0 { return data_idx }
+		LLVMBuildAdd(self.llvm_info.builder, data_idx, self.const_i64(offset as i64), cstring!("cell_idx"))
+	}
+
+	/// Pointer to the cell at `offset` from the data pointer.
+	unsafe fn cell_ptr(&self, offset: isize) -> *mut llvm::LLVMValue {
+		let mut indices = [self.cell_idx(offset)];
+		LLVMBuildInBoundsGEP2(self.llvm_info.builder, self.llvm_info.i8_type, self.llvm_info.tape_ptr,
+			indices.as_mut_ptr(), indices.len() as u32, cstring!("cell_ptr"))
+	}
+
+	unsafe fn load_cell(&self, offset: isize) -> *mut llvm::LLVMValue {
+		LLVMBuildLoad2(self.llvm_info.builder, self.llvm_info.i8_type, self.cell_ptr(offset), cstring!("cell_val"))
+	}
+
+	unsafe fn store_cell(&self, offset: isize, value: *mut llvm::LLVMValue) {
+		LLVMBuildStore(self.llvm_info.builder, value, self.cell_ptr(offset));
+	}
+
+	unsafe fn add_to_cell(&self, offset: isize, amount: BFCellValue) {
+		let cell_val = self.load_cell(offset);
+		let sum = LLVMBuildAdd(self.llvm_info.builder, cell_val, self.const_cell(amount), cstring!("cell_val"));
+		self.store_cell(offset, sum);
+	}
+
+	unsafe fn move_data_ptr(&mut self, amount: isize) {
+		let moved = self.cell_idx(amount);
+		LLVMBuildStore(self.llvm_info.builder, moved, self.llvm_info.var_data_idx);
+		if let Some(ref mut checks) = self.bounds_checks {
+			checks.moved(amount);
+		}
+	}
+
+	fn checked_cells(&self) -> Option<(isize, isize)> {
+		self.bounds_checks.as_ref().and_then(|checks| checks.checked)
+	}
+
+	fn set_checked_cells(&mut self, checked: Option<(isize, isize)>) {
+		if let Some(ref mut checks) = self.bounds_checks {
+			checks.checked = checked;
+		}
+	}
+
+	/// Whether going off of the upper end of the tape is left to the guard region.
+	fn guarded(&self) -> bool {
+		self.bounds_checks.as_ref().is_some_and(|checks| checks.guarded)
+	}
+
+	/// Hands the output buffer to the runtime if the tape has guard pages and there might
+	/// be something in it, so that none of it is lost if the code after this faults.
+	unsafe fn flush_before_faults(&mut self) {
+		if self.guarded() && self.bounds_checks.as_ref().is_some_and(|checks| checks.output_pending) {
+			self.build_pending_output_flush();
+			if let Some(ref mut checks) = self.bounds_checks {
+				checks.output_pending = false;
+			}
+		}
+	}
+
+	/// Makes sure that the cell at `offset` from the data pointer is on the tape before
+	/// the instruction at `idx` uses it, if bounds checks are on and that isn't already known.
+	///
+	/// With guard pages only the lower end of the tape is checked, and the output buffer is
+	/// handed to the runtime first if the cell might be past the upper end, so that the
+	/// program stops after printing the same thing as it does in the interpreter.
+	unsafe fn require_cell(&mut self, offset: isize, idx: usize) {
+		let (location, guarded, output_pending) = match self.bounds_checks {
+			Some(ref checks) if !checks.covers(offset) => (checks.locations[idx], checks.guarded, checks.output_pending),
+			_ => return,
+		};
+		if !guarded {
+			self.build_bounds_check(offset, location, false);
+		} else {
+			let (lowest, highest) = self.checked_cells().unwrap_or((offset, offset));
+			if offset <= lowest {
+				self.build_bounds_check(offset, location, true);
+			}
+			if offset >= highest && output_pending {
+				self.build_pending_output_flush();
+			}
+		}
+		if let Some(ref mut checks) = self.bounds_checks {
+			checks.add(offset);
+		}
+	}
+
+	/// `require_cell` for a group of cells that are all used, which only has to check the
+	/// lowest and the highest.
+	unsafe fn require_cells<I: Iterator<Item = isize>>(&mut self, offsets: I, idx: usize) {
+		let mut range = None;
+		for offset in offsets {
+			range = Some(match range {
+				Some((lowest, highest)) => (offset.min(lowest), offset.max(highest)),
+				None => (offset, offset),
+			});
+		}
+		if let Some((lowest, highest)) = range {
+			self.require_cell(lowest, idx);
+			self.require_cell(highest, idx);
+		}
+	}
+
+	/// Checks the cells that every pass through the loop starting at `idx` uses, when the
+	/// loop doesn't move the data pointer. Otherwise nothing is known inside of it.
+	unsafe fn begin_loop_checks(&mut self, instructions: &[BFInstr], idx: usize) {
+		let before = self.checked_cells();
+		match loop_cells(instructions, idx) {
+			Some(((lowest, lowest_idx), (highest, highest_idx))) => {
+				self.require_cell(lowest, lowest_idx);
+				// Past the upper end, the guard region only catches the loop once it uses
+				// the cell, which can be after it has printed something.
+				if !self.guarded() {
+					self.require_cell(highest, highest_idx);
+				}
+				if let Some(ref mut checks) = self.bounds_checks {
+					checks.loops.push(before);
 				}
 			},
-
-			b']' => {
-				unsafe {
-					for _ in 0..arg {
-						if let Some((loop_start_pc, loop_block, after_loop_block)) = block_stack.pop() {
-							let cell_ptr = LLVMBuildLoad(self.llvm_info.builder,
-								self.llvm_info.var_data_ptr,
-								cstring!("cell_ptr"));
-							let cell_val = LLVMBuildLoad(self.llvm_info.builder,
-								cell_ptr,
-								cstring!("cell_val"));
-							let jump_restart_loop = LLVMBuildICmp(self.llvm_info.builder,
-								llvm::LLVMIntPredicate::LLVMIntNE,
-								cell_val,
-								LLVMConstInt(self.llvm_info.i8_type, 0, 0),
-								cstring!("loop_end_cmp")
-							);
-							LLVMBuildCondBr(self.llvm_info.builder,
-								jump_restart_loop,
-								loop_block, after_loop_block);
-							LLVMPositionBuilderAtEnd(self.llvm_info.builder, after_loop_block);
-						} else {
-							panic!("No matching '[' for ']' at {}", self.pc);
-						}
-						self.pc += 1;
-					}
+			None => {
+				if let Some(ref mut checks) = self.bounds_checks {
+					checks.loops.push(None);
+					checks.checked = None;
 				}
 			},
+		}
+	}
+
+	/// Stops the program, reporting `location`, if the cell at `offset` from the data
+	/// pointer isn't on the tape, or with `lower_only` if it's before the first cell.
+	unsafe fn build_bounds_check(&self, offset: isize, location: SourceLocation, lower_only: bool) {
+		let trap_block = LLVMAppendBasicBlockInContext(self.llvm_info.context, self.llvm_info.llvm_bf_fn, cstring!("out_of_bounds"));
+		let ok_block = LLVMAppendBasicBlockInContext(self.llvm_info.context, self.llvm_info.llvm_bf_fn, cstring!("in_bounds"));
+
+		let cell_idx = self.cell_idx(offset);
+		let (predicate, limit) = if lower_only {
+			(llvm::LLVMIntPredicate::LLVMIntSGE, 0)
+		} else {
+			// Negative indices are huge as unsigned numbers, so one comparison covers both ends.
+			(llvm::LLVMIntPredicate::LLVMIntULT, BF_MEMORY_SIZE as i64)
+		};
+		let in_bounds = LLVMBuildICmp(self.llvm_info.builder,
+			predicate,
+			cell_idx,
+			self.const_i64(limit),
+			cstring!("in_bounds"));
+		LLVMBuildCondBr(self.llvm_info.builder, in_bounds, ok_block, trap_block);
 
-			_ => { /* Non comman characters are just ignored. */ },
+		LLVMPositionBuilderAtEnd(self.llvm_info.builder, trap_block);
+		self.flush_output_buffer(true);
+		let mut trap_args = [self.const_i64(location.line as i64), self.const_i64(location.column as i64), cell_idx];
+		self.build_call(self.llvm_info.bf_out_of_bounds_fn, &mut trap_args);
+		LLVMBuildRet(self.llvm_info.builder, LLVMConstInt(self.llvm_info.i8_type, 1, 0));
+
+		LLVMPositionBuilderAtEnd(self.llvm_info.builder, ok_block);
+	}
+
+	/// Hands the output buffer to the runtime if there's anything in it.
+	unsafe fn build_pending_output_flush(&self) {
+		let flush_block = LLVMAppendBasicBlockInContext(self.llvm_info.context, self.llvm_info.llvm_bf_fn, cstring!("pending_output_flush"));
+		let after_block = LLVMAppendBasicBlockInContext(self.llvm_info.context, self.llvm_info.llvm_bf_fn, cstring!("after_pending_output"));
+
+		let output_len = LLVMBuildLoad2(self.llvm_info.builder, self.llvm_info.i64_type, self.llvm_info.var_output_len, cstring!("output_len"));
+		let is_empty = LLVMBuildICmp(self.llvm_info.builder,
+			llvm::LLVMIntPredicate::LLVMIntEQ,
+			output_len,
+			self.const_i64(0),
+			cstring!("output_empty"));
+		LLVMBuildCondBr(self.llvm_info.builder, is_empty, after_block, flush_block);
+
+		LLVMPositionBuilderAtEnd(self.llvm_info.builder, flush_block);
+		self.flush_output_buffer(false);
+		LLVMBuildBr(self.llvm_info.builder, after_block);
+
+		LLVMPositionBuilderAtEnd(self.llvm_info.builder, after_block);
+	}
+
+	unsafe fn build_output(&self, offset: isize, times: usize) {
+		let cell_val = self.load_cell(offset);
+		if times <= MAX_INLINE_OUTPUT_REPEATS {
+			for _ in 0..times {
+				self.build_buffered_output(cell_val);
+			}
+			return
 		}
+
+		self.flush_output_buffer(false);
+		let ptr_stdout = LLVMBuildLoad2(self.llvm_info.builder, self.llvm_info.i8_ptr_type, self.llvm_info.var_ptr_stdout, cstring!("sout"));
+		let mut output_args = [ptr_stdout, cell_val, self.const_i64(times as i64)];
+		self.build_call(self.llvm_info.bf_output_fn, &mut output_args);
 	}
 
-	pub fn run(&mut self) {
+	/// Adds a byte to the output buffer, handing the buffer to the runtime first if it's full.
+	unsafe fn build_buffered_output(&self, value: *mut llvm::LLVMValue) {
+		let flush_block = LLVMAppendBasicBlockInContext(self.llvm_info.context, self.llvm_info.llvm_bf_fn, cstring!("output_flush"));
+		let store_block = LLVMAppendBasicBlockInContext(self.llvm_info.context, self.llvm_info.llvm_bf_fn, cstring!("output_store"));
+
+		let output_len = LLVMBuildLoad2(self.llvm_info.builder, self.llvm_info.i64_type, self.llvm_info.var_output_len, cstring!("output_len"));
+		let is_full = LLVMBuildICmp(self.llvm_info.builder,
+			llvm::LLVMIntPredicate::LLVMIntEQ,
+			output_len,
+			self.const_i64(OUTPUT_BUFFER_SIZE as i64),
+			cstring!("output_full"));
+		LLVMBuildCondBr(self.llvm_info.builder, is_full, flush_block, store_block);
+
+		LLVMPositionBuilderAtEnd(self.llvm_info.builder, flush_block);
+		self.flush_output_buffer(false);
+		LLVMBuildBr(self.llvm_info.builder, store_block);
+
+		LLVMPositionBuilderAtEnd(self.llvm_info.builder, store_block);
+		let output_len = LLVMBuildLoad2(self.llvm_info.builder, self.llvm_info.i64_type, self.llvm_info.var_output_len, cstring!("output_len"));
+		let mut indices = [output_len];
+		let output_ptr = LLVMBuildInBoundsGEP2(self.llvm_info.builder, self.llvm_info.i8_type, self.llvm_info.output_buffer,
+			indices.as_mut_ptr(), indices.len() as u32, cstring!("output_ptr"));
+		LLVMBuildStore(self.llvm_info.builder, value, output_ptr);
+		let output_len = LLVMBuildAdd(self.llvm_info.builder, output_len, self.const_i64(1), cstring!("output_len"));
+		LLVMBuildStore(self.llvm_info.builder, output_len, self.llvm_info.var_output_len);
+	}
+
+	/// Hands everything in the output buffer to the runtime. With `flush_stdout` the
+	/// runtime also flushes stdout, so that it shows up before reading input.
+	unsafe fn flush_output_buffer(&self, flush_stdout: bool) {
+		let output_len = LLVMBuildLoad2(self.llvm_info.builder, self.llvm_info.i64_type, self.llvm_info.var_output_len, cstring!("output_len"));
+		let function = if flush_stdout { self.llvm_info.bf_flush_fn } else { self.llvm_info.bf_output_bytes_fn };
+		self.build_output_bytes(function, self.llvm_info.output_buffer, output_len);
+		LLVMBuildStore(self.llvm_info.builder, self.const_i64(0), self.llvm_info.var_output_len);
+	}
+
+	unsafe fn build_output_bytes(&self, function: *mut llvm::LLVMValue, bytes: *mut llvm::LLVMValue, len: *mut llvm::LLVMValue) {
+		let ptr_stdout = LLVMBuildLoad2(self.llvm_info.builder, self.llvm_info.i8_ptr_type, self.llvm_info.var_ptr_stdout, cstring!("sout"));
+		let mut output_args = [ptr_stdout, bytes, len];
+		self.build_call(function, &mut output_args);
+	}
+
+	/// Calls one of the runtime functions.
+	unsafe fn build_call(&self, function: *mut llvm::LLVMValue, args: &mut [*mut llvm::LLVMValue]) {
+		LLVMBuildCall2(self.llvm_info.builder, LLVMGlobalGetValueType(function), function,
+			args.as_mut_ptr(), args.len() as u32, cstring!(""));
+	}
+
+	unsafe fn build_input(&self, offset: isize, times: usize) {
+		self.flush_output_buffer(true);
+		let ptr_stdin = LLVMBuildLoad2(self.llvm_info.builder, self.llvm_info.i8_ptr_type, self.llvm_info.var_ptr_stdin, cstring!("sin"));
+		let mut input_args = [ptr_stdin, self.cell_ptr(offset), self.const_i64(times as i64)];
+		self.build_call(self.llvm_info.bf_input_fn, &mut input_args);
+	}
+
+	/// Moves the data pointer by `step` until it's on a zero cell.
+	///
+	/// With guard pages the cells before the first one are always 0, since nothing is
+	/// written to them, so a scan that goes off of the lower end of the tape stops on the
+	/// first of them and is only checked once it's done. Going off of the upper end faults.
+	unsafe fn build_find_zero(&mut self, step: isize, idx: usize) {
+		let check_block = LLVMAppendBasicBlockInContext(self.llvm_info.context, self.llvm_info.llvm_bf_fn, cstring!("scan_check"));
+		let move_block = LLVMAppendBasicBlockInContext(self.llvm_info.context, self.llvm_info.llvm_bf_fn, cstring!("scan_move"));
+		let after_block = LLVMAppendBasicBlockInContext(self.llvm_info.context, self.llvm_info.llvm_bf_fn, cstring!("after_scan"));
+
+		let guarded = self.guarded();
+		self.flush_before_faults();
+		LLVMBuildBr(self.llvm_info.builder, check_block);
+
+		LLVMPositionBuilderAtEnd(self.llvm_info.builder, check_block);
+		self.set_checked_cells(None);
+		if !guarded {
+			self.require_cell(0, idx);
+		}
+		let is_zero = LLVMBuildICmp(self.llvm_info.builder,
+			llvm::LLVMIntPredicate::LLVMIntEQ,
+			self.load_cell(0),
+			self.const_cell(0),
+			cstring!("scan_cmp"));
+		LLVMBuildCondBr(self.llvm_info.builder, is_zero, after_block, move_block);
+
+		LLVMPositionBuilderAtEnd(self.llvm_info.builder, move_block);
+		self.move_data_ptr(step);
+		LLVMBuildBr(self.llvm_info.builder, check_block);
+
+		LLVMPositionBuilderAtEnd(self.llvm_info.builder, after_block);
+		if guarded {
+			self.set_checked_cells(None);
+			self.require_cell(0, idx);
+		}
+		self.set_checked_cells(Some((0, 0)));
+	}
+
+	/// Evaluates `expr` with the loop cell at `base` from the data pointer.
+	unsafe fn build_affine(&self, expr: &Affine, base: isize) -> *mut llvm::LLVMValue {
+		let mut value = self.const_cell(expr.constant);
+		for &(offset, factor) in expr.terms.iter() {
+			let term = LLVMBuildMul(self.llvm_info.builder, self.load_cell(base + offset), self.const_cell(factor), cstring!("term"));
+			value = LLVMBuildAdd(self.llvm_info.builder, value, term, cstring!("affine"));
+		}
+		value
+	}
+
+	/// Starts a block that only runs if the cell at `offset` isn't 0, the same as a loop
+	/// that was rewritten into straight line code. Returns the cell's value and the block
+	/// to pass to `end_if`.
+	unsafe fn begin_if_nonzero(&self, offset: isize) -> (*mut llvm::LLVMValue, *mut llvm::LLVMBasicBlock) {
+		let run_block = LLVMAppendBasicBlockInContext(self.llvm_info.context, self.llvm_info.llvm_bf_fn, cstring!("if_nonzero"));
+		let after_block = LLVMAppendBasicBlockInContext(self.llvm_info.context, self.llvm_info.llvm_bf_fn, cstring!("after_if"));
+
+		let value = self.load_cell(offset);
+		let is_zero = LLVMBuildICmp(self.llvm_info.builder,
+			llvm::LLVMIntPredicate::LLVMIntEQ,
+			value,
+			self.const_cell(0),
+			cstring!("if_cmp"));
+		LLVMBuildCondBr(self.llvm_info.builder, is_zero, after_block, run_block);
+
+		LLVMPositionBuilderAtEnd(self.llvm_info.builder, run_block);
+		(value, after_block)
+	}
+
+	unsafe fn end_if(&self, after_block: *mut llvm::LLVMBasicBlock) {
+		LLVMBuildBr(self.llvm_info.builder, after_block);
+		LLVMPositionBuilderAtEnd(self.llvm_info.builder, after_block);
+	}
+
+	unsafe fn build_closed_form(&mut self, summary: &LoopSummary, idx: usize) {
+		let (loop_cell, after_block) = self.begin_if_nonzero(summary.offset);
+		let checked = self.checked_cells();
+		self.require_cells(summary.touched_offsets().into_iter().map(|target| summary.offset + target), idx);
+		let passes = LLVMBuildMul(self.llvm_info.builder, loop_cell, self.const_cell(summary.trip_factor), cstring!("passes"));
+		let passes_after_first = LLVMBuildSub(self.llvm_info.builder, passes, self.const_cell(1), cstring!("passes_after_first"));
+
+		// Everything is in terms of the values from before the loop, so nothing can be
+		// stored until all of it is computed.
+		let mut new_values = Vec::with_capacity(summary.updates.len());
+		for &(target, ref update) in summary.updates.iter() {
+			let new_value = match update {
+				CellUpdate::Set(expr) => self.build_affine(expr, summary.offset),
+				CellUpdate::Accumulate { first, rest } => {
+					let rest_total = LLVMBuildMul(self.llvm_info.builder,
+						self.build_affine(rest, summary.offset), passes_after_first, cstring!("rest_total"));
+					let added = LLVMBuildAdd(self.llvm_info.builder,
+						self.build_affine(first, summary.offset), rest_total, cstring!("added"));
+					LLVMBuildAdd(self.llvm_info.builder, self.load_cell(summary.offset + target), added, cstring!("accumulated"))
+				},
+			};
+			new_values.push(new_value);
+		}
+
+		for (&(target, _), &new_value) in summary.updates.iter().zip(new_values.iter()) {
+			self.store_cell(summary.offset + target, new_value);
+		}
+		self.store_cell(summary.offset, self.const_cell(0));
+		self.end_if(after_block);
+		self.set_checked_cells(checked);
+	}
+
+	pub fn run(&mut self) -> Result<(), RunError> {
 		if !self.llvm_info.ready { panic!("LLVM is not ready!"); }
+		let compiled_bf_fn = self.llvm_info.compiled_bf_fn.expect("LLVM is not ready!");
 
-		let memory_ptr = self.memory.as_mut_ptr();
+		let memory_ptr = self.memory.cells();
 
 		let stdin = io::stdin();
 		let stdout = io::stdout();
 		let mut stdin_locked = stdin.lock();
 		let mut stdout_locked = stdout.lock();
 
-		unsafe {
-			(self.llvm_info.compiled_bf_fn)(memory_ptr, &mut stdin_locked, &mut stdout_locked)
+		// Without bounds checks, going off of the upper end of the tape faults in its guard
+		// region, after the compiled code has handed its output to the runtime.
+		let mut stopped = 0;
+		bftape::run_guarded(memory_ptr, || {
+			stopped = compiled_bf_fn(memory_ptr, &mut stdin_locked, &mut stdout_locked);
+		})?;
+		if stopped != 0 {
+			let error = OUT_OF_BOUNDS.with(|error| error.take());
+			return Err(error.expect("The compiled program stopped without reporting why."));
+		}
+		Ok(())
+	}
+
+	/// (entries, iterations) for each loop in `program`, in the order that they start, from
+	/// a program with profiling enabled that has been run.
+	pub fn profile_counts(&self, program: &BFProgram) -> Vec<(u64, u64)> {
+		let profiling = self.profiling.as_ref().expect("Profiling isn't enabled.");
+		match profiling.address {
+			Some(address) => {
+				let counters = unsafe { slice::from_raw_parts(address, 2 * loop_count(program)) };
+				counters.chunks(2).map(|counts| (counts[0], counts[1])).collect()
+			},
+			None => Vec::new(),
+		}
+	}
+
+	/// Runs a loop compiled by `compile_loop` on the tape that starts at `cells`, with the
+	/// data pointer at `data_ptr`, and returns where it leaves the data pointer. The tape
+	/// has to be a `Tape`, so that going off of its upper end faults in the guard region.
+	pub fn run_loop(&self, cells: *mut BFCellValue, data_ptr: usize, stdin: &mut StdinLock, stdout: &mut StdoutLock) -> Result<usize, RunError> {
+		if !self.llvm_info.ready || self.llvm_info.data_idx_out.is_null() { panic!("Can only run a loop that was compiled with compile_loop and finalized.") }
+		let compiled_bf_fn = self.llvm_info.compiled_bf_fn.expect("LLVM is not ready!");
+		let compiled_loop_fn = unsafe { mem::transmute::<CompiledBFFn, CompiledLoopFn>(compiled_bf_fn) };
+
+		let mut data_idx = data_ptr as i64;
+		let mut stopped = 0;
+		bftape::run_guarded(cells, || {
+			stopped = compiled_loop_fn(cells, stdin, stdout, &mut data_idx);
+		})?;
+		if stopped != 0 {
+			let error = OUT_OF_BOUNDS.with(|error| error.take());
+			return Err(error.expect("The compiled loop stopped without reporting why."));
+		}
+		// The loop ends on a cell that it checked, but the interpreter can't carry on
+		// from anywhere other than the tape if that ever changes.
+		if data_idx < 0 || data_idx >= BF_MEMORY_SIZE as i64 {
+			return Err(RunError::PointerOutOfRange { cell: data_idx as isize, location: None });
+		}
+		Ok(data_idx as usize)
+	}
+}
+
+/// Number of loops left in a program after it was optimized.
+fn loop_count(program: &BFProgram) -> usize {
+	program.get_instructions().iter().filter(|instr| matches!(instr, BFInstr::LoopStart(_))).count()
+}
+
+/// Whether the loop starting at `start` prints anything.
+fn loop_has_output(instructions: &[BFInstr], start: usize) -> bool {
+	let end = match instructions[start] {
+		BFInstr::LoopStart(end) => end,
+		_ => unreachable!("Not the start of a loop."),
+	};
+	instructions[start + 1..end].iter().any(|instr| matches!(instr, BFInstr::Output(_) | BFInstr::Out { .. }))
+}
+
+/// Lowest and highest offsets from the data pointer, each with the index of an instruction
+/// that uses it, of the cells that every pass through the loop starting at `start` uses.
+/// `None` if the loop can move the data pointer, since then they aren't the same cells
+/// on every pass.
+fn loop_cells(instructions: &[BFInstr], start: usize) -> Option<((isize, usize), (isize, usize))> {
+	let end = match instructions[start] {
+		BFInstr::LoopStart(end) => end,
+		_ => unreachable!("Not the start of a loop."),
+	};
+
+	let mut lowest = (0, start);
+	let mut highest = (0, start);
+	// Anything in an inner loop might not run.
+	let mut depth = 0;
+	for (idx, instr) in instructions.iter().enumerate().take(end).skip(start + 1) {
+		let offset = match *instr {
+			BFInstr::IncPC(_) | BFInstr::DecPC(_) | BFInstr::FindZeroCellLeft(_) | BFInstr::FindZeroCellRight(_) => return None,
+			BFInstr::LoopStart(_) => { depth += 1; continue },
+			BFInstr::LoopEnd(_) => { depth -= 1; continue },
+			BFInstr::IncVal(_) | BFInstr::DecVal(_) | BFInstr::Output(_) | BFInstr::Input(_) | BFInstr::ZeroCurrentCell => 0,
+			BFInstr::Add { offset, .. } | BFInstr::SetCell(_, offset) | BFInstr::Out { offset, .. } |
+			BFInstr::In { offset, .. } | BFInstr::MultiplyAdd { offset, .. } => offset,
+			BFInstr::ClosedForm(ref summary) => summary.offset,
+			BFInstr::OutputBytes(_) | BFInstr::Idiom { .. } | BFInstr::IdiomEnd(_) => continue,
 		};
+		if depth > 0 { continue }
+		if offset < lowest.0 { lowest = (offset, idx); }
+		if offset > highest.0 { highest = (offset, idx); }
 	}
+	Some((lowest, highest))
 }
 
 impl Drop for BFLLVMProgram {
@@ -427,26 +1344,77 @@ impl Drop for BFLLVMProgram {
 }
 
 
+/// The runtime functions that compiled programs call, by name.
+fn runtime_functions() -> [(&'static str, usize); 5] {
+	[
+		("__bf_print_output", __bf_print_output as *const () as usize),
+		("__bf_print_bytes", __bf_print_bytes as *const () as usize),
+		("__bf_flush_output", __bf_flush_output as *const () as usize),
+		("__bf_get_input", __bf_get_input as *const () as usize),
+		("__bf_out_of_bounds", __bf_out_of_bounds as *const () as usize),
+	]
+}
+
+unsafe fn initialize_native_target() -> Result<(), String> {
+	if LLVM_InitializeNativeTarget() != 0 {
+		return Err("LLVM doesn't support this machine.".to_string());
+	}
+	if LLVM_InitializeNativeAsmPrinter() != 0 {
+		return Err("LLVM can't generate code for this machine.".to_string());
+	}
+	Ok(())
+}
+
+/// Copies and frees an error message from LLVM.
+unsafe fn take_llvm_message(message: *mut ::std::os::raw::c_char) -> String {
+	if message.is_null() { return "Unknown LLVM error".to_string() }
+	let text = CStr::from_ptr(message).to_string_lossy().into_owned();
+	LLVMDisposeMessage(message);
+	text
+}
+
 #[no_mangle]
-pub unsafe extern "C" fn __bf_print_output(stdout: *mut StdoutLock, ch: u8) {
-	// print!("({}),", ch);
+pub unsafe extern "C" fn __bf_print_output(stdout: *mut StdoutLock, ch: u8, times: u64) {
 	if let Some(stdout) = stdout.as_mut() {
 		let buf = [ch];
-		match stdout.write(&buf) {
-			Err(err) => { panic!("Error while outputting char: {}", err) },
-			_ => {}
+		for _ in 0..times {
+			if let Err(err) = stdout.write(&buf) { panic!("Error while outputting char: {}", err) }
 		}
 	}
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn __bf_get_input(stdin: *mut StdinLock) -> u8 {
-	let mut buf = [0];
-	if let Some(stdin) = stdin.as_mut() {
-		match stdin.read(&mut buf) {
-			Err(err) => {println!("Error while reading char: {}", err)},
-			_ => {}
+pub unsafe extern "C" fn __bf_print_bytes(stdout: *mut StdoutLock, bytes: *const u8, len: u64) {
+	if let Some(stdout) = stdout.as_mut() {
+		if let Err(err) = stdout.write_all(slice::from_raw_parts(bytes, len as usize)) { panic!("Error while outputting chars: {}", err) }
+	}
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __bf_flush_output(stdout: *mut StdoutLock, bytes: *const u8, len: u64) {
+	__bf_print_bytes(stdout, bytes, len);
+	if let Some(stdout) = stdout.as_mut() {
+		if let Err(err) = stdout.flush() { panic!("Error while flushing output: {}", err) }
+	}
+}
+
+/// Called by code compiled with bounds checks right before it stops, because the
+/// instruction at `line` and `column` used `cell`, which isn't on the tape.
+#[no_mangle]
+pub extern "C" fn __bf_out_of_bounds(line: u64, column: u64, cell: i64) {
+	let location = SourceLocation { line: line as usize, column: column as usize };
+	OUT_OF_BOUNDS.with(|error| error.set(Some(RunError::PointerOutOfRange { cell: cell as isize, location: Some(location) })));
+}
+
+/// Reads into `cell` `times` times. The cell is left alone at the end of input,
+/// the same as in the interpreter.
+#[no_mangle]
+pub unsafe extern "C" fn __bf_get_input(stdin: *mut StdinLock, cell: *mut u8, times: u64) {
+	if let (Some(stdin), Some(cell)) = (stdin.as_mut(), cell.as_mut()) {
+		for _ in 0..times {
+			if let Err(err) = stdin.read(slice::from_raw_parts_mut(cell, 1)) {
+				println!("Error while reading char: {}", err);
+			}
 		}
 	}
-	return buf[0];
-}
\ No newline at end of file
+}
@@ -0,0 +1,415 @@
+use std::collections::HashMap;
+use ::bf::{BFInstr, BFCellValue};
+
+/// A sum of cell values multiplied by constants plus a constant, with wrapping arithmetic.
+/// Offsets are relative to the loop cell.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Affine {
+	pub constant: BFCellValue,
+	pub terms: Vec<(isize, BFCellValue)>, // (offset, factor)
+}
+
+impl Affine {
+	fn constant(value: BFCellValue) -> Affine {
+		Affine { constant: value, terms: Vec::new() }
+	}
+
+	fn cell(offset: isize) -> Affine {
+		Affine { constant: 0, terms: vec![(offset, 1)] }
+	}
+
+	fn add(&mut self, other: &Affine) {
+		self.constant = self.constant.wrapping_add(other.constant);
+		for &(offset, factor) in other.terms.iter() {
+			self.add_term(offset, factor);
+		}
+	}
+
+	fn add_term(&mut self, offset: isize, factor: BFCellValue) {
+		if let Some(idx) = self.terms.iter().position(|t| t.0 == offset) {
+			self.terms[idx].1 = self.terms[idx].1.wrapping_add(factor);
+			if self.terms[idx].1 == 0 { self.terms.remove(idx); }
+		} else if factor != 0 {
+			self.terms.push((offset, factor));
+		}
+	}
+
+	fn scaled(&self, factor: BFCellValue) -> Affine {
+		let mut result = Affine::constant(self.constant.wrapping_mul(factor));
+		for &(offset, f) in self.terms.iter() {
+			result.add_term(offset, f.wrapping_mul(factor));
+		}
+		result
+	}
+
+	fn factor_of(&self, offset: isize) -> BFCellValue {
+		self.terms.iter().find(|t| t.0 == offset).map(|t| t.1).unwrap_or(0)
+	}
+
+	fn without(&self, offset: isize) -> Affine {
+		Affine {
+			constant: self.constant,
+			terms: self.terms.iter().cloned().filter(|t| t.0 != offset).collect(),
+		}
+	}
+
+	fn is_constant(&self) -> bool {
+		self.terms.is_empty()
+	}
+
+	/// Replaces each cell that has an entry in `values` with that value.
+	fn substitute(&self, values: &HashMap<isize, Affine>) -> Affine {
+		let mut result = Affine::constant(self.constant);
+		for &(offset, factor) in self.terms.iter() {
+			match values.get(&offset) {
+				Some(value) => result.add(&value.scaled(factor)),
+				None => result.add_term(offset, factor),
+			}
+		}
+		result
+	}
+
+	/// Evaluates the expression against the tape with the loop cell at `base`.
+	#[inline(always)]
+	pub fn eval(&self, memory: &[BFCellValue], base: usize) -> BFCellValue {
+		let mut value = self.constant;
+		for &(offset, factor) in self.terms.iter() {
+			let cell = memory[(base as isize + offset) as usize];
+			value = value.wrapping_add(cell.wrapping_mul(factor));
+		}
+		value
+	}
+}
+
+/// What a summarized loop does to one cell. All expressions are in terms of the
+/// values the cells had before the loop started.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CellUpdate {
+	/// The cell is left with this value once the loop has run at least once.
+	Set(Affine),
+
+	/// The cell has `first` added on the first pass and `rest` added on every pass after that.
+	Accumulate { first: Affine, rest: Affine },
+}
+
+/// Closed form of a loop that runs a number of times that only depends on the loop cell.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoopSummary {
+	/// Offset of the loop cell relative to the data pointer.
+	pub offset: isize,
+
+	/// The loop runs `loop cell * trip_factor` (wrapping) times.
+	pub trip_factor: BFCellValue,
+
+	/// (offset relative to the loop cell, update)
+	pub updates: Vec<(isize, CellUpdate)>,
+}
+
+impl LoopSummary {
+	/// Every offset the loop reads or writes, relative to the loop cell.
+	pub fn touched_offsets(&self) -> Vec<isize> {
+		let mut offsets = vec![0];
+		for &(target, ref update) in self.updates.iter() {
+			offsets.push(target);
+			let exprs = match update {
+				CellUpdate::Set(value) => vec![value],
+				CellUpdate::Accumulate { first, rest } => vec![first, rest],
+			};
+			for expr in exprs {
+				offsets.extend(expr.terms.iter().map(|t| t.0));
+			}
+		}
+		offsets.sort();
+		offsets.dedup();
+		offsets
+	}
+
+	/// Lowest and highest of `touched_offsets`, without collecting them.
+	pub fn extent(&self) -> (isize, isize) {
+		let mut extent = (0, 0);
+		let mut add = |offset: isize| extent = (extent.0.min(offset), extent.1.max(offset));
+		for &(target, ref update) in self.updates.iter() {
+			add(target);
+			match update {
+				CellUpdate::Set(value) => value.terms.iter().for_each(|t| add(t.0)),
+				CellUpdate::Accumulate { first, rest } => {
+					first.terms.iter().chain(rest.terms.iter()).for_each(|t| add(t.0));
+				},
+			}
+		}
+		extent
+	}
+}
+
+/// Inverse of an odd number modulo the cell size.
+fn mod_inverse(value: BFCellValue) -> BFCellValue {
+	debug_assert!(value & 1 == 1, "Only odd numbers have an inverse modulo a power of two.");
+	// Newton's method doubles the number of correct low bits each step, and every odd
+	// number is its own inverse modulo 8 which gives us the first 3.
+	let mut inverse = value;
+	for _ in 0..3 {
+		inverse = inverse.wrapping_mul((2 as BFCellValue).wrapping_sub(value.wrapping_mul(inverse)));
+	}
+	debug_assert_eq!(value.wrapping_mul(inverse), 1);
+	inverse
+}
+
+fn current_value(cells: &HashMap<isize, Affine>, offset: isize) -> Affine {
+	cells.get(&offset).cloned().unwrap_or_else(|| Affine::cell(offset))
+}
+
+/// Evaluates an expression from an inner loop with its loop cell at `base` against the
+/// symbolic values of the outer loop.
+fn eval_symbolic(expr: &Affine, cells: &HashMap<isize, Affine>, base: isize) -> Affine {
+	let mut result = Affine::constant(expr.constant);
+	for &(offset, factor) in expr.terms.iter() {
+		result.add(&current_value(cells, base + offset).scaled(factor));
+	}
+	result
+}
+
+/// Symbolically runs one pass of a loop body made up of pointer moves, additions, and
+/// loops that were already optimized into linear forms. Returns the value of every
+/// cell written to in terms of the values at the start of the pass, or why it can't.
+fn run_symbolic(window: &[BFInstr]) -> Result<HashMap<isize, Affine>, &'static str> {
+	let mut cells: HashMap<isize, Affine> = HashMap::new();
+	let mut ptr = 0isize;
+
+	for instr in window.iter() {
+		match instr {
+			&BFInstr::IncPC(n) => ptr += n as isize,
+			&BFInstr::DecPC(n) => ptr -= n as isize,
+			&BFInstr::IncVal(n) => {
+				cells.entry(ptr).or_insert_with(|| Affine::cell(ptr)).add(&Affine::constant(n as BFCellValue));
+			},
+			&BFInstr::DecVal(n) => {
+				cells.entry(ptr).or_insert_with(|| Affine::cell(ptr)).add(&Affine::constant((n as BFCellValue).wrapping_neg()));
+			},
+			&BFInstr::ZeroCurrentCell => { cells.insert(ptr, Affine::constant(0)); },
+			&BFInstr::MultiplyAdd { offset, ref targets } => {
+				let loop_cell = ptr + offset;
+				let value = current_value(&cells, loop_cell);
+				for &(target, factor) in targets.iter() {
+					let cell = loop_cell + target;
+					cells.entry(cell).or_insert_with(|| Affine::cell(cell)).add(&value.scaled(factor));
+				}
+				cells.insert(loop_cell, Affine::constant(0));
+			},
+			BFInstr::ClosedForm(summary) => {
+				// Only affine if the number of passes is known, which it is when the
+				// inner loop cell was set to a constant earlier in the body.
+				let loop_cell = ptr + summary.offset;
+				let value = current_value(&cells, loop_cell);
+				if !value.is_constant() { return Err("an inner loop runs a number of times that isn't known") }
+				if value.constant == 0 { continue }
+				let passes = value.constant.wrapping_mul(summary.trip_factor);
+
+				let mut new_values = Vec::with_capacity(summary.updates.len());
+				for &(target, ref update) in summary.updates.iter() {
+					let new_value = match update {
+						CellUpdate::Set(expr) => eval_symbolic(expr, &cells, loop_cell),
+						CellUpdate::Accumulate { first, rest } => {
+							let mut new_value = current_value(&cells, loop_cell + target);
+							new_value.add(&eval_symbolic(first, &cells, loop_cell));
+							new_value.add(&eval_symbolic(rest, &cells, loop_cell).scaled(passes.wrapping_sub(1)));
+							new_value
+						},
+					};
+					new_values.push((loop_cell + target, new_value));
+				}
+				cells.extend(new_values);
+				cells.insert(loop_cell, Affine::constant(0));
+			},
+			_ => return Err("the body has I/O, scans or loops that weren't optimized"),
+		}
+	}
+
+	if ptr != 0 { return Err("the body doesn't end on the loop cell") }
+	Ok(cells)
+}
+
+/// Tries to summarize a loop body into a closed form. This handles loops whose loop
+/// cell changes by the same odd amount on every pass (an even step might never reach
+/// zero, so those are left alone) and where every other cell either has a constant
+/// amount added to it each pass, or settles on a value after the first pass. Cells
+/// that the loop doesn't change can be used anywhere, so something like
+/// `[>[->+>+<<]>>[-<<+>>]<<<-]` becomes a single multiplication.
+///
+/// If the loop can't be summarized, this returns why.
+pub fn summarize_loop(window: &[BFInstr]) -> Result<LoopSummary, &'static str> {
+	let cells = run_symbolic(window)?;
+
+	// The loop cell has to change by the same amount on every pass.
+	let loop_cell = match cells.get(&0) {
+		Some(loop_cell) => loop_cell,
+		None => return Err("the loop cell is never changed"),
+	};
+	if loop_cell.factor_of(0) != 1 || !loop_cell.without(0).is_constant() {
+		return Err("the loop cell doesn't change by a constant amount")
+	}
+	let step = loop_cell.constant.wrapping_neg();
+	if step & 1 == 0 { return Err("the loop cell changes by an even amount, so the loop might never end") }
+	let trip_factor = mod_inverse(step);
+
+	let mut written: Vec<(isize, Affine)> = cells.into_iter()
+		.filter(|&(offset, ref value)| offset != 0 && *value != Affine::cell(offset))
+		.collect();
+	written.sort_by_key(|w| w.0);
+	let is_written = |offset: isize| offset == 0 || written.iter().any(|w| w.0 == offset);
+
+	// Cells that have the same value after every pass, starting with the first one.
+	let mut settled: HashMap<isize, Affine> = HashMap::new();
+	let mut updates = Vec::new();
+
+	for &(offset, ref value) in written.iter() {
+		if value.factor_of(offset) == 0 && value.terms.iter().all(|t| !is_written(t.0)) {
+			settled.insert(offset, value.clone());
+			updates.push((offset, CellUpdate::Set(value.clone())));
+		}
+	}
+
+	// Cells that have something added to them on each pass. If what they depend on
+	// is settled after the first pass, then they are either settled as well (if
+	// nothing is added after the first pass) or grow by the same amount on each pass.
+	let mut pending: Vec<(isize, Affine)> = written.iter()
+		.filter(|w| !settled.contains_key(&w.0))
+		.map(|&(offset, ref value)| (offset, value.clone()))
+		.collect();
+	let mut progress = true;
+	while progress && !pending.is_empty() {
+		progress = false;
+		let mut idx = 0;
+		while idx < pending.len() {
+			let (offset, delta) = {
+				let &(offset, ref value) = &pending[idx];
+				if value.factor_of(offset) != 1 { return Err("a cell is multiplied by a constant on each pass") }
+				(offset, value.without(offset))
+			};
+
+			let ready = delta.terms.iter().all(|t| !is_written(t.0) || settled.contains_key(&t.0));
+			if !ready {
+				idx += 1;
+				continue;
+			}
+
+			let rest = delta.substitute(&settled);
+			if rest == Affine::constant(0) {
+				let mut value = Affine::cell(offset);
+				value.add(&delta);
+				settled.insert(offset, value.clone());
+				updates.push((offset, CellUpdate::Set(value)));
+			} else {
+				updates.push((offset, CellUpdate::Accumulate { first: delta, rest }));
+			}
+			pending.remove(idx);
+			progress = true;
+		}
+	}
+
+	// Whatever is left depends on cells that keep changing, or on each other.
+	if !pending.is_empty() { return Err("cells depend on other cells that keep changing") }
+
+	updates.sort_by_key(|u| u.0);
+	Ok(LoopSummary { offset: 0, trip_factor, updates })
+}
+
+/// Lowers a summary into the simplest instruction that does the same thing.
+pub fn summary_to_instr(summary: LoopSummary) -> BFInstr {
+	if summary.updates.is_empty() {
+		return BFInstr::ZeroCurrentCell;
+	}
+
+	// If every cell just has a constant added on each pass this is a multiplication.
+	let mut targets = Vec::with_capacity(summary.updates.len());
+	for &(target, ref update) in summary.updates.iter() {
+		match update {
+			CellUpdate::Accumulate { first, rest } if first.is_constant() && first == rest => {
+				targets.push((target, first.constant.wrapping_mul(summary.trip_factor)));
+			},
+			_ => return BFInstr::ClosedForm(Box::new(summary)),
+		}
+	}
+	BFInstr::MultiplyAdd { offset: summary.offset, targets: targets.into_boxed_slice() }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn summarized(window: &[BFInstr]) -> Result<BFInstr, &'static str> {
+		summarize_loop(window).map(summary_to_instr)
+	}
+
+	#[test]
+	fn every_odd_byte_has_its_inverse() {
+		for value in (1..=BFCellValue::MAX).step_by(2) {
+			let expected = (0..=BFCellValue::MAX).find(|&x| value.wrapping_mul(x) == 1).unwrap();
+			assert_eq!(mod_inverse(value), expected, "inverse of {}", value);
+		}
+	}
+
+	#[test]
+	fn counted_loops_become_multiply_adds() {
+		// [-]
+		assert_eq!(summarized(&[BFInstr::DecVal(1)]), Ok(BFInstr::ZeroCurrentCell));
+		// [->++<]
+		assert_eq!(summarized(&[BFInstr::DecVal(1), BFInstr::IncPC(1), BFInstr::IncVal(2), BFInstr::DecPC(1)]),
+			Ok(BFInstr::MultiplyAdd { offset: 0, targets: vec![(1, 2)].into_boxed_slice() }));
+		// [<+>+++], which runs -1/3 times the loop cell.
+		assert_eq!(summarized(&[BFInstr::DecPC(1), BFInstr::IncVal(1), BFInstr::IncPC(1), BFInstr::IncVal(3)]),
+			Ok(BFInstr::MultiplyAdd { offset: 0, targets: vec![(-1, 85)].into_boxed_slice() }));
+	}
+
+	#[test]
+	fn loops_over_optimized_loops_become_closed_forms() {
+		// [>[->+>+<<]>>[-<<+>>]<<<-] adds the loop cell times the next one to the one after
+		// that. The temporary cell after it is added to the next one too if it isn't 0.
+		let summary = summarize_loop(&[
+			BFInstr::IncPC(1), BFInstr::MultiplyAdd { offset: 0, targets: vec![(1, 1), (2, 1)].into_boxed_slice() },
+			BFInstr::IncPC(2), BFInstr::MultiplyAdd { offset: 0, targets: vec![(-2, 1)].into_boxed_slice() },
+			BFInstr::DecPC(3), BFInstr::DecVal(1),
+		]).unwrap();
+		assert_eq!(summary.trip_factor, 1);
+		let copied = Affine { constant: 0, terms: vec![(1, 1), (3, 1)] };
+		assert_eq!(summary.updates, vec![
+			(1, CellUpdate::Set(copied.clone())),
+			(2, CellUpdate::Accumulate { first: Affine::cell(1), rest: copied }),
+			(3, CellUpdate::Set(Affine::constant(0))),
+		]);
+		assert!(matches!(summary_to_instr(summary), BFInstr::ClosedForm(_)));
+	}
+
+	#[test]
+	fn cells_that_settle_after_the_first_pass_are_set() {
+		// [->[-]+++<]
+		let summary = summarize_loop(&[BFInstr::DecVal(1), BFInstr::IncPC(1), BFInstr::ZeroCurrentCell, BFInstr::IncVal(3), BFInstr::DecPC(1)]).unwrap();
+		assert_eq!(summary.updates, vec![(1, CellUpdate::Set(Affine::constant(3)))]);
+	}
+
+	#[test]
+	fn loops_that_cant_be_summarized_say_why() {
+		let rejected: [(&[BFInstr], &str); 7] = [
+			(&[BFInstr::IncPC(1), BFInstr::IncVal(1), BFInstr::DecPC(1)], "the loop cell is never changed"),
+			(&[BFInstr::DecVal(2), BFInstr::IncPC(1), BFInstr::IncVal(1), BFInstr::DecPC(1)],
+				"the loop cell changes by an even amount, so the loop might never end"),
+			(&[BFInstr::IncPC(1), BFInstr::MultiplyAdd { offset: 0, targets: vec![(-1, 1)].into_boxed_slice() }, BFInstr::DecPC(1), BFInstr::DecVal(1)],
+				"the loop cell doesn't change by a constant amount"),
+			(&[BFInstr::DecVal(1), BFInstr::Output(1)], "the body has I/O, scans or loops that weren't optimized"),
+			(&[BFInstr::DecVal(1), BFInstr::IncPC(1)], "the body doesn't end on the loop cell"),
+			// [->[->++<]>[-<+>]<<] doubles the next cell on each pass.
+			(&[
+				BFInstr::DecVal(1), BFInstr::IncPC(1), BFInstr::MultiplyAdd { offset: 0, targets: vec![(1, 2)].into_boxed_slice() },
+				BFInstr::IncPC(1), BFInstr::MultiplyAdd { offset: 0, targets: vec![(-1, 1)].into_boxed_slice() }, BFInstr::DecPC(2),
+			], "a cell is multiplied by a constant on each pass"),
+			// [->>[-<+>>+<]>[-<+>]<+<<] adds the cell after next, which goes up on each pass, to the next one.
+			(&[
+				BFInstr::DecVal(1), BFInstr::IncPC(2), BFInstr::MultiplyAdd { offset: 0, targets: vec![(-1, 1), (1, 1)].into_boxed_slice() },
+				BFInstr::IncPC(1), BFInstr::MultiplyAdd { offset: 0, targets: vec![(-1, 1)].into_boxed_slice() },
+				BFInstr::DecPC(1), BFInstr::IncVal(1), BFInstr::DecPC(2),
+			], "cells depend on other cells that keep changing"),
+		];
+		for &(window, reason) in rejected.iter() {
+			assert_eq!(summarize_loop(window), Err(reason), "{:?}", window);
+		}
+	}
+}
@@ -0,0 +1,634 @@
+use std::collections::HashMap;
+use ::bf::{self, BFInstr, BFCellValue, BF_MEMORY_SIZE};
+use ::bfreport::{OptReport, SourceSpan};
+
+/// An instruction and the byte offset in the source of the BF command it came from.
+/// Instructions that several commands were merged into keep the first one's position.
+pub type Located = (BFInstr, usize);
+
+/// Runs all of the passes in this module over a parsed program. See `eliminate_dead_code`
+/// for what is added to `dead_loops`. Each pass records what it rewrote in `report`.
+pub fn optimize(instructions: Vec<Located>, dead_loops: &mut Vec<usize>, mut report: Option<&mut OptReport>) -> Vec<Located> {
+	let instructions = sink_pointer_moves(instructions, report.as_deref_mut());
+	let instructions = fold_cell_writes(instructions, report.as_deref_mut());
+	let instructions = eliminate_dead_code(instructions, dead_loops, report.as_deref_mut());
+	// Removing code can leave moves and writes next to each other that can be merged.
+	let instructions = sink_pointer_moves(instructions, report.as_deref_mut());
+	fold_cell_writes(instructions, report)
+}
+
+/// Rewrites cell arithmetic and I/O to be addressed relative to the data pointer
+/// and sinks pointer moves to the end of each basic block, so that something like
+/// `>>+++<-<` only moves the data pointer once.
+///
+/// Loop boundaries and scans need the data pointer to be up to date, so pending
+/// moves are flushed before them. Loop targets have to be relinked afterwards.
+pub fn sink_pointer_moves(instructions: Vec<Located>, mut report: Option<&mut OptReport>) -> Vec<Located> {
+	let mut output = Vec::with_capacity(instructions.len());
+	let mut offset = 0isize;
+	// Position of the first move that hasn't been flushed yet.
+	let mut move_position = None;
+	// The current basic block as it was, and where its rewritten version starts in `output`,
+	// if there's a report to put it in.
+	let mut block = Vec::new();
+	let mut block_start = 0;
+
+	for (instr, position) in instructions.into_iter() {
+		if report.is_some() {
+			block.push((instr.clone(), position));
+		}
+		match instr {
+			BFInstr::IncPC(n) => { offset += n as isize; move_position = move_position.or(Some(position)); },
+			BFInstr::DecPC(n) => { offset -= n as isize; move_position = move_position.or(Some(position)); },
+
+			BFInstr::IncVal(n) => push_add(&mut output, offset, n as BFCellValue, position),
+			BFInstr::DecVal(n) => push_add(&mut output, offset, (n as BFCellValue).wrapping_neg(), position),
+			BFInstr::Output(times) => output.push((BFInstr::Out { offset, times }, position)),
+			BFInstr::Input(times) => output.push((BFInstr::In { offset, times }, position)),
+
+			BFInstr::ZeroCurrentCell => output.push((BFInstr::SetCell(0, offset), position)),
+
+			BFInstr::MultiplyAdd { offset: loop_cell, targets } => {
+				output.push((BFInstr::MultiplyAdd { offset: offset + loop_cell, targets }, position));
+			},
+
+			BFInstr::ClosedForm(mut summary) => {
+				summary.offset += offset;
+				output.push((BFInstr::ClosedForm(summary), position));
+			},
+
+			BFInstr::Add { offset: cell, amount } => push_add(&mut output, offset + cell, amount, position),
+			BFInstr::SetCell(value, cell) => output.push((BFInstr::SetCell(value, offset + cell), position)),
+			BFInstr::Out { offset: cell, times } => output.push((BFInstr::Out { offset: offset + cell, times }, position)),
+			BFInstr::In { offset: cell, times } => output.push((BFInstr::In { offset: offset + cell, times }, position)),
+
+			other => {
+				flush_move(&mut output, offset, move_position.unwrap_or(position));
+				offset = 0;
+				move_position = None;
+				block.pop();
+				report_sunk_moves(report.as_deref_mut(), &block, &output[block_start..]);
+				block.clear();
+				output.push((other, position));
+				block_start = output.len();
+			},
+		}
+	}
+
+	// Moves at the very end of the program still stop it if they go off of the tape.
+	if let Some(position) = move_position {
+		flush_move(&mut output, offset, position);
+	}
+	report_sunk_moves(report, &block, &output[block_start..]);
+	output
+}
+
+/// Records that the moves in `block` were sunk, if any of them had to be moved past
+/// something else to get to the end of it.
+fn report_sunk_moves(report: Option<&mut OptReport>, block: &[Located], sunk: &[Located]) {
+	let is_move = |located: &&Located| matches!(located.0, BFInstr::IncPC(_) | BFInstr::DecPC(_));
+	if let Some(report) = report {
+		if block.iter().skip_while(|located| !is_move(located)).any(|located| !is_move(&located)) {
+			report.rewrote(span_of(block), "sink-moves", format_located(block), format_located(sunk));
+		}
+	}
+}
+
+/// Folds additions into a preceding `SetCell` on the same cell (`[-]+++++` becomes a
+/// single `SetCell(5, 0)`), merges additions to the same cell, and drops writes that
+/// are overwritten before anything reads them, like the `+++` in `+++[-]` or the
+/// first of two consecutive `[-]`s.
+///
+/// A write to a cell that might be off of the tape stops the program, so it's only
+/// dropped if the cell is known to be on the tape, and nothing is folded into it across
+/// I/O. Additions are still merged into it otherwise, since that keeps it where it was.
+///
+/// This only looks inside of basic blocks, so it expects `sink_pointer_moves` to have
+/// been run first.
+pub fn fold_cell_writes(instructions: Vec<Located>, mut report: Option<&mut OptReport>) -> Vec<Located> {
+	let mut output: Vec<Option<Located>> = Vec::with_capacity(instructions.len());
+
+	// Index in `output` of the last write to each offset that nothing has read yet.
+	let mut pending_writes: HashMap<isize, usize> = HashMap::new();
+	// Only `on_tape` is kept up to date. Writes can still be dropped, so they don't count
+	// as using their cells.
+	let mut known = KnownCells::program_start();
+
+	for (instr, position) in instructions.into_iter() {
+		match instr {
+			BFInstr::SetCell(value, offset) => {
+				if !known.is_on_tape(offset) {
+					pending_writes.remove(&offset);
+				}
+				if let Some(idx) = pending_writes.remove(&offset) {
+					let dropped = output[idx].take().unwrap();
+					if let Some(ref mut report) = report {
+						let before = [dropped, (BFInstr::SetCell(value, offset), position)];
+						report.rewrote(span_of(&before), "fold-writes", format_located(&before), bf::format_bf_window(&[BFInstr::SetCell(value, offset)]));
+					}
+				}
+				pending_writes.insert(offset, output.len());
+				output.push(Some((BFInstr::SetCell(value, offset), position)));
+			},
+
+			BFInstr::Add { offset, amount } => {
+				if let Some(&idx) = pending_writes.get(&offset) {
+					if let Some((ref mut pending, pending_position)) = output[idx] {
+						let merged = match *pending {
+							BFInstr::SetCell(value, _) => BFInstr::SetCell(value.wrapping_add(amount), offset),
+							BFInstr::Add { amount: previous, .. } => BFInstr::Add { offset, amount: previous.wrapping_add(amount) },
+							_ => unreachable!("Pending writes are always SetCell or Add."),
+						};
+						if let Some(ref mut report) = report {
+							let before = [(pending.clone(), pending_position), (BFInstr::Add { offset, amount }, position)];
+							let after = match merged {
+								BFInstr::Add { amount: 0, .. } => "removed, they add up to 0".to_string(),
+								_ => bf::format_bf_window(std::slice::from_ref(&merged)),
+							};
+							report.rewrote(span_of(&before), "fold-writes", format_located(&before), after);
+						}
+						*pending = merged;
+					}
+				} else {
+					pending_writes.insert(offset, output.len());
+					output.push(Some((BFInstr::Add { offset, amount }, position)));
+				}
+			},
+
+			BFInstr::Out { offset, .. } | BFInstr::In { offset, .. } => {
+				pending_writes.remove(&offset);
+				pending_writes.retain(|&pending, _| known.is_on_tape(pending));
+				known.use_cell(offset);
+				output.push(Some((instr, position)));
+			},
+
+			BFInstr::MultiplyAdd { offset, ref targets } => {
+				pending_writes.remove(&offset);
+				for &(target, _) in targets.iter() {
+					pending_writes.remove(&(offset + target));
+				}
+				known.use_cell(offset);
+				output.push(Some((instr.clone(), position)));
+			},
+
+			BFInstr::ClosedForm(ref summary) => {
+				for target in summary.touched_offsets() {
+					pending_writes.remove(&(summary.offset + target));
+				}
+				known.use_cell(summary.offset);
+				output.push(Some((instr.clone(), position)));
+			},
+
+			other => {
+				// Anything else either moves the data pointer or branches.
+				pending_writes.clear();
+				match other {
+					BFInstr::IncPC(n) => known.move_ptr(n as isize),
+					BFInstr::DecPC(n) => known.move_ptr(-(n as isize)),
+					BFInstr::LoopStart(_) | BFInstr::LoopEnd(_) | BFInstr::FindZeroCellLeft(_) | BFInstr::FindZeroCellRight(_) => {
+						known.on_tape = Some((0, 0));
+					},
+					_ => known.on_tape = None,
+				}
+				output.push(Some((other, position)));
+			},
+		}
+	}
+
+	output.into_iter()
+		.flatten()
+		.filter(|located| !matches!(located, (BFInstr::Add { amount: 0, .. }, _)))
+		.collect()
+}
+
+/// Cell values that are known at some point in the program, relative to the data pointer.
+struct KnownCells {
+	/// Value of every cell that isn't in `cells`.
+	rest: Option<BFCellValue>,
+	cells: HashMap<isize, Option<BFCellValue>>,
+	/// Lowest and highest offsets that are known to be on the tape.
+	on_tape: Option<(isize, isize)>,
+}
+
+impl KnownCells {
+	/// Every cell starts out as zero.
+	fn program_start() -> KnownCells {
+		KnownCells { rest: Some(0), cells: HashMap::new(), on_tape: Some((0, BF_MEMORY_SIZE as isize - 1)) }
+	}
+
+	fn get(&self, offset: isize) -> Option<BFCellValue> {
+		match self.cells.get(&offset) {
+			Some(&value) => value,
+			None => self.rest,
+		}
+	}
+
+	fn set(&mut self, offset: isize, value: Option<BFCellValue>) {
+		self.cells.insert(offset, value);
+	}
+
+	fn move_ptr(&mut self, amount: isize) {
+		self.cells = self.cells.drain().map(|(offset, value)| (offset - amount, value)).collect();
+		self.on_tape = self.on_tape.map(|(lowest, highest)| (lowest - amount, highest - amount));
+	}
+
+	fn is_on_tape(&self, offset: isize) -> bool {
+		match self.on_tape {
+			Some((lowest, highest)) => lowest <= offset && offset <= highest,
+			None => false,
+		}
+	}
+
+	/// Records that the cell at `offset` is used by code that stays in the program, which
+	/// stops it if the cell isn't on the tape. The tape has no gaps, so neither does the range.
+	fn use_cell(&mut self, offset: isize) {
+		self.on_tape = Some(match self.on_tape {
+			Some((lowest, highest)) => (lowest.min(offset), highest.max(offset)),
+			None => (offset, offset),
+		});
+	}
+
+	fn forget(&mut self) {
+		self.rest = None;
+		self.cells.clear();
+	}
+}
+
+/// Tracks cells with known values to remove code that can never do anything: loops
+/// entered while the current cell is known to be 0 (every cell is 0 at the start of
+/// the program and the current cell is 0 right after any loop, so this catches
+/// "comment loops"), scans that start on a zero cell, and `SetCell`s that write a
+/// value the cell already has. `MultiplyAdd`s on a cell with a known value are folded
+/// into plain additions.
+///
+/// Code that uses a cell that might be off of the tape is never removed, because it
+/// would stop the program there.
+///
+/// The position of every removed loop among all of the loops in `instructions`
+/// (counting from 0, in order of their `LoopStart`s) is added to `dead_loops`.
+pub fn eliminate_dead_code(instructions: Vec<Located>, dead_loops: &mut Vec<usize>, mut report: Option<&mut OptReport>) -> Vec<Located> {
+	let mut output = Vec::with_capacity(instructions.len());
+	let mut known = KnownCells::program_start();
+	let mut instructions = instructions.into_iter();
+	let mut loops_seen = 0;
+
+	while let Some((instr, position)) = instructions.next() {
+		match instr {
+			BFInstr::IncPC(n) => { known.move_ptr(n as isize); output.push((instr, position)); },
+			BFInstr::DecPC(n) => { known.move_ptr(-(n as isize)); output.push((instr, position)); },
+
+			BFInstr::Add { offset, amount } => {
+				let value = known.get(offset).map(|v| v.wrapping_add(amount));
+				known.set(offset, value);
+				known.use_cell(offset);
+				output.push((instr, position));
+			},
+
+			BFInstr::SetCell(value, offset) => {
+				if known.get(offset) != Some(value) || !known.is_on_tape(offset) {
+					known.set(offset, Some(value));
+					known.use_cell(offset);
+					output.push((instr, position));
+				} else if let Some(ref mut report) = report {
+					let removed = [(instr, position)];
+					report.rewrote(span_of(&removed), "dead-code", format_located(&removed), "removed, the cell already has this value".to_string());
+				}
+			},
+
+			BFInstr::In { offset, .. } => { known.set(offset, None); known.use_cell(offset); output.push((instr, position)); },
+			BFInstr::Out { offset, .. } => { known.use_cell(offset); output.push((instr, position)); },
+
+			BFInstr::MultiplyAdd { offset, ref targets } => {
+				let targets_on_tape = targets.iter().all(|&(target, _)| known.is_on_tape(offset + target));
+				let removed = [(instr.clone(), position)];
+				match known.get(offset) {
+					Some(0) if known.is_on_tape(offset) => {
+						if let Some(ref mut report) = report {
+							report.rewrote(span_of(&removed), "dead-code", format_located(&removed), "removed, the loop cell is always 0 here".to_string());
+						}
+					},
+					Some(loop_value) if loop_value != 0 && targets_on_tape => {
+						let folded_start = output.len();
+						for &(target, factor) in targets.iter() {
+							let amount = loop_value.wrapping_mul(factor);
+							let value = known.get(offset + target).map(|v| v.wrapping_add(amount));
+							known.set(offset + target, value);
+							push_add(&mut output, offset + target, amount, position);
+						}
+						known.set(offset, Some(0));
+						known.use_cell(offset);
+						output.push((BFInstr::SetCell(0, offset), position));
+						if let Some(ref mut report) = report {
+							report.rewrote(span_of(&removed), "dead-code", format_located(&removed), format_located(&output[folded_start..]));
+						}
+					},
+					loop_value => {
+						if let (Some(report), Some(_)) = (report.as_mut(), loop_value) {
+							report.rejected(span_of(&removed), "dead-code", format_located(&removed), "a cell it uses might not be on the tape");
+						}
+						for &(target, _) in targets.iter() {
+							known.set(offset + target, None);
+						}
+						known.set(offset, Some(0));
+						known.use_cell(offset);
+						output.push((instr.clone(), position));
+					},
+				}
+			},
+
+			BFInstr::ClosedForm(ref summary) => {
+				if known.get(summary.offset) != Some(0) || !known.is_on_tape(summary.offset) {
+					for &(target, _) in summary.updates.iter() {
+						known.set(summary.offset + target, None);
+					}
+					known.set(summary.offset, Some(0));
+					known.use_cell(summary.offset);
+					output.push((instr.clone(), position));
+				} else if let Some(ref mut report) = report {
+					let removed = [(instr.clone(), position)];
+					report.rewrote(span_of(&removed), "dead-code", format_located(&removed), "removed, the loop cell is always 0 here".to_string());
+				}
+			},
+
+			BFInstr::FindZeroCellLeft(_) | BFInstr::FindZeroCellRight(_) => {
+				if known.get(0) != Some(0) || !known.is_on_tape(0) {
+					known.forget();
+					known.set(0, Some(0));
+					known.on_tape = Some((0, 0));
+					output.push((instr, position));
+				} else if let Some(ref mut report) = report {
+					let removed = [(instr, position)];
+					report.rewrote(span_of(&removed), "dead-code", format_located(&removed), "removed, the current cell is already 0".to_string());
+				}
+			},
+
+			BFInstr::LoopStart(_) => {
+				loops_seen += 1;
+				if known.get(0) == Some(0) && known.is_on_tape(0) {
+					dead_loops.push(loops_seen - 1);
+					loops_seen += skip_loop(&mut instructions);
+				} else {
+					// The body can run any number of times so nothing is known inside of it,
+					// other than that the cell that was just checked is on the tape.
+					known.forget();
+					known.on_tape = Some((0, 0));
+					output.push((instr, position));
+				}
+			},
+
+			BFInstr::LoopEnd(_) => {
+				known.forget();
+				known.set(0, Some(0));
+				known.on_tape = Some((0, 0));
+				output.push((instr, position));
+			},
+
+			// Either the idiom or its snippet runs, so nothing is known across either end.
+			BFInstr::Idiom { .. } | BFInstr::IdiomEnd(_) => {
+				known.forget();
+				known.on_tape = None;
+				output.push((instr, position));
+			},
+
+			BFInstr::ZeroCurrentCell => { known.set(0, Some(0)); known.use_cell(0); output.push((instr, position)); },
+			BFInstr::IncVal(_) | BFInstr::DecVal(_) | BFInstr::Input(_) => { known.set(0, None); known.use_cell(0); output.push((instr, position)); },
+			BFInstr::Output(_) | BFInstr::OutputBytes(_) => output.push((instr, position)),
+		}
+	}
+
+	output
+}
+
+/// Skips the rest of a loop whose `LoopStart` was just taken from `instructions`,
+/// and returns how many loops were inside of it.
+fn skip_loop<I: Iterator<Item = Located>>(instructions: &mut I) -> usize {
+	let mut depth = 1;
+	let mut inner_loops = 0;
+	while depth > 0 {
+		match instructions.next() {
+			Some((BFInstr::LoopStart(_), _)) => { depth += 1; inner_loops += 1; },
+			Some((BFInstr::LoopEnd(_), _)) => depth -= 1,
+			Some(_) => {},
+			None => break,
+		}
+	}
+	inner_loops
+}
+
+/// Points every `LoopStart` and `LoopEnd`, and every `Idiom` and `IdiomEnd`, at its partner.
+pub fn link_loops(instructions: &mut [BFInstr]) {
+	let mut loop_stack = Vec::new();
+	let mut idiom_stack = Vec::new();
+	for idx in 0..instructions.len() {
+		match instructions[idx] {
+			BFInstr::LoopStart(_) => loop_stack.push(idx),
+			BFInstr::LoopEnd(_) => {
+				let loop_start = loop_stack.pop().expect("Unbalanced loops after optimization.");
+				instructions[loop_start] = BFInstr::LoopStart(idx);
+				instructions[idx] = BFInstr::LoopEnd(loop_start);
+			},
+			BFInstr::Idiom { .. } => idiom_stack.push(idx),
+			BFInstr::IdiomEnd(_) => {
+				let idiom_start = idiom_stack.pop().expect("Unbalanced idioms after optimization.");
+				if let BFInstr::Idiom { ref mut end, .. } = instructions[idiom_start] {
+					*end = idx;
+				}
+				instructions[idx] = BFInstr::IdiomEnd(idiom_start);
+			},
+			_ => {},
+		}
+	}
+	debug_assert!(loop_stack.is_empty(), "Unbalanced loops after optimization.");
+	debug_assert!(idiom_stack.is_empty(), "Unbalanced idioms after optimization.");
+}
+
+/// Span from the first to the last of the commands that `located` came from.
+fn span_of(located: &[Located]) -> SourceSpan {
+	let start = located.iter().map(|&(_, position)| position).min().unwrap_or(0);
+	let end = located.iter().map(|&(_, position)| position).max().unwrap_or(0);
+	SourceSpan { start, end }
+}
+
+fn format_located(located: &[Located]) -> String {
+	let instructions: Vec<BFInstr> = located.iter().map(|(instr, _)| instr.clone()).collect();
+	bf::format_bf_window(&instructions)
+}
+
+fn push_add(output: &mut Vec<Located>, offset: isize, amount: BFCellValue, position: usize) {
+	if amount != 0 {
+		output.push((BFInstr::Add { offset, amount }, position));
+	}
+}
+
+fn flush_move(output: &mut Vec<Located>, offset: isize, position: usize) {
+	if offset > 0 {
+		output.push((BFInstr::IncPC(offset as usize), position));
+	} else if offset < 0 {
+		output.push((BFInstr::DecPC((-offset) as usize), position));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Gives each instruction its index as its position.
+	fn located(instructions: Vec<BFInstr>) -> Vec<Located> {
+		instructions.into_iter().enumerate().map(|(position, instr)| (instr, position)).collect()
+	}
+
+	#[test]
+	fn moves_are_folded_into_offsets() {
+		// >>+++<-<.
+		let sunk = sink_pointer_moves(located(vec![
+			BFInstr::IncPC(2), BFInstr::IncVal(3), BFInstr::DecPC(1), BFInstr::DecVal(1), BFInstr::DecPC(1), BFInstr::Output(1),
+		]), None);
+		assert_eq!(sunk, vec![
+			(BFInstr::Add { offset: 2, amount: 3 }, 1), (BFInstr::Add { offset: 1, amount: 255 }, 3),
+			(BFInstr::Out { offset: 0, times: 1 }, 5),
+		]);
+	}
+
+	#[test]
+	fn moves_are_flushed_before_loops() {
+		// >+>[<]
+		let sunk = sink_pointer_moves(located(vec![
+			BFInstr::IncPC(1), BFInstr::IncVal(1), BFInstr::IncPC(1), BFInstr::LoopStart(5), BFInstr::DecPC(1), BFInstr::LoopEnd(3),
+		]), None);
+		assert_eq!(sunk, vec![
+			(BFInstr::Add { offset: 1, amount: 1 }, 1), (BFInstr::IncPC(2), 0),
+			(BFInstr::LoopStart(5), 3), (BFInstr::DecPC(1), 4), (BFInstr::LoopEnd(3), 5),
+		]);
+	}
+
+	#[test]
+	fn moves_at_the_end_are_kept() {
+		let sunk = sink_pointer_moves(located(vec![BFInstr::IncVal(1), BFInstr::DecPC(3)]), None);
+		assert_eq!(sunk, vec![(BFInstr::Add { offset: 0, amount: 1 }, 0), (BFInstr::DecPC(3), 1)]);
+	}
+
+	#[test]
+	fn multiply_adds_are_moved_with_the_data_pointer() {
+		let sunk = sink_pointer_moves(located(vec![
+			BFInstr::IncPC(1), BFInstr::MultiplyAdd { offset: 0, targets: vec![(1, 2)].into_boxed_slice() },
+			BFInstr::Input(1), BFInstr::LoopStart(4), BFInstr::LoopEnd(3),
+		]), None);
+		assert_eq!(sunk, vec![
+			(BFInstr::MultiplyAdd { offset: 1, targets: vec![(1, 2)].into_boxed_slice() }, 1),
+			(BFInstr::In { offset: 1, times: 1 }, 2), (BFInstr::IncPC(1), 0), (BFInstr::LoopStart(4), 3), (BFInstr::LoopEnd(3), 4),
+		]);
+	}
+
+	#[test]
+	fn additions_are_folded_into_writes() {
+		// [-]+++++
+		let folded = fold_cell_writes(located(vec![BFInstr::SetCell(0, 0), BFInstr::Add { offset: 0, amount: 5 }]), None);
+		assert_eq!(folded, vec![(BFInstr::SetCell(5, 0), 0)]);
+	}
+
+	#[test]
+	fn additions_to_the_same_cell_are_merged() {
+		let folded = fold_cell_writes(located(vec![
+			BFInstr::Add { offset: 1, amount: 2 }, BFInstr::Add { offset: 0, amount: 1 },
+			BFInstr::Add { offset: 1, amount: 3 }, BFInstr::Add { offset: 0, amount: 255 },
+		]), None);
+		assert_eq!(folded, vec![(BFInstr::Add { offset: 1, amount: 5 }, 0)]);
+	}
+
+	#[test]
+	fn overwritten_writes_are_dropped() {
+		// +++[-] and [-][-]
+		let folded = fold_cell_writes(located(vec![
+			BFInstr::Add { offset: 0, amount: 3 }, BFInstr::SetCell(0, 0),
+			BFInstr::SetCell(0, 1), BFInstr::SetCell(0, 1),
+		]), None);
+		assert_eq!(folded, vec![(BFInstr::SetCell(0, 0), 1), (BFInstr::SetCell(0, 1), 3)]);
+	}
+
+	#[test]
+	fn writes_that_are_read_are_kept() {
+		let instructions = located(vec![
+			BFInstr::SetCell(1, 0), BFInstr::Out { offset: 0, times: 1 }, BFInstr::SetCell(2, 0),
+			BFInstr::SetCell(3, 1), BFInstr::MultiplyAdd { offset: 1, targets: vec![(1, 1)].into_boxed_slice() }, BFInstr::SetCell(4, 1),
+			BFInstr::Add { offset: 2, amount: 1 }, BFInstr::LoopStart(8), BFInstr::LoopEnd(7), BFInstr::Add { offset: 2, amount: 1 },
+		]);
+		assert_eq!(fold_cell_writes(instructions.clone(), None), instructions);
+	}
+
+	#[test]
+	fn writes_that_might_be_off_of_the_tape_are_kept() {
+		// <+++>.<[-] and the same in a loop, where only the loop cell is known to be on the tape.
+		let instructions = located(vec![
+			BFInstr::Add { offset: -1, amount: 3 }, BFInstr::Out { offset: 0, times: 1 }, BFInstr::SetCell(0, -1),
+			BFInstr::LoopStart(6), BFInstr::Add { offset: 1, amount: 3 }, BFInstr::SetCell(0, 1), BFInstr::LoopEnd(3),
+		]);
+		assert_eq!(fold_cell_writes(instructions.clone(), None), instructions);
+	}
+
+	/// Runs `eliminate_dead_code` and returns what's left and which loops were removed.
+	fn without_dead_code(instructions: Vec<BFInstr>) -> (Vec<BFInstr>, Vec<usize>) {
+		let mut dead_loops = Vec::new();
+		let output = eliminate_dead_code(located(instructions), &mut dead_loops, None);
+		(output.into_iter().map(|(instr, _)| instr).collect(), dead_loops)
+	}
+
+	#[test]
+	fn comment_loops_are_removed() {
+		// [[.]]+[-][.]
+		let (output, dead_loops) = without_dead_code(vec![
+			BFInstr::LoopStart(4), BFInstr::LoopStart(3), BFInstr::Out { offset: 0, times: 1 }, BFInstr::LoopEnd(1), BFInstr::LoopEnd(0),
+			BFInstr::Add { offset: 0, amount: 1 }, BFInstr::LoopStart(8), BFInstr::Add { offset: 0, amount: 255 }, BFInstr::LoopEnd(6),
+			BFInstr::LoopStart(11), BFInstr::Out { offset: 0, times: 1 }, BFInstr::LoopEnd(9),
+		]);
+		assert_eq!(output, vec![
+			BFInstr::Add { offset: 0, amount: 1 }, BFInstr::LoopStart(8), BFInstr::Add { offset: 0, amount: 255 }, BFInstr::LoopEnd(6),
+		]);
+		// The inner loop of the first one is counted too.
+		assert_eq!(dead_loops, vec![0, 3]);
+	}
+
+	#[test]
+	fn loops_on_cells_that_might_not_be_zero_are_kept() {
+		let instructions = vec![
+			BFInstr::In { offset: 0, times: 1 }, BFInstr::LoopStart(3), BFInstr::Add { offset: 0, amount: 255 }, BFInstr::LoopEnd(1),
+			BFInstr::IncPC(1), BFInstr::Add { offset: 0, amount: 1 }, BFInstr::LoopStart(7), BFInstr::LoopEnd(6),
+		];
+		assert_eq!(without_dead_code(instructions.clone()), (instructions, vec![]));
+	}
+
+	#[test]
+	fn dead_code_that_might_be_off_of_the_tape_is_kept() {
+		// <[-]>
+		let instructions = vec![BFInstr::DecPC(1), BFInstr::LoopStart(3), BFInstr::Add { offset: 0, amount: 255 }, BFInstr::LoopEnd(1), BFInstr::IncPC(1)];
+		assert_eq!(without_dead_code(instructions.clone()), (instructions, vec![]));
+		let instructions = vec![BFInstr::SetCell(0, -1), BFInstr::SetCell(0, BF_MEMORY_SIZE as isize)];
+		assert_eq!(without_dead_code(instructions.clone()), (instructions, vec![]));
+	}
+
+	#[test]
+	fn writes_of_known_values_are_removed() {
+		let (output, _) = without_dead_code(vec![
+			BFInstr::SetCell(0, 1), BFInstr::Add { offset: 2, amount: 3 }, BFInstr::SetCell(3, 2), BFInstr::FindZeroCellRight(1),
+		]);
+		assert_eq!(output, vec![BFInstr::Add { offset: 2, amount: 3 }]);
+	}
+
+	#[test]
+	fn multiply_adds_of_known_values_are_folded() {
+		let (output, _) = without_dead_code(vec![
+			BFInstr::Add { offset: 0, amount: 3 }, BFInstr::MultiplyAdd { offset: 0, targets: vec![(1, 2), (-1, 1)].into_boxed_slice() },
+		]);
+		// The cell to the left of the first one isn't on the tape, so that can't be folded.
+		assert_eq!(output, vec![
+			BFInstr::Add { offset: 0, amount: 3 }, BFInstr::MultiplyAdd { offset: 0, targets: vec![(1, 2), (-1, 1)].into_boxed_slice() },
+		]);
+
+		let (output, _) = without_dead_code(vec![
+			BFInstr::Add { offset: 0, amount: 3 }, BFInstr::MultiplyAdd { offset: 0, targets: vec![(1, 2), (2, 1)].into_boxed_slice() },
+		]);
+		assert_eq!(output, vec![
+			BFInstr::Add { offset: 0, amount: 3 }, BFInstr::Add { offset: 1, amount: 6 }, BFInstr::Add { offset: 2, amount: 3 },
+			BFInstr::SetCell(0, 0),
+		]);
+	}
+}
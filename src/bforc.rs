@@ -0,0 +1,77 @@
+use std::ffi::{CStr, CString};
+use std::ptr;
+use llvm::core::*;
+use llvm::error::*;
+use llvm::orc2::*;
+use llvm::orc2::lljit::*;
+
+/// Machine code that was already compiled to an object file, linked into this process.
+/// The code is unloaded when this is dropped.
+pub struct ObjectJIT {
+	jit: LLVMOrcLLJITRef,
+}
+
+impl ObjectJIT {
+	/// Links `object`, resolving the functions it calls by name from `symbols`. The native
+	/// target has to have been initialized.
+	pub fn load(object: &[u8], symbols: &[(&str, usize)]) -> Result<ObjectJIT, String> {
+		unsafe {
+			let mut jit = ptr::null_mut();
+			check(LLVMOrcCreateLLJIT(&mut jit, ptr::null_mut()))?;
+			let object_jit = ObjectJIT { jit };
+			let dylib = LLVMOrcLLJITGetMainJITDylib(jit);
+
+			let mut pairs = Vec::with_capacity(symbols.len());
+			for &(name, address) in symbols.iter() {
+				let name = CString::new(name).map_err(|err| err.to_string())?;
+				pairs.push(LLVMJITCSymbolMapPair {
+					Name: LLVMOrcLLJITMangleAndIntern(jit, name.as_ptr()),
+					Sym: LLVMJITEvaluatedSymbol {
+						Address: address as u64,
+						Flags: LLVMJITSymbolFlags {
+							GenericFlags: LLVMJITSymbolGenericFlags::LLVMJITSymbolGenericFlagsExported as u8 |
+								LLVMJITSymbolGenericFlags::LLVMJITSymbolGenericFlagsCallable as u8,
+							TargetFlags: 0,
+						},
+					},
+				});
+			}
+			// The unit takes over the references to the names.
+			let unit = LLVMOrcAbsoluteSymbols(pairs.as_mut_ptr(), pairs.len());
+			if let Err(err) = check(LLVMOrcJITDylibDefine(dylib, unit)) {
+				LLVMOrcDisposeMaterializationUnit(unit);
+				return Err(err);
+			}
+
+			// The JIT takes over the buffer.
+			let buffer = LLVMCreateMemoryBufferWithMemoryRangeCopy(object.as_ptr() as *const _,
+				object.len(), b"object\0".as_ptr() as *const _);
+			check(LLVMOrcLLJITAddObjectFile(jit, dylib, buffer))?;
+			Ok(object_jit)
+		}
+	}
+
+	/// Address of the function called `name`, linking the object if it hasn't been yet.
+	pub fn lookup(&self, name: &str) -> Result<usize, String> {
+		let name = CString::new(name).map_err(|err| err.to_string())?;
+		let mut address = 0;
+		unsafe { check(LLVMOrcLLJITLookup(self.jit, &mut address, name.as_ptr()))?; }
+		Ok(address as usize)
+	}
+}
+
+impl Drop for ObjectJIT {
+	fn drop(&mut self) {
+		unsafe { let _ = check(LLVMOrcDisposeLLJIT(self.jit)); }
+	}
+}
+
+unsafe fn check(error: LLVMErrorRef) -> Result<(), String> {
+	if error.is_null() {
+		return Ok(());
+	}
+	let message = LLVMGetErrorMessage(error);
+	let result = CStr::from_ptr(message).to_string_lossy().into_owned();
+	LLVMDisposeErrorMessage(message);
+	Err(result)
+}
@@ -0,0 +1,230 @@
+use std::cmp::Reverse;
+use std::fmt;
+
+/// Longest window that is printed in full, in characters.
+const MAX_WINDOW_LEN: usize = 80;
+
+/// Byte offsets of the first and last character of something in the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SourceSpan {
+	pub start: usize,
+	pub end: usize,
+}
+
+/// Line and column of something in the source, both starting at 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceLocation {
+	pub line: usize,
+	pub column: usize,
+}
+
+impl SourceLocation {
+	/// Location of a byte offset, given the byte offset of the start of every line after the first.
+	pub fn of(position: usize, line_starts: &[usize]) -> SourceLocation {
+		let line = match line_starts.binary_search(&position) {
+			Ok(idx) => idx + 1,
+			Err(idx) => idx,
+		};
+		let line_start = if line == 0 { 0 } else { line_starts[line - 1] };
+		SourceLocation { line: line + 1, column: position - line_start + 1 }
+	}
+}
+
+impl fmt::Display for SourceLocation {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}:{}", self.line, self.column)
+	}
+}
+
+pub struct ReportEntry {
+	pub span: SourceSpan,
+	pub pass: &'static str,
+
+	/// The code before the pass ran, in `format_bf_window` notation.
+	pub before: String,
+
+	/// What the code was rewritten to, or why it was left alone.
+	pub outcome: Result<String, String>,
+}
+
+/// Record of which rewrites were done to which parts of a program.
+pub struct OptReport {
+	pub entries: Vec<ReportEntry>,
+
+	/// Byte offset of the start of every line after the first.
+	line_starts: Vec<usize>,
+
+	/// Byte offset of every BF command read so far.
+	commands: Vec<usize>,
+
+	/// Loops that are still around after parsing, in no particular order until `finish_parse`.
+	loop_spans: Vec<SourceSpan>,
+}
+
+impl OptReport {
+	pub fn new() -> OptReport {
+		OptReport {
+			entries: Vec::new(),
+			line_starts: Vec::new(),
+			commands: Vec::new(),
+			loop_spans: Vec::new(),
+		}
+	}
+
+	/// Called with every BF command and newline in the source.
+	pub fn read_char(&mut self, ch: u8, position: usize) {
+		if ch == b'\n' {
+			self.line_starts.push(position + 1);
+		} else {
+			self.commands.push(position);
+		}
+	}
+
+	/// Span from the `first` to the `last` BF command read (counting from 0).
+	pub fn span(&self, first: usize, last: usize) -> SourceSpan {
+		SourceSpan { start: self.commands[first], end: self.commands[last] }
+	}
+
+	pub fn rewrote(&mut self, span: SourceSpan, pass: &'static str, before: String, after: String) {
+		self.entries.push(ReportEntry { span, pass, before, outcome: Ok(after) });
+	}
+
+	pub fn rejected(&mut self, span: SourceSpan, pass: &'static str, before: String, reason: &str) {
+		self.entries.push(ReportEntry { span, pass, before, outcome: Err(reason.to_string()) });
+	}
+
+	/// A loop made it through being parsed as a loop.
+	pub fn kept_loop(&mut self, span: SourceSpan) {
+		self.loop_spans.push(span);
+	}
+
+	/// A loop was rewritten, so the loops inside of it are gone.
+	pub fn replaced_loop(&mut self, span: SourceSpan) {
+		self.loop_spans.retain(|inner| inner.start < span.start || inner.end > span.end);
+	}
+
+	/// Loops found while parsing are closed innermost first, so they have to be sorted
+	/// to match up with the order of the `LoopStart`s in the parsed program.
+	pub fn finish_parse(&mut self) {
+		self.loop_spans.sort();
+		self.commands = Vec::new();
+	}
+
+	/// Span of the `nth` loop left in the program after parsing.
+	pub fn loop_span(&self, nth: usize) -> Option<SourceSpan> {
+		self.loop_spans.get(nth).cloned()
+	}
+
+	fn location(&self, position: usize) -> SourceLocation {
+		SourceLocation::of(position, &self.line_starts)
+	}
+}
+
+impl fmt::Display for OptReport {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let mut entries: Vec<&ReportEntry> = self.entries.iter().collect();
+		entries.sort_by_key(|entry| entry.span);
+
+		for entry in entries {
+			write!(f, "{}-{} {}: {}", self.location(entry.span.start), self.location(entry.span.end),
+				entry.pass, shorten(&entry.before))?;
+			match entry.outcome {
+				Ok(ref after) => writeln!(f, " => {}", shorten(after))?,
+				Err(ref reason) => writeln!(f, " rejected: {}", reason)?,
+			}
+		}
+		Ok(())
+	}
+}
+
+fn shorten(window: &str) -> String {
+	if window.chars().count() <= MAX_WINDOW_LEN {
+		window.to_string()
+	} else {
+		let mut short: String = window.chars().take(MAX_WINDOW_LEN).collect();
+		short.push_str("...");
+		short
+	}
+}
+
+/// How many times a loop ran, from a build instrumented to count it.
+pub struct LoopProfileEntry {
+	pub start: SourceLocation,
+	pub end: SourceLocation,
+
+	/// The loop, in `format_bf_window` notation.
+	pub code: String,
+
+	/// Times the loop was got to, whether or not it went around.
+	pub entries: u64,
+
+	/// Times the loop's body ran.
+	pub iterations: u64,
+}
+
+/// Counts of how many times each loop in a program ran.
+pub struct LoopProfile {
+	pub entries: Vec<LoopProfileEntry>,
+}
+
+impl fmt::Display for LoopProfile {
+	/// Loops that ran the most go first, and loops that were never got to are left out.
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let mut entries: Vec<&LoopProfileEntry> = self.entries.iter().filter(|entry| entry.entries > 0).collect();
+		entries.sort_by_key(|entry| (Reverse(entry.iterations), entry.start.line, entry.start.column));
+
+		for entry in entries {
+			writeln!(f, "{}-{} {} iterations in {} entries: {}", entry.start, entry.end,
+				entry.iterations, entry.entries, shorten(&entry.code))?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn locations_count_lines_and_columns_from_1() {
+		// "ab\ncd\n\nef"
+		let line_starts = [3, 6, 7];
+		assert_eq!(SourceLocation::of(0, &line_starts), SourceLocation { line: 1, column: 1 });
+		assert_eq!(SourceLocation::of(2, &line_starts), SourceLocation { line: 1, column: 3 });
+		assert_eq!(SourceLocation::of(3, &line_starts), SourceLocation { line: 2, column: 1 });
+		assert_eq!(SourceLocation::of(6, &line_starts), SourceLocation { line: 3, column: 1 });
+		assert_eq!(SourceLocation::of(8, &line_starts), SourceLocation { line: 4, column: 2 });
+		assert_eq!(SourceLocation::of(8, &line_starts).to_string(), "4:2");
+	}
+
+	#[test]
+	fn replaced_loops_take_their_inner_loops_with_them() {
+		let mut report = OptReport::new();
+		report.kept_loop(SourceSpan { start: 2, end: 4 });
+		report.kept_loop(SourceSpan { start: 6, end: 8 });
+		report.kept_loop(SourceSpan { start: 10, end: 12 });
+		report.replaced_loop(SourceSpan { start: 1, end: 9 });
+		report.finish_parse();
+		assert_eq!(report.loop_span(0), Some(SourceSpan { start: 10, end: 12 }));
+		assert_eq!(report.loop_span(1), None);
+	}
+
+	#[test]
+	fn entries_are_printed_in_source_order() {
+		let mut report = OptReport::new();
+		for (position, &ch) in b"[-]\n[>]".iter().enumerate() {
+			report.read_char(ch, position);
+		}
+		let (find_zero, zero) = (report.span(3, 5), report.span(0, 2));
+		report.rejected(find_zero, "counted-loop", "[>]".to_string(), "the loop cell is never changed");
+		report.rewrote(zero, "zero", "[-]".to_string(), "ZERO".to_string());
+		assert_eq!(report.to_string(), "1:1-1:3 zero: [-] => ZERO\n2:1-2:3 counted-loop: [>] rejected: the loop cell is never changed\n");
+	}
+
+	#[test]
+	fn long_windows_are_shortened() {
+		assert_eq!(shorten("[->+<]"), "[->+<]");
+		let long = "+".repeat(MAX_WINDOW_LEN + 1);
+		assert_eq!(shorten(&long), format!("{}...", &long[..MAX_WINDOW_LEN]));
+	}
+}
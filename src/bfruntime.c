@@ -0,0 +1,60 @@
+/* Runtime for executables built with `bf build`. The program itself is the `bf`
+ * function in the object file that this gets linked with. */
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+#ifndef BF_MEMORY_SIZE
+#define BF_MEMORY_SIZE 30000
+#endif
+
+/* Returns 0 when the program finishes, or 1 if it was stopped by `__bf_out_of_bounds`. */
+uint8_t bf(uint8_t *memory, FILE *in, FILE *out);
+
+void __bf_print_output(FILE *out, uint8_t ch, uint64_t times) {
+	while (times-- > 0) {
+		putc(ch, out);
+	}
+}
+
+void __bf_print_bytes(FILE *out, const uint8_t *bytes, uint64_t len) {
+	fwrite(bytes, 1, len, out);
+}
+
+void __bf_flush_output(FILE *out, const uint8_t *bytes, uint64_t len) {
+	fwrite(bytes, 1, len, out);
+	fflush(out);
+}
+
+/* The cell is left alone at the end of input, the same as in the interpreter. */
+void __bf_get_input(FILE *in, uint8_t *cell, uint64_t times) {
+	while (times-- > 0) {
+		int ch = getc(in);
+		if (ch != EOF) {
+			*cell = (uint8_t) ch;
+		}
+	}
+}
+
+/* Called by programs built with `--checked` right before they stop, because the
+ * instruction at `line` and `column` used `cell`, which isn't on the tape. */
+void __bf_out_of_bounds(uint64_t line, uint64_t column, int64_t cell) {
+	fprintf(stderr, "Data pointer out of range at %llu:%llu (cell %lld of %d)\n",
+		(unsigned long long) line, (unsigned long long) column, (long long) cell, BF_MEMORY_SIZE);
+}
+
+int main(void) {
+	uint8_t *memory = calloc(BF_MEMORY_SIZE, 1);
+	if (memory == NULL) {
+		fputs("Failed to allocate the tape.\n", stderr);
+		return 101;
+	}
+
+	uint8_t stopped = bf(memory, stdin, stdout);
+
+	free(memory);
+	if (fflush(stdout) != 0 || stopped) {
+		return 101;
+	}
+	return 0;
+}
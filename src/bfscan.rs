@@ -0,0 +1,204 @@
+use std::cmp;
+use memchr::{memchr, memrchr};
+use ::bf::BFCellValue;
+
+/// Number of cells checked at a time by strided scans that can't be done on whole words.
+const CHUNK: usize = 8;
+
+const LOW_BITS: u64 = 0x7f7f_7f7f_7f7f_7f7f;
+
+/// Returns the index of the first zero cell at or right of `start`, only looking at
+/// every `step`th cell, or None if the scan would run off the end of the tape.
+pub fn find_zero_right(memory: &[BFCellValue], start: usize, step: usize) -> Option<usize> {
+	if start >= memory.len() { return None }
+	match step {
+		1 => memchr(0, &memory[start..]).map(|idx| start + idx),
+		2 | 4 => find_zero_right_words(memory, start, step),
+		_ => find_zero_right_chunked(memory, start, step),
+	}
+}
+
+/// Returns the index of the first zero cell at or left of `start`, only looking at
+/// every `step`th cell, or None if the scan would run off the start of the tape.
+pub fn find_zero_left(memory: &[BFCellValue], start: usize, step: usize) -> Option<usize> {
+	if start >= memory.len() { return None }
+	match step {
+		1 => memrchr(0, &memory[..(start + 1)]),
+		2 | 4 => find_zero_left_words(memory, start, step),
+		_ => find_zero_left_chunked(memory, start, step),
+	}
+}
+
+/// Checks 8 cells at a time by reading them as one word. Only works for steps that
+/// divide 8, so that every word has the cells to check in the same positions.
+fn find_zero_right_words(memory: &[BFCellValue], start: usize, step: usize) -> Option<usize> {
+	let skipped = !stride_mask(step);
+	let mut idx = start;
+	while idx + 8 <= memory.len() {
+		let zeros = zero_bytes(read_word(&memory[idx..(idx + 8)]) | skipped);
+		if zeros != 0 {
+			return Some(idx + (zeros.trailing_zeros() / 8) as usize);
+		}
+		idx += 8;
+	}
+	find_zero_right_simple(memory, idx, step)
+}
+
+fn find_zero_left_words(memory: &[BFCellValue], start: usize, step: usize) -> Option<usize> {
+	// The cells to check end at the last byte of each word instead of starting at the first.
+	let skipped = !(stride_mask(step) << ((step - 1) * 8));
+	let mut end = start + 1;
+	while end >= 8 {
+		let zeros = zero_bytes(read_word(&memory[(end - 8)..end]) | skipped);
+		if zeros != 0 {
+			return Some(end - 1 - (zeros.leading_zeros() / 8) as usize);
+		}
+		end -= 8;
+	}
+	if end == 0 { None } else { find_zero_left_simple(memory, end - 1, step) }
+}
+
+/// Checks `CHUNK` cells at a time with no branches in between, and only looks for
+/// which one was zero once a chunk has one.
+fn find_zero_right_chunked(memory: &[BFCellValue], start: usize, step: usize) -> Option<usize> {
+	let span = step * (CHUNK - 1);
+	let mut idx = start;
+	while idx + span < memory.len() {
+		let mut min = BFCellValue::MAX;
+		for n in 0..CHUNK {
+			min = cmp::min(min, memory[idx + n * step]);
+		}
+		if min == 0 { break }
+		idx += step * CHUNK;
+	}
+	find_zero_right_simple(memory, idx, step)
+}
+
+fn find_zero_left_chunked(memory: &[BFCellValue], start: usize, step: usize) -> Option<usize> {
+	let span = step * (CHUNK - 1);
+	let mut idx = start;
+	while idx >= span + step {
+		let mut min = BFCellValue::MAX;
+		for n in 0..CHUNK {
+			min = cmp::min(min, memory[idx - n * step]);
+		}
+		if min == 0 { break }
+		idx -= step * CHUNK;
+	}
+	find_zero_left_simple(memory, idx, step)
+}
+
+fn find_zero_right_simple(memory: &[BFCellValue], start: usize, step: usize) -> Option<usize> {
+	let mut idx = start;
+	while idx < memory.len() {
+		if memory[idx] == 0 { return Some(idx) }
+		idx += step;
+	}
+	None
+}
+
+fn find_zero_left_simple(memory: &[BFCellValue], start: usize, step: usize) -> Option<usize> {
+	let mut idx = start;
+	loop {
+		if memory[idx] == 0 { return Some(idx) }
+		if idx < step { return None }
+		idx -= step;
+	}
+}
+
+/// Word with 0xff in every `step`th byte, starting from the lowest one.
+fn stride_mask(step: usize) -> u64 {
+	let mut mask = 0;
+	let mut byte = 0;
+	while byte < 8 {
+		mask |= 0xff << (byte * 8);
+		byte += step;
+	}
+	mask
+}
+
+/// Little endian, so that the first cell is the lowest byte.
+#[inline(always)]
+fn read_word(cells: &[BFCellValue]) -> u64 {
+	let mut bytes = [0u8; 8];
+	bytes.copy_from_slice(cells);
+	u64::from_le_bytes(bytes)
+}
+
+/// Sets the high bit of every byte in `word` that is zero, and clears every other bit.
+#[inline(always)]
+fn zero_bytes(word: u64) -> u64 {
+	// The high bit of each byte of `low` is set if any of the low 7 bits of that byte are.
+	let low = (word & LOW_BITS).wrapping_add(LOW_BITS);
+	!(low | word | LOW_BITS)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use ::bf::BF_MEMORY_SIZE;
+
+	/// How close to the ends of the tape scans are checked from and zeros are put.
+	const NEAR_END: usize = 24;
+
+	fn naive_right(memory: &[BFCellValue], start: usize, step: usize) -> Option<usize> {
+		(start..memory.len()).step_by(step).find(|&idx| memory[idx] == 0)
+	}
+
+	fn naive_left(memory: &[BFCellValue], start: usize, step: usize) -> Option<usize> {
+		(0..(start + 1)).rev().step_by(step).find(|&idx| memory[idx] == 0)
+	}
+
+	/// Checks scans in both directions from each of `starts` against the naive ones,
+	/// with every step up to 9 and a zero at each of `zeros` in turn, or none at all.
+	fn assert_scans_match(len: usize, starts: &[usize], zeros: &[usize]) {
+		let mut memory = vec![1 as BFCellValue; len];
+		for zero in zeros.iter().map(|&zero| Some(zero)).chain(Some(None)) {
+			if let Some(zero) = zero { memory[zero] = 0; }
+			for step in 1..10 {
+				for &start in starts.iter() {
+					assert_eq!(find_zero_right(&memory, start, step), naive_right(&memory, start, step),
+						"right from {} by {} with a zero at {:?}", start, step, zero);
+					assert_eq!(find_zero_left(&memory, start, step), naive_left(&memory, start, step),
+						"left from {} by {} with a zero at {:?}", start, step, zero);
+				}
+			}
+			if let Some(zero) = zero { memory[zero] = 1; }
+		}
+	}
+
+	#[test]
+	fn scans_on_short_tapes_match_naive_scans() {
+		// Both a multiple of the word size and not.
+		for &len in [64, 67].iter() {
+			let cells: Vec<usize> = (0..len).collect();
+			assert_scans_match(len, &cells, &cells);
+		}
+	}
+
+	#[test]
+	fn scans_near_the_ends_of_the_tape_match_naive_scans() {
+		let near_ends: Vec<usize> = (0..NEAR_END).chain((BF_MEMORY_SIZE - NEAR_END)..BF_MEMORY_SIZE).collect();
+		assert_scans_match(BF_MEMORY_SIZE, &near_ends, &near_ends);
+	}
+
+	#[test]
+	fn scans_off_of_the_tape_find_nothing() {
+		let memory = vec![0 as BFCellValue; 16];
+		for step in 1..10 {
+			assert_eq!(find_zero_right(&memory, 16, step), None);
+			assert_eq!(find_zero_left(&memory, 16, step), None);
+		}
+	}
+
+	#[test]
+	fn zero_bytes_are_found_in_words() {
+		for byte in 0..8 {
+			let word = !(0xffu64 << (byte * 8));
+			assert_eq!(zero_bytes(word), 0x80 << (byte * 8));
+		}
+		assert_eq!(zero_bytes(0x0101_0101_0101_0101), 0);
+		assert_eq!(zero_bytes(0x8080_8080_8080_8080), 0);
+		assert_eq!(zero_bytes(0), 0x8080_8080_8080_8080);
+	}
+}
@@ -0,0 +1,29 @@
+/* Lets the SIGSEGV handler in `bftape.rs` stop a program that faulted in one of the
+ * tape's guard regions. `sigsetjmp` is called from here since Rust doesn't know that
+ * it returns twice. */
+#include <setjmp.h>
+#include <stddef.h>
+
+/* Where `bf_tape_escape` jumps to, for the innermost `bf_tape_run` on this thread. */
+static __thread sigjmp_buf *current_jump = NULL;
+
+/* Calls `run` with `data`. Returns 0 once it returns, or 1 if `bf_tape_escape` was
+ * called while it was running. */
+int bf_tape_run(void (*run)(void *), void *data) {
+	sigjmp_buf jump;
+	sigjmp_buf *outer = current_jump;
+	if (sigsetjmp(jump, 1) != 0) {
+		current_jump = outer;
+		return 1;
+	}
+
+	current_jump = &jump;
+	run(data);
+	current_jump = outer;
+	return 0;
+}
+
+/* Jumps out of the `run` that `bf_tape_run` is running on this thread. */
+void bf_tape_escape(void) {
+	siglongjmp(*current_jump, 1);
+}
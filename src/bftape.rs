@@ -0,0 +1,259 @@
+/// Whether code compiled by LLVM can use the tape without checking its upper end, because
+/// going past it faults in a guard region and `run_guarded` turns that into an error.
+/// Elsewhere the LLVM backend always checks both ends.
+pub const GUARD_PAGES: bool = cfg!(all(feature = "llvm", target_os = "linux", target_env = "gnu"));
+
+#[cfg(all(feature = "llvm", target_os = "linux", target_env = "gnu"))]
+pub use self::guarded::{Tape, run_guarded};
+
+#[cfg(not(all(feature = "llvm", target_os = "linux", target_env = "gnu")))]
+#[cfg_attr(not(feature = "llvm"), allow(unused_imports))]
+pub use self::unguarded::{Tape, run_guarded};
+
+#[cfg(all(feature = "llvm", target_os = "linux", target_env = "gnu"))]
+mod guarded {
+	use std::{mem, ptr, slice};
+	use std::cell::Cell;
+	use std::ops::{Deref, DerefMut};
+	use std::sync::Once;
+	use libc::{self, c_int, c_void};
+	use ::bf::{BFCellValue, BF_MEMORY_SIZE, RunError};
+
+	/// Size of the inaccessible region on each side of the tape. Going off of the tape
+	/// is caught as long as it's by less than this, which compiled code only does with
+	/// offsets of more than a million cells.
+	const GUARD_SIZE: usize = 1 << 20;
+
+	extern "C" {
+		// In `bftape.c`.
+		fn bf_tape_run(run: extern "C" fn(*mut c_void), data: *mut c_void) -> c_int;
+		fn bf_tape_escape() -> !;
+	}
+
+	/// The start of `siginfo_t` for SIGSEGV, which the libc crate doesn't have fields for.
+	#[repr(C)]
+	struct FaultInfo {
+		signo: c_int,
+		errno: c_int,
+		code: c_int,
+		address: *mut c_void,
+	}
+
+	/// Addresses that fault when a program running under `run_guarded` on this thread
+	/// goes off of its tape, as [start, end).
+	#[derive(Clone, Copy)]
+	struct Guarded {
+		tape_start: usize,
+		guard_start: usize,
+		guard_end: usize,
+	}
+
+	thread_local! {
+		static GUARDED: Cell<Option<Guarded>> = const { Cell::new(None) };
+
+		/// Cell that the last guard region fault on this thread was for.
+		static FAULT: Cell<Option<isize>> = const { Cell::new(None) };
+	}
+
+	static INSTALL_HANDLER: Once = Once::new();
+	static mut PREVIOUS_HANDLER: Option<libc::sigaction> = None;
+
+	/// The tape, in its own mapping with `PROT_NONE` regions on both sides so that
+	/// compiled code that goes off of it faults instead of touching other memory.
+	///
+	/// The mapping is a whole number of pages and the last cell is right before the
+	/// upper guard region, so the rest of the first page, before the first cell, can
+	/// be used without faulting. Compiled code checks that it doesn't go below the first
+	/// cell itself, and never writes to those cells, so they stay 0.
+	pub struct Tape {
+		mapping: *mut c_void,
+		mapping_len: usize,
+		cells: *mut BFCellValue,
+	}
+
+	impl Tape {
+		/// A tape of `BF_MEMORY_SIZE` zero cells.
+		pub fn new() -> Tape {
+			let tape_len = round_to_pages(BF_MEMORY_SIZE);
+			let mapping_len = GUARD_SIZE + tape_len + GUARD_SIZE;
+			unsafe {
+				let mapping = libc::mmap(ptr::null_mut(), mapping_len, libc::PROT_NONE,
+					libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0);
+				if mapping == libc::MAP_FAILED {
+					panic!("Failed to map the tape.");
+				}
+				let pages = (mapping as *mut BFCellValue).add(GUARD_SIZE);
+				if libc::mprotect(pages as *mut c_void, tape_len, libc::PROT_READ | libc::PROT_WRITE) != 0 {
+					libc::munmap(mapping, mapping_len);
+					panic!("Failed to make the tape writable.");
+				}
+				let cells = pages.add(tape_len - BF_MEMORY_SIZE);
+				Tape { mapping, mapping_len, cells }
+			}
+		}
+
+		/// Pointer to the first cell, for compiled code.
+		pub fn cells(&self) -> *mut BFCellValue {
+			self.cells
+		}
+	}
+
+	impl Clone for Tape {
+		fn clone(&self) -> Tape {
+			let mut tape = Tape::new();
+			tape.copy_from_slice(self);
+			tape
+		}
+	}
+
+	impl Deref for Tape {
+		type Target = [BFCellValue];
+
+		fn deref(&self) -> &[BFCellValue] {
+			unsafe { slice::from_raw_parts(self.cells, BF_MEMORY_SIZE) }
+		}
+	}
+
+	impl DerefMut for Tape {
+		fn deref_mut(&mut self) -> &mut [BFCellValue] {
+			unsafe { slice::from_raw_parts_mut(self.cells, BF_MEMORY_SIZE) }
+		}
+	}
+
+	impl Drop for Tape {
+		fn drop(&mut self) {
+			unsafe { libc::munmap(self.mapping, self.mapping_len); }
+		}
+	}
+
+	/// Runs compiled code on the `Tape` whose first cell is at `cells`, returning
+	/// `RunError::PointerOutOfRange` if it faults in one of the tape's guard regions.
+	///
+	/// The code is stopped by jumping out of it from the signal handler, so nothing
+	/// that `run` calls can have destructors that need to run.
+	pub fn run_guarded<F: FnOnce()>(cells: *mut BFCellValue, run: F) -> Result<(), RunError> {
+		INSTALL_HANDLER.call_once(|| unsafe { install_handler() });
+
+		let tape_start = cells as usize;
+		let tape_end = tape_start + BF_MEMORY_SIZE;
+		let guarded = Guarded {
+			tape_start,
+			guard_start: tape_end - round_to_pages(BF_MEMORY_SIZE) - GUARD_SIZE,
+			guard_end: tape_end + GUARD_SIZE,
+		};
+		let outer = GUARDED.with(|current| current.replace(Some(guarded)));
+
+		let mut run = Some(run);
+		let faulted = unsafe { bf_tape_run(call_once::<F>, &mut run as *mut Option<F> as *mut c_void) } != 0;
+
+		GUARDED.with(|current| current.set(outer));
+		match FAULT.with(|fault| fault.take()) {
+			Some(cell) if faulted => Err(RunError::PointerOutOfRange { cell, location: None }),
+			_ => Ok(()),
+		}
+	}
+
+	extern "C" fn call_once<F: FnOnce()>(run: *mut c_void) {
+		if let Some(run) = unsafe { (*(run as *mut Option<F>)).take() } {
+			run();
+		}
+	}
+
+	fn round_to_pages(len: usize) -> usize {
+		let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+		len.div_ceil(page_size) * page_size
+	}
+
+	unsafe fn install_handler() {
+		let mut action: libc::sigaction = mem::zeroed();
+		action.sa_sigaction = handle_fault as *const () as usize;
+		action.sa_flags = libc::SA_SIGINFO;
+		libc::sigemptyset(&mut action.sa_mask);
+
+		let mut previous: libc::sigaction = mem::zeroed();
+		if libc::sigaction(libc::SIGSEGV, &action, &mut previous) != 0 {
+			panic!("Failed to install the SIGSEGV handler.");
+		}
+		PREVIOUS_HANDLER = Some(previous);
+	}
+
+	extern "C" fn handle_fault(signal: c_int, info: *mut libc::siginfo_t, context: *mut c_void) {
+		unsafe {
+			let address = (*(info as *const FaultInfo)).address as usize;
+			if let Some(guarded) = GUARDED.with(|current| current.get()) {
+				// Nothing in the mapping other than the guard regions can fault.
+				if address >= guarded.guard_start && address < guarded.guard_end {
+					let cell = (address as isize).wrapping_sub(guarded.tape_start as isize);
+					FAULT.with(|fault| fault.set(Some(cell)));
+					bf_tape_escape();
+				}
+			}
+
+			// Anything else is left to whatever handled it before, like Rust's stack
+			// overflow handler, or crashes the same way it would have without this.
+			let previous = (*ptr::addr_of!(PREVIOUS_HANDLER)).unwrap_or_else(|| mem::zeroed());
+			match previous.sa_sigaction {
+				libc::SIG_DFL | libc::SIG_IGN => {
+					// Returning faults again, which kills the process this time.
+					libc::signal(libc::SIGSEGV, libc::SIG_DFL);
+				},
+				handler if previous.sa_flags & libc::SA_SIGINFO != 0 => {
+					let handler: extern "C" fn(c_int, *mut libc::siginfo_t, *mut c_void) = mem::transmute(handler);
+					handler(signal, info, context);
+				},
+				handler => {
+					let handler: extern "C" fn(c_int) = mem::transmute(handler);
+					handler(signal);
+				},
+			}
+		}
+	}
+}
+
+#[cfg(not(all(feature = "llvm", target_os = "linux", target_env = "gnu")))]
+mod unguarded {
+	use std::ops::{Deref, DerefMut};
+	use ::bf::{BFCellValue, BF_MEMORY_SIZE, RunError};
+
+	/// The tape, as plain memory. Without guard regions anything that runs on it has
+	/// to check the cells it uses, which the interpreter always does and which the LLVM
+	/// backend does when `GUARD_PAGES` is false.
+	#[derive(Clone)]
+	pub struct Tape {
+		cells: Box<[BFCellValue]>,
+	}
+
+	impl Tape {
+		/// A tape of `BF_MEMORY_SIZE` zero cells.
+		pub fn new() -> Tape {
+			Tape { cells: vec![0; BF_MEMORY_SIZE].into_boxed_slice() }
+		}
+
+		/// Pointer to the first cell, for compiled code.
+		#[cfg_attr(not(feature = "llvm"), allow(dead_code))]
+		pub fn cells(&self) -> *mut BFCellValue {
+			self.cells.as_ptr() as *mut BFCellValue
+		}
+	}
+
+	impl Deref for Tape {
+		type Target = [BFCellValue];
+
+		fn deref(&self) -> &[BFCellValue] {
+			&self.cells
+		}
+	}
+
+	impl DerefMut for Tape {
+		fn deref_mut(&mut self) -> &mut [BFCellValue] {
+			&mut self.cells
+		}
+	}
+
+	/// Runs compiled code on the tape that starts at `cells`. It has to have bounds checks.
+	#[cfg_attr(not(feature = "llvm"), allow(dead_code))]
+	pub fn run_guarded<F: FnOnce()>(_: *mut BFCellValue, run: F) -> Result<(), RunError> {
+		run();
+		Ok(())
+	}
+}
@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::io::{StdinLock, StdoutLock};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use ::bf::{BFCellValue, BFProgram, LoopTier, RunError};
+use ::bfllvm::BFLLVMProgram;
+use ::bfreport::SourceLocation;
+
+/// Compiles loops with LLVM once they're hot, and runs them on the interpreter's tape.
+pub struct LLVMLoopTier {
+	threshold: usize,
+	opt_level: u32,
+	checked: bool,
+	debug_source: Option<PathBuf>,
+
+	/// Compiled loops, by the index of their start.
+	loops: HashMap<usize, BFLLVMProgram>,
+
+	/// Where each loop that was compiled starts in the source, in the order they were compiled.
+	pub compiled: Vec<SourceLocation>,
+
+	/// Loops that LLVM couldn't compile, with why.
+	pub failed: Vec<(SourceLocation, String)>,
+
+	/// Total time spent compiling loops.
+	pub compile_dur: Duration,
+}
+
+impl LLVMLoopTier {
+	/// Compiles loops at `opt_level`, with bounds checks if `checked` and debug info
+	/// pointing at `debug_source` if there is one, the same as whole programs.
+	pub fn new(threshold: usize, opt_level: u32, checked: bool, debug_source: Option<PathBuf>) -> LLVMLoopTier {
+		LLVMLoopTier {
+			threshold,
+			opt_level,
+			checked,
+			debug_source,
+			loops: HashMap::new(),
+			compiled: Vec::new(),
+			failed: Vec::new(),
+			compile_dur: Duration::from_secs(0),
+		}
+	}
+
+	fn compile_loop(&self, program: &BFProgram, start: usize) -> Result<BFLLVMProgram, String> {
+		let mut compiled = BFLLVMProgram::for_loop();
+		if self.checked {
+			compiled.enable_bounds_checks();
+		}
+		if let Some(ref source) = self.debug_source {
+			compiled.enable_debug_info(source);
+		}
+		compiled.compile_loop(program, start)?;
+		compiled.optimize(self.opt_level)?;
+		compiled.finalize()?;
+		Ok(compiled)
+	}
+}
+
+impl LoopTier for LLVMLoopTier {
+	fn threshold(&self) -> usize {
+		self.threshold
+	}
+
+	fn compile(&mut self, program: &BFProgram, start: usize) -> bool {
+		let started = Instant::now();
+		let result = self.compile_loop(program, start);
+		self.compile_dur += started.elapsed();
+
+		let location = program.source_location(start);
+		match result {
+			Ok(compiled) => {
+				self.loops.insert(start, compiled);
+				self.compiled.push(location);
+				true
+			},
+			Err(err) => {
+				self.failed.push((location, err));
+				false
+			},
+		}
+	}
+
+	fn run(&mut self, start: usize, cells: *mut BFCellValue, data_ptr: usize, stdin: &mut StdinLock, stdout: &mut StdoutLock) -> Result<usize, RunError> {
+		self.loops[&start].run_loop(cells, data_ptr, stdin, stdout)
+	}
+}
@@ -1,10 +1,31 @@
+
 extern crate clap;
+extern crate libc;
+extern crate memchr;
+#[cfg(feature = "llvm")]
 extern crate llvm_sys as llvm;
 
 mod bf;
+#[cfg(feature = "llvm")]
+mod bfbuild;
+#[cfg(feature = "llvm")]
+mod bfcache;
+#[cfg(feature = "llvm")]
+mod bfdebuginfo;
+mod bfidiom;
+#[cfg(feature = "llvm")]
 mod bfllvm;
+mod bfloop;
+mod bfopt;
+#[cfg(feature = "llvm")]
+mod bforc;
+mod bfreport;
+mod bfscan;
+mod bftape;
+#[cfg(feature = "llvm")]
+mod bftiered;
 
-use clap::{Arg, App};
+use clap::{Arg, App, AppSettings, ArgMatches, SubCommand};
 use std::fs::File;
 use std::io::prelude::*;
 use std::process::exit;
@@ -25,7 +46,7 @@ macro_rules! time_op {
 }
 
 fn open_file(filename: &str) -> File {
-    match File::open(&filename) {
+    match File::open(filename) {
         Ok(f) => f,
         Err(err) => {
             println_err!("Failed to open file: {}", err);
@@ -34,36 +55,47 @@ fn open_file(filename: &str) -> File {
     }
 }
 
-fn as_millis(d: std::time::Duration) -> f64 {
-	(d.as_secs() as f64) * 1000.0f64 + (d.subsec_nanos() as f64) / 1000000f64
-}
+/// Reads a file of idioms and makes sure that each one does the same thing as its snippet.
+fn load_idioms(filename: &str) -> Vec<bfidiom::Idiom> {
+    let mut text = String::new();
+    if let Err(err) = open_file(filename).read_to_string(&mut text) {
+        println_err!("Failed to read idioms: {}", err);
+        exit(101);
+    }
 
-fn run_bf_program_llvm<R: Read+Sized>(input: R, show_debug: bool, show_timing: bool) {
-    println!("Using LLVM");
-    let mut program = bfllvm::BFLLVMProgram::new();
-    let compile_dur = time_op! { program.compile(input) };
+    let idioms = match bfidiom::Idiom::parse_all(&text) {
+        Ok(idioms) => idioms,
+        Err(err) => {
+            println_err!("Invalid idiom file: {}", err);
+            exit(101);
+        }
+    };
 
-    if show_debug {
-        println!("LLVM IR:");
-        println!("==============");
-        program.dump_llvm_ir();
-        println!("==============");
+    for idiom in idioms.iter() {
+        if let Err(err) = bf::BFProgram::verify_idiom(idiom) {
+            println_err!("Invalid idiom: {}", err);
+            exit(101);
+        }
     }
+    idioms
+}
 
-    if show_timing {
-        println!("Compiled In: {:.2}ms", as_millis(compile_dur));
-        println!("Running...");
-        println!();
-        let dur = time_op! { program.run() };
-        println!();
-        println!("Finished Running In: {:.2}ms", as_millis(dur));
-    } else {
-        program.run();
-    }
+fn as_millis(d: std::time::Duration) -> f64 {
+	(d.as_secs() as f64) * 1000.0f64 + (d.subsec_nanos() as f64) / 1000000f64
 }
 
-fn run_bf_program<R: Read+Sized>(input: R, show_debug: bool, show_timing: bool) {
+/// Parses and optimizes a program. Both backends run the same instructions.
+fn compile_bf_program<R: Read+Sized>(input: R, show_debug: bool, partial_eval: Option<usize>, idioms: &[bfidiom::Idiom], opt_report: bool) -> (bf::BFProgram, std::time::Duration) {
     let mut program = bf::BFProgram::new();
+    if opt_report {
+        program.enable_opt_report();
+    }
+    for idiom in idioms.iter() {
+        program.add_idiom(idiom);
+    }
+    if let Some(step_budget) = partial_eval {
+        program.enable_partial_eval(step_budget);
+    }
     let compile_dur = time_op! { program.compile(input) };
 
     let instr_count = program.get_instr_count();
@@ -91,20 +123,325 @@ fn run_bf_program<R: Read+Sized>(input: R, show_debug: bool, show_timing: bool)
             if program.stats.loop_count > 0 {
                 (program.stats.optimized_loop_count as f32 / program.stats.loop_count as f32) * 100.0
             } else {100.0f32});
+
+        println!("Idioms: {} snippets replaced", program.stats.idiom_count);
+
+        if partial_eval.is_some() {
+            println!("Partial Evaluation: {} steps run at compile time", program.stats.partial_eval_steps);
+        }
+    }
+
+    if let Some(report) = program.get_opt_report() {
+        println!("Optimization Report:");
+        println!("==============");
+        print!("{}", report);
+        println!("==============");
     }
 
+    (program, compile_dur)
+}
+
+#[cfg(feature = "llvm")]
+fn exit_on_llvm_error(result: Result<(), String>) {
+    if let Err(err) = result {
+        println_err!("LLVM error: {}", err);
+        exit(101);
+    }
+}
+
+fn exit_on_run_error(result: Result<(), bf::RunError>) {
+    if let Err(err) = result {
+        println_err!("{}", err);
+        exit(101);
+    }
+}
+
+/// How a program is compiled with LLVM.
+#[cfg_attr(not(feature = "llvm"), allow(dead_code))]
+struct LLVMOptions<'a> {
+    opt_level: u32,
+    checked: bool,
+    /// Whether the compiled code counts how often each loop is entered and iterated.
+    profile: bool,
+    /// The source file, if the program gets debug info.
+    debug_source: Option<&'a str>,
+    /// Where compiled code is kept for the next time the same program is run with -l.
+    cache_dir: Option<&'a str>,
+}
+
+/// A program to be compiled by LLVM, with bounds checks and debug info if `options` asks for them.
+#[cfg(feature = "llvm")]
+fn new_llvm_program(options: &LLVMOptions) -> bfllvm::BFLLVMProgram {
+    let mut program = bfllvm::BFLLVMProgram::new();
+    if options.checked {
+        program.enable_bounds_checks();
+    }
+    if let Some(source) = options.debug_source {
+        program.enable_debug_info(std::path::Path::new(source));
+    }
+    if options.profile {
+        program.enable_profiling();
+    }
+    program
+}
+
+/// Builds and optimizes the IR for a program, printing it if `show_debug`.
+#[cfg(feature = "llvm")]
+fn compile_llvm_program(program: &mut bfllvm::BFLLVMProgram, bf_program: &bf::BFProgram, show_debug: bool, opt_level: u32) -> std::time::Duration {
+    let mut compiled;
+    let mut compile_dur = time_op! { compiled = program.compile(bf_program) };
+    exit_on_llvm_error(compiled);
+
+    if show_debug {
+        println!("LLVM IR:");
+        println!("==============");
+        program.dump_llvm_ir();
+        println!("==============");
+    }
+
+    compile_dur += time_op! { compiled = program.optimize(opt_level) };
+    exit_on_llvm_error(compiled);
+
+    if show_debug && opt_level > 0 {
+        println!("Optimized LLVM IR (-O{}):", opt_level);
+        println!("==============");
+        program.dump_llvm_ir();
+        println!("==============");
+    }
+    compile_dur
+}
+
+/// Gets a program ready to run from the code cached for it, or compiles it and caches
+/// the code if there isn't any.
+#[cfg(feature = "llvm")]
+fn finalize_llvm_program_cached(bf_program: &bf::BFProgram, show_debug: bool, options: &LLVMOptions, cache_dir: &str) -> (bfllvm::BFLLVMProgram, std::time::Duration) {
+    let cache = bfcache::JITCache::new(std::path::Path::new(cache_dir));
+    let key = cache.key(bf_program, options.opt_level, options.checked, options.profile, options.debug_source.map(std::path::Path::new));
+
+    if let Some(object) = cache.load(&key) {
+        let mut program = new_llvm_program(options);
+        let loaded;
+        let load_dur = time_op! { loaded = program.finalize_from_object(bf_program, &object) };
+        // An object that can't be loaded is compiled again and replaced.
+        if loaded.is_ok() {
+            if show_debug {
+                println!("Loaded compiled code from the cache ({}).", key);
+            }
+            return (program, load_dur);
+        }
+    }
+
+    let mut program = new_llvm_program(options);
+    let mut compile_dur = compile_llvm_program(&mut program, bf_program, show_debug, options.opt_level);
+    let object;
+    compile_dur += time_op! { object = program.emit_object() };
+    let object = match object {
+        Ok(object) => object,
+        Err(err) => {
+            println_err!("LLVM error: {}", err);
+            exit(101);
+        }
+    };
+    if let Err(err) = cache.store(&key, &object) {
+        println_err!("Failed to cache compiled code: {}", err);
+    }
+
+    let finalized;
+    compile_dur += time_op! { finalized = program.finalize_from_object(bf_program, &object) };
+    exit_on_llvm_error(finalized);
+    (program, compile_dur)
+}
+
+#[cfg(feature = "llvm")]
+fn run_bf_program_llvm(bf_program: &bf::BFProgram, parse_dur: std::time::Duration, show_debug: bool, show_timing: bool, options: &LLVMOptions) {
+    println!("Using LLVM");
+    let (mut program, compile_dur) = match options.cache_dir {
+        Some(cache_dir) => finalize_llvm_program_cached(bf_program, show_debug, options, cache_dir),
+        None => {
+            let mut program = new_llvm_program(options);
+            let mut compile_dur = compile_llvm_program(&mut program, bf_program, show_debug, options.opt_level);
+            let finalized;
+            compile_dur += time_op! { finalized = program.finalize() };
+            exit_on_llvm_error(finalized);
+            (program, compile_dur)
+        },
+    };
+    let compile_dur = parse_dur + compile_dur;
+
+    if show_timing {
+        println!("Compiled In: {:.2}ms", as_millis(compile_dur));
+        println!("Running...");
+        println!();
+        let result;
+        let dur = time_op! { result = program.run() };
+        print_loop_profile(&program, bf_program, options);
+        exit_on_run_error(result);
+        println!();
+        println!("Finished Running In: {:.2}ms", as_millis(dur));
+    } else {
+        let result = program.run();
+        print_loop_profile(&program, bf_program, options);
+        exit_on_run_error(result);
+    }
+}
+
+/// Prints how often each loop ran, if the program was compiled to count it.
+#[cfg(feature = "llvm")]
+fn print_loop_profile(program: &bfllvm::BFLLVMProgram, bf_program: &bf::BFProgram, options: &LLVMOptions) {
+    if options.profile {
+        println!();
+        println!("Loop Profile:");
+        println!("==============");
+        print!("{}", bf_program.loop_profile(&program.profile_counts(bf_program)));
+        println!("==============");
+    }
+}
+
+fn run_bf_program(mut program: bf::BFProgram, compile_dur: std::time::Duration, show_timing: bool) {
     if show_timing {
         println!("Compiled In: {:.2}ms", as_millis(compile_dur));
         println!("Running...");
         println!();
-        let dur = time_op! { program.run() };
+        let result;
+        let dur = time_op! { result = program.run() };
+        exit_on_run_error(result);
         println!();
         println!("Finished Running In: {:.2}ms", as_millis(dur));
     } else {
-        program.run();
+        exit_on_run_error(program.run());
+    }
+}
+
+/// Runs a program with the interpreter, compiling each loop with LLVM once it has run
+/// `threshold` times.
+#[cfg(feature = "llvm")]
+fn run_bf_program_tiered(mut program: bf::BFProgram, compile_dur: std::time::Duration, show_debug: bool, show_timing: bool, options: &LLVMOptions, threshold: usize) {
+    println!("Using LLVM for hot loops");
+    let mut tier = bftiered::LLVMLoopTier::new(threshold, options.opt_level, options.checked,
+        options.debug_source.map(std::path::PathBuf::from));
+
+    if show_timing {
+        println!("Compiled In: {:.2}ms", as_millis(compile_dur));
+        println!("Running...");
+        println!();
+    }
+    let result;
+    let dur = time_op! { result = program.run_tiered(&mut tier) };
+
+    if show_debug {
+        println!();
+        println!("Loops Compiled by LLVM: {} ({:.2}ms)", tier.compiled.len(), as_millis(tier.compile_dur));
+        for location in tier.compiled.iter() {
+            println!("  loop at {}", location);
+        }
+        for (location, err) in tier.failed.iter() {
+            println!("  loop at {} left to the interpreter: {}", location, err);
+        }
+    }
+    exit_on_run_error(result);
+    if show_timing {
+        println!();
+        println!("Finished Running In: {:.2}ms (including {:.2}ms compiling loops)", as_millis(dur), as_millis(tier.compile_dur));
+    }
+}
+
+/// Writes the program to files named after `input` in the current directory instead of running it.
+#[cfg(feature = "llvm")]
+fn emit_bf_program(bf_program: &bf::BFProgram, input: &str, kinds: &[&str], options: &LLVMOptions) {
+    // The code is for linking with `bfruntime.c`, whose tape doesn't have guard pages.
+    let options = &LLVMOptions { checked: true, ..*options };
+    let mut program = new_llvm_program(options);
+    let compiled = program.compile(bf_program)
+        .and_then(|_| program.optimize(options.opt_level));
+    exit_on_llvm_error(compiled);
+
+    let stem = std::path::Path::new(input).file_stem().unwrap_or(input.as_ref());
+    for name in kinds.iter() {
+        // clap already made sure that it's one of the possible values.
+        let kind = bfllvm::EmitKind::parse(name).unwrap();
+        let path = std::path::Path::new(stem).with_extension(kind.extension());
+        if let Err(err) = program.emit(kind, &path) {
+            println_err!("Failed to write {}: {}", path.display(), err);
+            exit(101);
+        }
     }
 }
 
+/// Compiles a program ahead of time into an executable that doesn't need the interpreter.
+#[cfg(feature = "llvm")]
+fn build_bf_program(matches: &ArgMatches) {
+    let input = matches.value_of("INPUT").unwrap();
+    let output = matches.value_of("output").unwrap();
+    let options = LLVMOptions {
+        opt_level: matches.value_of("opt-level").unwrap().parse::<u32>().unwrap(),
+        // The runtime's tape doesn't have guard pages, so bounds checks are the only way
+        // to catch the program going off of it.
+        checked: true,
+        profile: false,
+        debug_source: if matches.is_present("debug-info") { Some(input) } else { None },
+        cache_dir: None,
+    };
+    let partial_eval = if matches.is_present("partial-eval") { Some(bf::DEFAULT_PARTIAL_EVAL_STEPS) } else { None };
+
+    let (bf_program, _) = compile_bf_program(open_file(input), false, partial_eval, &[], false);
+    let mut program = new_llvm_program(&options);
+    let compiled = program.compile(&bf_program)
+        .and_then(|_| program.optimize(options.opt_level));
+    exit_on_llvm_error(compiled);
+
+    if let Err(err) = bfbuild::build_executable(&program, std::path::Path::new(output)) {
+        println_err!("Failed to build {}: {}", output, err);
+        exit(101);
+    }
+}
+
+#[cfg(not(feature = "llvm"))]
+fn run_bf_program_llvm(_: &bf::BFProgram, _: std::time::Duration, _: bool, _: bool, _: &LLVMOptions) {
+    exit_without_llvm("-l");
+}
+
+#[cfg(not(feature = "llvm"))]
+fn run_bf_program_tiered(_: bf::BFProgram, _: std::time::Duration, _: bool, _: bool, _: &LLVMOptions, _: usize) {
+    exit_without_llvm("--tiered");
+}
+
+#[cfg(not(feature = "llvm"))]
+fn emit_bf_program(_: &bf::BFProgram, _: &str, _: &[&str], _: &LLVMOptions) {
+    exit_without_llvm("--emit");
+}
+
+#[cfg(not(feature = "llvm"))]
+fn build_bf_program(_: &ArgMatches) {
+    exit_without_llvm("bf build");
+}
+
+#[cfg(not(feature = "llvm"))]
+fn exit_without_llvm(option: &str) {
+    println_err!("{} needs the LLVM backend, which isn't in this build of bf. Build it with `cargo build --features llvm` to use it.", option);
+    exit(101);
+}
+
+fn checked_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("checked")
+        .long("checked")
+        .help("Make the LLVM compiled program check that the cells it uses are on the tape, and stop with where in the source it went off of it.")
+}
+
+fn debug_info_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("debug-info")
+        .short("g")
+        .help("Add debug info to the LLVM compiled program that maps it to lines and columns of the source, for debuggers and profilers.")
+}
+
+fn opt_level_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("opt-level")
+        .short("O")
+        .takes_value(true)
+        .value_name("LEVEL")
+        .possible_values(&["0", "1", "2", "3"])
+        .default_value("2")
+}
+
 fn main() {
     let matches = App::new("BF Assembler")
         .version("1.0")
@@ -119,21 +456,141 @@ fn main() {
         .arg(Arg::with_name("llvm")
             .short("l")
             .help("Use LLVM."))
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .arg(opt_level_arg()
+            .help("LLVM optimization level used with -l and --tiered."))
+        .arg(checked_arg())
+        .arg(debug_info_arg())
+        .arg(Arg::with_name("partial-eval")
+            .short("p")
+            .long("partial-eval")
+            .help("Run the program at compile time until it first reads input."))
+        .arg(Arg::with_name("eval-budget")
+            .long("eval-budget")
+            .takes_value(true)
+            .value_name("STEPS")
+            .requires("partial-eval")
+            .help("Maximum number of instructions to run at compile time."))
+        .arg(Arg::with_name("idioms")
+            .long("idioms")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Also replaces the snippets in FILE with idioms. Each line is an op followed by its snippet, like `copy(1,2) [->+>+<<]>>[-<<+>>]<<`."))
+        .arg(Arg::with_name("tiered")
+            .long("tiered")
+            .conflicts_with_all(&["llvm", "emit", "jit-cache"])
+            .help("Start the program in the interpreter and compile each loop with LLVM once it has run enough times."))
+        .arg(Arg::with_name("tier-threshold")
+            .long("tier-threshold")
+            .takes_value(true)
+            .value_name("COUNT")
+            .requires("tiered")
+            .help("Number of times a loop has to run before --tiered compiles it. Defaults to 10000."))
+        .arg(Arg::with_name("jit-cache")
+            .long("jit-cache")
+            .takes_value(true)
+            .value_name("DIR")
+            .help("Keep the code that -l compiles in DIR, and load it from there instead of compiling the same program with the same options again."))
+        .arg(Arg::with_name("profile")
+            .long("profile")
+            .requires("llvm")
+            .help("Count how many times each loop is entered and iterated in the code that -l compiles, and print the counts after the program finishes."))
+        .arg(Arg::with_name("opt-report")
+            .long("opt-report")
+            .help("Print which optimizations were done to which parts of the program, and why loops weren't optimized."))
+        .arg(Arg::with_name("emit")
+            .long("emit")
+            .takes_value(true)
+            .value_name("KINDS")
+            .use_delimiter(true)
+            .possible_values(&["llvm-ir", "llvm-bc", "asm", "obj"])
+            .help("Compile with LLVM and write each kind of file, named after the input, instead of running the program."))
         .arg(Arg::with_name("INPUT")
             .help("Sets the input file to use")
             .required(true)
             .index(1))
+        .subcommand(SubCommand::with_name("build")
+            .about("Compiles a BF program into a native executable with LLVM.")
+            .arg(Arg::with_name("output")
+                .short("o")
+                .takes_value(true)
+                .value_name("FILE")
+                .required(true)
+                .help("Where to write the executable."))
+            .arg(opt_level_arg()
+                .help("LLVM optimization level."))
+            .arg(checked_arg()
+                .help("Always on, since the executable's tape doesn't have guard pages."))
+            .arg(debug_info_arg())
+            .arg(Arg::with_name("partial-eval")
+                .short("p")
+                .long("partial-eval")
+                .help("Run the program at compile time until it first reads input."))
+            .arg(Arg::with_name("INPUT")
+                .help("The BF program to compile")
+                .required(true)
+                .index(1)))
         .get_matches();
+
+    if let Some(build_matches) = matches.subcommand_matches("build") {
+        build_bf_program(build_matches);
+        return;
+    }
     
     let input = matches.value_of("INPUT").unwrap();
     let show_debug = matches.is_present("debug");
     let show_timing = matches.is_present("time");
     let llvm = matches.is_present("llvm");
+    let opt_report = matches.is_present("opt-report");
+    let llvm_options = LLVMOptions {
+        // clap already made sure that it's one of the possible values.
+        opt_level: matches.value_of("opt-level").unwrap().parse::<u32>().unwrap(),
+        // Without guard pages around the tape, bounds checks are the only way to catch
+        // compiled code going off of it.
+        checked: matches.is_present("checked") || !bftape::GUARD_PAGES,
+        profile: matches.is_present("profile"),
+        debug_source: if matches.is_present("debug-info") { Some(input) } else { None },
+        cache_dir: matches.value_of("jit-cache"),
+    };
+    let partial_eval = if matches.is_present("partial-eval") {
+        match matches.value_of("eval-budget").map(|b| b.parse::<usize>()) {
+            None => Some(bf::DEFAULT_PARTIAL_EVAL_STEPS),
+            Some(Ok(budget)) => Some(budget),
+            Some(Err(err)) => {
+                println_err!("Invalid eval budget: {}", err);
+                exit(101);
+            }
+        }
+    } else {
+        None
+    };
+
+    let tier_threshold = match matches.value_of("tier-threshold").map(|count| count.parse::<usize>()) {
+        None => bf::DEFAULT_TIER_THRESHOLD,
+        Some(Ok(count)) if count > 0 => count,
+        Some(Ok(_)) => {
+            println_err!("Invalid tier threshold: it has to be at least 1");
+            exit(101);
+        },
+        Some(Err(err)) => {
+            println_err!("Invalid tier threshold: {}", err);
+            exit(101);
+        }
+    };
+
+    let idioms = match matches.value_of("idioms") {
+        Some(filename) => load_idioms(filename),
+        None => Vec::new(),
+    };
 
-    if llvm {
-        run_bf_program_llvm(open_file(input), show_debug, show_timing);
+    let (program, compile_dur) = compile_bf_program(open_file(input), show_debug, partial_eval, &idioms, opt_report);
+    if let Some(kinds) = matches.values_of("emit") {
+        emit_bf_program(&program, input, &kinds.collect::<Vec<_>>(), &llvm_options);
+    } else if llvm {
+        run_bf_program_llvm(&program, compile_dur, show_debug, show_timing, &llvm_options);
+    } else if matches.is_present("tiered") {
+        run_bf_program_tiered(program, compile_dur, show_debug, show_timing, &llvm_options, tier_threshold);
     } else {
-        run_bf_program(open_file(input), show_debug, show_timing);
+        run_bf_program(program, compile_dur, show_timing);
     }
-    return;
 }
\ No newline at end of file
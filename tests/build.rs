@@ -0,0 +1,64 @@
+//! Builds executables with `bf build`, and checks that they print the same thing as the
+//! interpreter and fail the same way when they go off of the tape.
+#![cfg(feature = "llvm")]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// Programs that print something and then go off of the upper or the lower end of the tape.
+const OFF_TAPE_PROGRAMS: &[&str] = &[
+	"+++++++[>+++++++<-]>.[>+]",
+	"+++++++[>+++++++<-]>.<<<+.",
+];
+
+/// A directory for one test to build in, since they run at the same time.
+fn build_dir(name: &str) -> PathBuf {
+	let dir = std::env::temp_dir().join(format!("bf-build-test-{}-{}", std::process::id(), name));
+	fs::create_dir_all(&dir).expect("Failed to create the build directory");
+	dir
+}
+
+fn run_bf(args: &[&str], program: &Path) -> Output {
+	Command::new(env!("CARGO_BIN_EXE_bf"))
+		.args(args)
+		.arg(program)
+		.output()
+		.expect("Failed to run bf")
+}
+
+/// Builds `program` into an executable in `dir` and runs it.
+fn build_and_run(program: &Path, dir: &Path) -> Output {
+	let executable = dir.join("program");
+	let built = run_bf(&["build", "-o", executable.to_str().unwrap()], program);
+	assert!(built.status.success(), "bf build {} failed: {}", program.display(), String::from_utf8_lossy(&built.stderr));
+	Command::new(&executable).output().expect("Failed to run the executable")
+}
+
+#[test]
+fn built_program_prints_like_interpreter() {
+	let dir = build_dir("hello");
+	let program = Path::new(env!("CARGO_MANIFEST_DIR")).join("bf-test").join("helloworld.bf");
+	let expected = run_bf(&[], &program);
+	let output = build_and_run(&program, &dir);
+	let _ = fs::remove_dir_all(&dir);
+
+	assert!(output.status.success(), "The executable failed: {}", output.status);
+	assert_eq!(String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&expected.stdout));
+}
+
+#[test]
+fn built_program_fails_like_interpreter() {
+	let dir = build_dir("off-tape");
+	for source in OFF_TAPE_PROGRAMS.iter() {
+		let program = dir.join("off-tape.bf");
+		fs::write(&program, source).expect("Failed to write the program");
+		let expected = run_bf(&[], &program);
+		let output = build_and_run(&program, &dir);
+
+		assert_eq!(output.status.code(), Some(101), "{} exited differently", source);
+		assert_eq!(output.stdout, expected.stdout, "{} printed something different", source);
+		assert_eq!(String::from_utf8_lossy(&output.stderr), String::from_utf8_lossy(&expected.stderr), "{} failed differently", source);
+	}
+	let _ = fs::remove_dir_all(&dir);
+}
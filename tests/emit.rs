@@ -0,0 +1,80 @@
+//! Writes LLVM IR, bitcode, assembly and object files with `--emit`, and checks that
+//! each of them is what it's named after and that `-g` adds line tables to them.
+#![cfg(feature = "llvm")]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// A directory for one test to emit into, since the files are named after the input and
+/// written to the current directory.
+fn emit_dir(name: &str) -> PathBuf {
+	let dir = std::env::temp_dir().join(format!("bf-emit-test-{}-{}", std::process::id(), name));
+	fs::create_dir_all(&dir).expect("Failed to create the emit directory");
+	dir
+}
+
+/// Emits each of `kinds` for `helloworld.bf` with `args` into `dir`.
+fn emit(kinds: &str, args: &[&str], dir: &Path) {
+	let program = Path::new(env!("CARGO_MANIFEST_DIR")).join("bf-test").join("helloworld.bf");
+	let output = Command::new(env!("CARGO_BIN_EXE_bf"))
+		.args(["--emit", kinds])
+		.args(args)
+		.arg(&program)
+		.current_dir(dir)
+		.output()
+		.expect("Failed to run bf");
+	assert!(output.status.success(), "bf --emit {} failed: {}", kinds, String::from_utf8_lossy(&output.stderr));
+	assert!(output.stdout.is_empty(), "bf --emit ran the program");
+}
+
+#[test]
+fn every_kind_is_written() {
+	let dir = emit_dir("kinds");
+	emit("llvm-ir,llvm-bc,asm,obj", &[], &dir);
+	let read = |name: &str| fs::read(dir.join(name)).unwrap_or_else(|_| panic!("{} wasn't written", name));
+	let (ir, bitcode, asm, object) = (read("helloworld.ll"), read("helloworld.bc"), read("helloworld.s"), read("helloworld.o"));
+	let _ = fs::remove_dir_all(&dir);
+
+	let ir = String::from_utf8_lossy(&ir);
+	assert!(ir.contains("define") && ir.contains("@bf("), "The IR doesn't define the program: {}", ir);
+	assert!(bitcode.starts_with(b"BC\xc0\xde"), "The bitcode doesn't start with its magic number");
+	assert!(String::from_utf8_lossy(&asm).contains("bf:"), "The assembly doesn't have the program's symbol");
+	if cfg!(target_os = "linux") {
+		assert!(object.starts_with(b"\x7fELF"), "The object file isn't an ELF file");
+	}
+}
+
+#[test]
+fn only_the_kinds_asked_for_are_written() {
+	let dir = emit_dir("ir-only");
+	emit("llvm-ir", &[], &dir);
+	let mut written: Vec<String> = fs::read_dir(&dir)
+		.expect("Failed to read the emit directory")
+		.map(|entry| entry.expect("Failed to read the emit directory").file_name().to_string_lossy().into_owned())
+		.collect();
+	written.sort();
+	let _ = fs::remove_dir_all(&dir);
+	assert_eq!(written, vec!["helloworld.ll"]);
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+	haystack.windows(needle.len()).any(|window| window == needle)
+}
+
+#[test]
+fn debug_info_has_line_tables() {
+	let dir = emit_dir("debug-info");
+	emit("llvm-ir,obj", &["-g"], &dir);
+	let (ir, object) = (fs::read(dir.join("helloworld.ll")), fs::read(dir.join("helloworld.o")));
+	emit("obj", &[], &dir);
+	let plain_object = fs::read(dir.join("helloworld.o"));
+	let _ = fs::remove_dir_all(&dir);
+
+	let ir = String::from_utf8(ir.expect("The IR wasn't written")).expect("The IR isn't text");
+	assert!(ir.contains("filename: \"helloworld.bf\""), "The IR doesn't name the source file");
+	assert!(ir.contains("!DILocation(line: 13, column: 1"), "The IR doesn't have the location of the first loop");
+	// Mach-O calls it `__debug_line`.
+	assert!(contains(&object.expect("The object file wasn't written"), b"debug_line"), "-g didn't add a line table");
+	assert!(!contains(&plain_object.expect("The object file wasn't written"), b"debug_line"), "There's a line table without -g");
+}
@@ -0,0 +1,164 @@
+//! Runs every program in `bf-test` with the interpreter and with the LLVM backend,
+//! and checks that they print exactly the same thing. Also checks that programs that
+//! go off of the tape print the same thing and fail the same way with each of them.
+#![cfg(feature = "llvm")]
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Given to every program, the same as in the README's example for `factor.bf`.
+const INPUT: &[u8] = b"179424691\n";
+
+/// Printed by `-l` before the program runs.
+const LLVM_BANNER: &[u8] = b"Using LLVM\n";
+
+/// Printed by `--tiered` before the program runs.
+const TIERED_BANNER: &[u8] = b"Using LLVM for hot loops\n";
+
+fn run_bf(args: &[&str], program: &Path) -> Output {
+	let mut child = Command::new(env!("CARGO_BIN_EXE_bf"))
+		.args(args)
+		.arg(program)
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.spawn()
+		.expect("Failed to start bf");
+	// Programs that don't read input can exit before this is written.
+	let _ = child.stdin.take().unwrap().write_all(INPUT);
+
+	child.wait_with_output().expect("Failed to wait for bf")
+}
+
+/// What `program` prints when it's run with `args`, which it has to finish without errors.
+fn run_bf_ok(args: &[&str], program: &Path) -> Vec<u8> {
+	let output = run_bf(args, program);
+	assert!(output.status.success(), "bf {:?} {} failed: {}", args, program.display(), output.status);
+	output.stdout
+}
+
+fn test_programs() -> Vec<PathBuf> {
+	let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("bf-test");
+	let mut programs: Vec<PathBuf> = fs::read_dir(dir)
+		.expect("Failed to read bf-test")
+		.map(|entry| entry.expect("Failed to read bf-test").path())
+		.filter(|path| path.extension() == Some("bf".as_ref()))
+		.collect();
+	programs.sort();
+	programs
+}
+
+/// Every test program with what it prints in the interpreter, which is only run once
+/// for all of the tests.
+fn interpreter_outputs() -> &'static [(PathBuf, Vec<u8>)] {
+	static OUTPUTS: OnceLock<Vec<(PathBuf, Vec<u8>)>> = OnceLock::new();
+	OUTPUTS.get_or_init(|| test_programs().into_iter().map(|program| {
+		let output = run_bf_ok(&[], &program);
+		(program, output)
+	}).collect())
+}
+
+/// Checks that every test program prints `banner` and then the same thing as it does
+/// in the interpreter when it's run with `args`.
+fn assert_matches_interpreter(args: &[&str], banner: &[u8]) {
+	for (program, expected) in interpreter_outputs().iter() {
+		let output = run_bf_ok(args, program);
+		assert!(output.starts_with(banner), "{} didn't print the banner with {:?}", program.display(), args);
+		assert!(output[banner.len()..] == expected[..],
+			"{} printed something different with {:?}", program.display(), args);
+	}
+}
+
+/// Programs that print something and then go off of the tape.
+const OFF_TAPE_PROGRAMS: &[&str] = &[
+	// Runs off of the upper end in a loop.
+	"+++++++[>+++++++<-]>.[>+]",
+	// Go below the first cell, onto the ones before it that share its page.
+	"+++++++[>+++++++<-]>.<<<+.",
+	"<<<+++++++++++++++++++++++++++++++++++++++++++++++++.>>>",
+];
+
+/// Checks that each of `OFF_TAPE_PROGRAMS` prints `banner` and then the same thing as it
+/// does in the interpreter when it's run with `args`, and fails the same way.
+fn assert_fails_like_interpreter(args: &[&str], banner: &[u8]) {
+	static RUNS: AtomicUsize = AtomicUsize::new(0);
+	for source in OFF_TAPE_PROGRAMS.iter() {
+		let run = RUNS.fetch_add(1, Ordering::SeqCst);
+		let program = std::env::temp_dir().join(format!("bf-off-tape-{}-{}.bf", std::process::id(), run));
+		fs::write(&program, source).expect("Failed to write the program");
+		let expected = run_bf(&[], &program);
+		let output = run_bf(args, &program);
+		let _ = fs::remove_file(&program);
+
+		let expected_stderr = String::from_utf8_lossy(&expected.stderr);
+		let stderr = String::from_utf8_lossy(&output.stderr);
+		assert!(!expected.status.success(), "{} didn't fail", source);
+		assert_eq!(output.status.code(), expected.status.code(), "bf {:?} exited differently with {}", args, source);
+		assert!(output.stdout.starts_with(banner), "bf {:?} didn't print the banner with {}", args, source);
+		assert!(output.stdout[banner.len()..] == expected.stdout[..], "bf {:?} printed something different with {}", args, source);
+		// Going off of the upper end without bounds checks is caught by the guard pages,
+		// which don't know where in the source it happened.
+		if args.contains(&"--checked") {
+			assert_eq!(stderr, expected_stderr, "bf {:?} failed differently with {}", args, source);
+		} else {
+			let cell = &expected_stderr[expected_stderr.find("(cell").expect("The interpreter didn't say which cell")..];
+			assert!(stderr.starts_with("Data pointer out of range") && stderr.ends_with(cell),
+				"bf {:?} failed with {}: {}", args, source, stderr);
+		}
+	}
+}
+
+#[test]
+fn llvm_matches_interpreter() {
+	for level in ["0", "2"].iter() {
+		assert_matches_interpreter(&["-l", "-O", level], LLVM_BANNER);
+	}
+}
+
+#[test]
+fn llvm_fails_like_interpreter() {
+	assert_fails_like_interpreter(&["-l"], LLVM_BANNER);
+	assert_fails_like_interpreter(&["-l", "--checked"], LLVM_BANNER);
+}
+
+#[test]
+fn tiered_matches_interpreter() {
+	// 1 switches every loop over the first time it gets to its end.
+	for threshold in ["1", "100"].iter() {
+		assert_matches_interpreter(&["--tiered", "--tier-threshold", threshold, "-O", "0"], TIERED_BANNER);
+	}
+}
+
+#[test]
+fn tiered_fails_like_interpreter() {
+	assert_fails_like_interpreter(&["--tiered", "--tier-threshold", "1"], TIERED_BANNER);
+	assert_fails_like_interpreter(&["--tiered", "--tier-threshold", "1", "--checked"], TIERED_BANNER);
+}
+
+#[test]
+fn cached_code_matches_interpreter() {
+	let cache_dir = std::env::temp_dir().join(format!("bf-jit-cache-test-{}", std::process::id()));
+	let _ = fs::remove_dir_all(&cache_dir);
+	let cache_arg = cache_dir.to_str().unwrap();
+	// Compiled and cached the first time, loaded from the cache the second.
+	for _ in 0..2 {
+		assert_matches_interpreter(&["-l", "-O", "0", "--jit-cache", cache_arg], LLVM_BANNER);
+	}
+	let cached = fs::read_dir(&cache_dir).expect("Failed to read the cache").count();
+	let _ = fs::remove_dir_all(&cache_dir);
+	assert_eq!(cached, test_programs().len());
+}
+
+#[test]
+fn cached_code_fails_like_interpreter() {
+	let cache_dir = std::env::temp_dir().join(format!("bf-jit-cache-fail-test-{}", std::process::id()));
+	let _ = fs::remove_dir_all(&cache_dir);
+	for _ in 0..2 {
+		assert_fails_like_interpreter(&["-l", "--jit-cache", cache_dir.to_str().unwrap()], LLVM_BANNER);
+	}
+	let _ = fs::remove_dir_all(&cache_dir);
+}
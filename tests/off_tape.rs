@@ -0,0 +1,105 @@
+//! Runs programs that go off of the tape with the interpreter and as compiled code, and
+//! checks that they stop with an error instead of touching other memory or carrying on.
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+/// Writes `source` to a file named after `name` and runs it with `args`.
+fn run_source(name: &str, source: &str, args: &[&str]) -> Output {
+	let path: PathBuf = std::env::temp_dir().join(format!("bf-off-tape-{}-{}.bf", std::process::id(), name));
+	fs::write(&path, source).expect("Failed to write the program");
+	let output = Command::new(env!("CARGO_BIN_EXE_bf"))
+		.args(args)
+		.arg(&path)
+		.output()
+		.expect("Failed to run bf");
+	let _ = fs::remove_file(&path);
+	output
+}
+
+/// Ways of running the programs: in the interpreter with and without partial evaluation,
+/// and compiled with and without bounds checks when the LLVM backend is built.
+fn all_runs() -> Vec<&'static [&'static str]> {
+	let mut runs: Vec<&[&str]> = vec![&[], &["-p"]];
+	if cfg!(feature = "llvm") {
+		runs.push(&["-l"]);
+		runs.push(&["-l", "--checked"]);
+	}
+	runs
+}
+
+/// Checks that `source` stops with the error for going off of the tape at `cell` when
+/// it's run with each of `runs`.
+fn assert_off_tape_with(runs: &[&[&str]], name: &str, source: &str, cell: isize) {
+	for args in runs.iter() {
+		let output = run_source(name, source, args);
+		let stderr = String::from_utf8_lossy(&output.stderr);
+		assert!(!output.status.success(), "{} {:?} didn't fail", name, args);
+		assert!(stderr.contains("Data pointer out of range"), "{} {:?} failed with: {}", name, args, stderr);
+		assert!(stderr.contains(&format!("(cell {} of", cell)), "{} {:?} failed with: {}", name, args, stderr);
+	}
+}
+
+fn assert_off_tape(name: &str, source: &str, cell: isize) {
+	assert_off_tape_with(&all_runs(), name, source, cell);
+}
+
+#[test]
+fn move_left_of_the_first_cell() {
+	// Compiled code only checks cells when it uses them, and this never uses one.
+	assert_off_tape_with(&[&[], &["-p"]], "left", "<", -1);
+}
+
+#[test]
+fn move_left_of_the_first_cell_and_use_it() {
+	// The cells right before the first one share its page, so compiled code can't leave
+	// them to the guard pages.
+	assert_off_tape("left-use", &format!("<<<{}.>>>", "+".repeat(49)), -3);
+}
+
+#[test]
+fn scan_left_off_the_tape() {
+	assert_off_tape("scan-left", "+[<]", -1);
+}
+
+#[test]
+fn scan_right_off_the_tape() {
+	// From the last cell, since `+[>]` from the first one stops on the zero next to it.
+	assert_off_tape("scan-right", &format!("{}+[>]", ">".repeat(2999)), 3000);
+}
+
+#[test]
+fn scan_right_stops_on_a_zero_cell() {
+	let output = run_source("scan-right-zero", "+[>]", &[]);
+	assert!(output.status.success());
+}
+
+#[test]
+fn add_right_of_the_last_cell() {
+	assert_off_tape("right", &format!("{}+", ">".repeat(3000)), 3000);
+}
+
+#[test]
+fn dead_loop_off_the_tape() {
+	// The loop can never run, but getting to it goes off of the tape.
+	assert_off_tape("dead-loop", "<[-]>", -1);
+}
+
+#[test]
+fn overwritten_write_off_the_tape_between_output() {
+	// Prints "1", then stops at the `+++` before it prints again.
+	let source = "++++++++[>++++++<-]>+.<<<+++>>>.<<<[-]";
+	let mut runs = all_runs();
+	if cfg!(feature = "llvm") {
+		runs.push(&["-l", "-O", "0"]);
+		runs.push(&["-l", "-O", "2"]);
+	}
+	assert_off_tape_with(&runs, "overwritten", source, -2);
+	for args in runs.iter() {
+		let output = run_source("overwritten", source, args);
+		let stdout = String::from_utf8_lossy(&output.stdout);
+		assert_eq!(stdout.trim_start_matches("Using LLVM\n"), "1", "{:?} printed something different", args);
+		assert!(String::from_utf8_lossy(&output.stderr).contains("at 1:26 "), "{:?} stopped somewhere else", args);
+	}
+}
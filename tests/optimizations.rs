@@ -0,0 +1,141 @@
+//! Runs every program in `bf-test` with the optimizations that can be turned on from the
+//! command line, and checks that they print the same thing as a plain interpreter with
+//! no optimizations at all, like the `nooptim` build.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::OnceLock;
+
+/// Given to every program, the same as in the README's example for `factor.bf`.
+const INPUT: &[u8] = b"179424691\n";
+
+/// Same as `BF_MEMORY_SIZE`.
+const TAPE_SIZE: usize = 3000;
+
+/// Number of steps that the reference interpreter gives up after.
+const REFERENCE_STEPS: usize = 20_000_000;
+
+/// Snippets that none of the built in idioms cover.
+const EXTRA_IDIOMS: &str = "copy(2,1) [->>+<+<]>[-<+>]<\ncopy(1,3) [->+>>+<<<]>>>[-<<<+>>>]<<<\n";
+
+fn run_bf(args: &[&str], program: &Path) -> Vec<u8> {
+	let mut child = Command::new(env!("CARGO_BIN_EXE_bf"))
+		.args(args)
+		.arg(program)
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		.spawn()
+		.expect("Failed to start bf");
+	// Programs that don't read input can exit before this is written.
+	let _ = child.stdin.take().unwrap().write_all(INPUT);
+
+	let output = child.wait_with_output().expect("Failed to wait for bf");
+	assert!(output.status.success(), "bf {:?} {} failed: {}", args, program.display(), output.status);
+	output.stdout
+}
+
+/// Runs `source` the way it's written, except that runs of the same `+`, `-`, `<` or `>`
+/// are done all at once. `None` if it takes more than `REFERENCE_STEPS` steps.
+fn run_reference(source: &[u8]) -> Option<Vec<u8>> {
+	// (command, how many times in a row or the index of the other bracket)
+	let mut commands: Vec<(u8, usize)> = Vec::new();
+	let mut open = Vec::new();
+	for &ch in source.iter().filter(|ch| b"<>+-.,[]".contains(ch)) {
+		match ch {
+			b'[' => { open.push(commands.len()); commands.push((ch, 0)); },
+			b']' => {
+				let start = open.pop().expect("Unbalanced loops");
+				commands[start].1 = commands.len();
+				commands.push((ch, start));
+			},
+			b'<' | b'>' | b'+' | b'-' if commands.last().map(|&(last, _)| last) == Some(ch) => {
+				commands.last_mut().unwrap().1 += 1;
+			},
+			_ => commands.push((ch, 1)),
+		}
+	}
+
+	let mut tape = [0u8; TAPE_SIZE];
+	let mut ptr = 0;
+	let mut input = INPUT.iter();
+	let mut output = Vec::new();
+	let mut pc = 0;
+	let mut steps = 0;
+	while pc < commands.len() {
+		if steps == REFERENCE_STEPS {
+			return None;
+		}
+		steps += 1;
+		let (command, arg) = commands[pc];
+		match command {
+			b'>' => ptr += arg,
+			b'<' => ptr -= arg,
+			b'+' => tape[ptr] = tape[ptr].wrapping_add(arg as u8),
+			b'-' => tape[ptr] = tape[ptr].wrapping_sub(arg as u8),
+			b'.' => output.push(tape[ptr]),
+			// Cells are left alone at the end of the input.
+			b',' => if let Some(&byte) = input.next() { tape[ptr] = byte },
+			b'[' => if tape[ptr] == 0 { pc = arg },
+			b']' => if tape[ptr] != 0 { pc = arg },
+			_ => unreachable!(),
+		}
+		pc += 1;
+	}
+	Some(output)
+}
+
+/// Where the output of a program that takes too long for the reference interpreter in a
+/// debug build is checked in. These were made by running it without the step limit.
+fn expected_output_path(program: &Path) -> PathBuf {
+	let name = program.file_stem().expect("Test programs have names");
+	Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("expected").join(name).with_extension("out")
+}
+
+/// Every test program with what it prints without optimizations, which is only worked
+/// out once for all of the tests.
+fn reference_outputs() -> &'static [(PathBuf, Vec<u8>)] {
+	static OUTPUTS: OnceLock<Vec<(PathBuf, Vec<u8>)>> = OnceLock::new();
+	OUTPUTS.get_or_init(|| {
+		let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("bf-test");
+		let mut programs: Vec<PathBuf> = fs::read_dir(dir)
+			.expect("Failed to read bf-test")
+			.map(|entry| entry.expect("Failed to read bf-test").path())
+			.filter(|path| path.extension() == Some("bf".as_ref()))
+			.collect();
+		programs.sort();
+		programs.into_iter().map(|program| {
+			let output = fs::read(expected_output_path(&program)).unwrap_or_else(|_| {
+				let source = fs::read(&program).expect("Failed to read the program");
+				run_reference(&source).unwrap_or_else(|| panic!("{} takes more than {} steps, so what it prints has to be checked in as {}",
+					program.display(), REFERENCE_STEPS, expected_output_path(&program).display()))
+			});
+			(program, output)
+		}).collect()
+	})
+}
+
+fn assert_matches_reference(args: &[&str]) {
+	for (program, expected) in reference_outputs().iter() {
+		assert!(run_bf(args, program) == expected[..], "{} printed something different with {:?}", program.display(), args);
+	}
+}
+
+#[test]
+fn optimized_matches_reference() {
+	assert_matches_reference(&[]);
+}
+
+#[test]
+fn partial_eval_matches_reference() {
+	assert_matches_reference(&["-p"]);
+}
+
+#[test]
+fn extra_idioms_match_reference() {
+	let idioms = std::env::temp_dir().join(format!("bf-optimizations-test-{}.idioms", std::process::id()));
+	fs::write(&idioms, EXTRA_IDIOMS).expect("Failed to write the idioms");
+	assert_matches_reference(&["--idioms", idioms.to_str().unwrap()]);
+	let _ = fs::remove_file(&idioms);
+}
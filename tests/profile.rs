@@ -0,0 +1,47 @@
+//! Runs programs with `--profile` and checks how many times it says each loop ran.
+#![cfg(feature = "llvm")]
+
+use std::fs;
+use std::process::{Command, Output};
+
+/// Writes `source` to a file named after `name` and runs it with `args`.
+fn run_source(name: &str, source: &str, args: &[&str]) -> Output {
+	let path = std::env::temp_dir().join(format!("bf-profile-{}-{}.bf", std::process::id(), name));
+	fs::write(&path, source).expect("Failed to write the program");
+	let output = Command::new(env!("CARGO_BIN_EXE_bf"))
+		.args(args)
+		.arg(&path)
+		.output()
+		.expect("Failed to run bf");
+	let _ = fs::remove_file(&path);
+	assert!(output.status.success(), "bf {:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+	output
+}
+
+/// The loops that print, so that they aren't optimized away, and one that never runs.
+const NESTED_LOOPS: &str = "+++[>++[-.]<-]\n[comment .]";
+
+/// What `NESTED_LOOPS` prints with `-l`.
+const NESTED_LOOPS_OUTPUT: &[u8] = b"Using LLVM\n\x01\x00\x01\x00\x01\x00";
+
+#[test]
+fn loops_are_counted_with_their_spans() {
+	let output = run_source("nested", NESTED_LOOPS, &["-l", "--profile"]);
+	let stdout = String::from_utf8_lossy(&output.stdout);
+	let profile = &stdout[stdout.find("Loop Profile:").expect("There's no profile")..];
+	// The inner loop goes around the most, so it's first, and the one that never ran is left out.
+	assert_eq!(profile.lines().collect::<Vec<&str>>(), vec![
+		"Loop Profile:",
+		"==============",
+		"1:8-1:11 6 iterations in 3 entries: [A(0;-1)O(0;1)]",
+		"1:4-1:14 3 iterations in 1 entries: [A(1;2)>[A(0;-1)O(0;1)]A(-1;-1)<]",
+		"==============",
+	]);
+	assert!(output.stdout.starts_with(NESTED_LOOPS_OUTPUT), "The program printed something different");
+}
+
+#[test]
+fn nothing_is_counted_without_profile() {
+	let output = run_source("unprofiled", NESTED_LOOPS, &["-l"]);
+	assert_eq!(output.stdout, NESTED_LOOPS_OUTPUT);
+}